@@ -3,9 +3,12 @@
 #       compiling and generating the C library as well
 #       as generating C++ main program to demonstrate the
 #       effect of the C library.
-#       The executable binary (i.e. main.exe) will NOT be
-#       generated via build.rs so if you want to see the
-#       effect in C++, you need to run this script.
+#       build.rs now builds the C++ host as well (into
+#       OUT_DIR/test1.exe, see build_cpp_host()), and
+#       `cargo run -- compare` runs it side by side with the
+#       Rust host, and the .so as well (OUT_DIR/libmid_exit.so,
+#       see build_shared_clib(), for `cargo run -- call`); this
+#       script is only needed for the .dll.
 # Alternatively, I could probably have build.rs call this
 # script to generate the C library and the C++ main program
 # but the issue of not knowing the location of OUT_DIR
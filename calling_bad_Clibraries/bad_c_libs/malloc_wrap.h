@@ -0,0 +1,23 @@
+#ifndef MALLOC_WRAP_H
+#define MALLOC_WRAP_H
+
+/* NOTE: This header is force-included (gcc/clang `-include`) into every C
+ *  file of the library when building with `--features malloc_wrap` (see
+ *  build.rs); it is NOT meant to be #include'd by hand.  It redirects the
+ *  library's malloc()/calloc()/realloc()/free() to the accounting hooks in
+ *  ffi_guard/src/alloc_accounting.rs, along with the call site (__FILE__/__LINE__).
+ *  <stdlib.h> comes first so that its own declarations are not mangled by
+ *  the macros below (and it is include-guarded from then on). */
+#include <stdlib.h>
+
+void *ffi_wrap_malloc(size_t size, const char *file, int line);
+void *ffi_wrap_calloc(size_t count, size_t size, const char *file, int line);
+void *ffi_wrap_realloc(void *ptr, size_t size, const char *file, int line);
+void ffi_wrap_free(void *ptr, const char *file, int line);
+
+#define malloc(size) ffi_wrap_malloc((size), __FILE__, __LINE__)
+#define calloc(count, size) ffi_wrap_calloc((count), (size), __FILE__, __LINE__)
+#define realloc(ptr, size) ffi_wrap_realloc((ptr), (size), __FILE__, __LINE__)
+#define free(ptr) ffi_wrap_free((ptr), __FILE__, __LINE__)
+
+#endif
@@ -1,6 +1,9 @@
 #include <stdlib.h>
 #include <stdio.h>
 #include <math.h>
+#include <string.h>
+#include <unistd.h>
+#include <time.h> /* clock_gettime(), for mid_flaky() only */
 #include "mid_exit.h"
 
 int mid_exit(int status)
@@ -42,3 +45,130 @@ int mid_access_violation()
     printf("mid_access_violation(): This will not print...\n");
     return(0);
 }
+
+/* NOTE: a well behaved function (it does not crash), but every call
+ *  "forgets" to free its cache; fine for a one-shot tool, not so fine
+ *  once somebody calls it from a long running service */
+int mid_leak()
+{
+    static int calls = 0;
+    char *scratch = malloc(64);
+    int *cache = calloc(16, sizeof(int));
+    if (scratch == NULL || cache == NULL)
+    {
+        free(scratch);
+        free(cache);
+        return(-1);
+    }
+    snprintf(scratch, 64, "mid_leak(): call #%d", ++calls);
+    cache[0] = calls;
+    scratch = realloc(scratch, 128);
+    free(scratch);
+    return(calls); /* cache is never freed */
+}
+
+/* NOTE: opens a scratch file in $TMPDIR and "forgets" to close (and to
+ *  remove) it, just like a library that was about to clean up after
+ *  itself when it got interrupted; returns the leaked fd (or -1) */
+int mid_open_temp()
+{
+    char path[512];
+    const char *tmp_dir = getenv("TMPDIR");
+    int fd = -1;
+    snprintf(path, sizeof(path), "%s/mid_exit_XXXXXX", (tmp_dir && *tmp_dir) ? tmp_dir : "/tmp");
+    fd = mkstemp(path);
+    if (fd < 0)
+    {
+        return(-1);
+    }
+    printf("mid_open_temp(): scratch file '%s' (fd %d)\n", path, fd);
+    if (write(fd, "scratch\n", strlen("scratch\n")) < 0)
+    {
+        printf("mid_open_temp(): write failed\n");
+    }
+    return(fd);
+}
+
+/* NOTE: a "vendor" routine with the usual amount of input validation
+ *  (none); one entry point, many ways to go wrong depending on the
+ *  arguments, which is what `cargo run -- fuzz` is for */
+int mid_checksum(int count, int stride)
+{
+    static int table[64] = {3, 1, 4, 1, 5, 9, 2, 6, 5, 3, 5, 8, 9, 7, 9, 3};
+    int sum = 0;
+    int i;
+    if (count == 13)
+    {
+        printf("mid_checksum(): unlucky count, bailing out\n");
+        exit(13);
+    }
+    if (stride == -1)
+    {
+        /* "stream mode": waits for more data, which never comes */
+        for (;;)
+            sleep(1);
+    }
+    sum += count % stride;        /* stride 0 -> SIGFPE */
+    sum += table[count / stride]; /* no bounds check -> SIGSEGV if far enough out */
+    for (i = 0; i < count && i < 64; i += (stride > 0 ? stride : 1))
+        sum += table[i];
+    return(sum);
+}
+
+/* NOTE: an "image filter" on caller supplied buffers; the last byte of
+ *  the image is a checksum of the pixels, which it only checks AFTER it
+ *  has written the inverted pixels out (and on a mismatch it looks the
+ *  error up in a table it never allocated), and an image "too big to
+ *  copy" gets inverted in place, i.e. in the buffer it promised not to
+ *  touch; returns the number of bytes written */
+int mid_invert(const unsigned char *pixels, unsigned char *out, int len)
+{
+    unsigned char *target = out;
+    unsigned char sum = 0;
+    int *error_table = NULL;
+    int i;
+    if (len > 16 * 1024 * 1024)
+    {
+        target = (unsigned char *)pixels;
+    }
+    for (i = 0; i < len - 1; i++)
+    {
+        target[i] = 255 - pixels[i];
+        sum += pixels[i];
+    }
+    if (len > 0 && sum != pixels[len - 1])
+    {
+        printf("mid_invert(): bad checksum, looking up the error...\n");
+        return(error_table[sum]);
+    }
+    if (len > 0)
+    {
+        target[len - 1] = pixels[len - 1];
+    }
+    return(len);
+}
+
+/* NOTE: stands in for a library with a race inside; its "worker
+ *  thread" sometimes frees the buffer before we are done with it, so
+ *  roughly one call in two crashes, and which one is anybody's guess
+ *  (here: the clock's); returns `work` doubled when it does not crash */
+int mid_flaky(int work)
+{
+    struct timespec now;
+    int *buffer = malloc(sizeof(int));
+    clock_gettime(CLOCK_MONOTONIC, &now);
+    if (buffer == NULL)
+    {
+        return(-1);
+    }
+    if ((now.tv_nsec / 1000) % 2)
+    {
+        printf("mid_flaky(): lost the race\n");
+        free(buffer);
+        buffer = NULL; /* "freed by the other thread" */
+    }
+    *buffer = work * 2;
+    work = *buffer;
+    free(buffer);
+    return(work);
+}
@@ -4,5 +4,10 @@
 int mid_exit(int status);
 int mid_access_violation();
 int mid_divide_by_zero();
+int mid_leak();
+int mid_open_temp();
+int mid_checksum(int count, int stride);
+int mid_invert(const unsigned char *pixels, unsigned char *out, int len);
+int mid_flaky(int work);
 
 #endif
\ No newline at end of file
@@ -1,4 +1,5 @@
 #include <iostream>
+#include <string>
 extern "C" {
     #include "mid_exit.h"
 }
@@ -55,7 +56,17 @@ int test_segmentation_fault(){
     return 0;
 }
 
-int main() {
+// with a scenario name (see src/compare_hosts.rs on the Rust side), runs only that one; the
+// names are the same for both hosts so that `cargo run -- compare` can run them side by side
+int main(int argc, char *argv[]) {
+    if (argc > 1) {
+        std::string scenario = argv[1];
+        if (scenario == "divide_by_zero") return test_NaN_usage();
+        if (scenario == "access_violation") return test_segmentation_fault();
+        if (scenario == "exit") return test_OS_exit();
+        std::cerr << "usage: " << argv[0] << " [divide_by_zero|access_violation|exit]" << std::endl;
+        return 2;
+    }
     test_NaN_usage();    // in C/C++, unlike other languages, division by zero does not throw exception, but it causes NaN (which, in mathmaatics, it's as valid as INF)
     test_segmentation_fault();
     test_OS_exit();  // this will cause exit, so comment if want to test next line
@@ -14,6 +14,8 @@ const EXTERN_LIB_FILENAME: &str = "bindings";    // used for lib and rs filename
 // NOTE: Just like writing a BASH script or Makefile, ORDER of "cargo:" commands matters
 fn main() {
     build_single_clib();
+    build_cpp_host();
+    build_shared_clib();
     bind_gen_clibs();
     println!("cargo:rerun-if-changed=build.rs")
 }
@@ -97,7 +99,18 @@ fn build_single_clib() {
     //       set to "lib<src_file>.a" (static file), hence if you want to have a separate shared library, you
     //       WILL NEED to recompile again with .shared_flag().  Alternatively, don't use cc::Build and
     //       just cc/clang to generate the .o file...
-    let cc_result = cc::Build::new() // USE gcc (should be OK, as long as cc crate is installed)
+    let mut cc_build = cc::Build::new(); // USE gcc (should be OK, as long as cc crate is installed)
+    // Opt-in (`cargo build --features malloc_wrap`): force-include the header that redirects the
+    // library's malloc()/calloc()/realloc()/free() to the accounting hooks in ffi_guard/src/alloc_accounting.rs
+    // NOTE: cargo sets CARGO_FEATURE_<name> for the build script of the crate with the feature on
+    println!("cargo:rerun-if-changed=./{}/malloc_wrap.h", src_dir);
+    if env::var_os("CARGO_FEATURE_MALLOC_WRAP").is_some() {
+        println!("# INFO: build_single_clib() - malloc_wrap: wrapping malloc/calloc/realloc/free");
+        cc_build
+            .flag("-include")
+            .flag(format!("{}/malloc_wrap.h", src_dir));
+    }
+    let cc_result = cc_build
         //.compiler("clang")    // USE clang
         .file(format!("{}/{}.c", src_dir, src_file))
         .flag("-c") // compile only
@@ -185,6 +198,103 @@ fn build_single_clib() {
     // ############################## END LINKER
 }
 
+// The C++ host (bad_c_libs/test1.cpp), same as bad_c_libs/build.sh builds it, so that the
+// compare subcommand (src/compare_hosts.rs) can run the same scenarios under both hosts.
+// NOTE: it gets its OWN copy of the C library, compiled without malloc_wrap.h; the one
+// above may call into the accounting hooks which only exist on the Rust side.
+// NOTE: a missing C++ compiler is NOT fatal (the Rust side is what this crate is about);
+// we just do not set CPP_HOST_EXE, and `compare` says so at runtime
+fn build_cpp_host() {
+    println!("# INFO: build_cpp_host(entry)");
+    let src_dir = "./bad_c_libs";
+    let host_lib = "mid_exit_cpp_host";
+    let out_dir = env::var("OUT_DIR").unwrap();
+    println!("cargo:rerun-if-changed=./{}/test1.cpp", src_dir);
+
+    let c_result = cc::Build::new()
+        .file(format!("{}/mid_exit.c", src_dir))
+        .flag("-Wno-div-by-zero")
+        .cargo_metadata(false) // do NOT link this one into the Rust side as well
+        .try_compile(host_lib);
+    if let Err(e) = c_result {
+        println!("cargo:warning=build_cpp_host(): could not compile the C library: {}", e);
+        return;
+    }
+
+    // $ clang++ -v -std=c++20 -L. -o ${_BIN_SRC}.exe ${_BIN_SRC}.cpp -l${_LIB_SRC}
+    let exe = PathBuf::from(&out_dir).join("test1.exe");
+    let compiler = match cc::Build::new().cpp(true).try_get_compiler() {
+        Ok(compiler) => compiler,
+        Err(e) => {
+            println!("cargo:warning=build_cpp_host(): no C++ compiler: {}", e);
+            return;
+        }
+    };
+    let status = compiler
+        .to_command()
+        .arg("-std=c++20")
+        .arg(format!("-I{}", src_dir))
+        .arg("-o")
+        .arg(&exe)
+        .arg(format!("{}/test1.cpp", src_dir))
+        .arg(format!("-L{}", out_dir))
+        .arg(format!("-l{}", host_lib))
+        .arg("-lm") // sqrt(), log() and friends of mid_divide_by_zero()
+        .status();
+    match status {
+        Ok(status) if status.success() => {
+            println!("# INFO: build_cpp_host() - linked '{}'", exe.display());
+            println!("cargo:rustc-env=CPP_HOST_EXE={}", exe.display());
+        }
+        other => println!(
+            "cargo:warning=build_cpp_host(): could not build {}: {:?}",
+            exe.display(),
+            other
+        ),
+    }
+}
+
+// The C library as a shared object (OUT_DIR/libmid_exit.so), same as bad_c_libs/build.sh
+// builds it, for `cargo run -- call libmid_exit.so ...` (ffi_guard/src/dyn_call.rs) to dlopen().
+// NOTE: just like the C++ host, it is NOT linked into the Rust side, and failing to
+// build it is NOT fatal; we just do not set MID_EXIT_SO
+fn build_shared_clib() {
+    println!("# INFO: build_shared_clib(entry)");
+    let src_dir = "./bad_c_libs";
+    let out_dir = env::var("OUT_DIR").unwrap();
+
+    // $ clang -v -shared -o ${_LIB_SRC}.so ${_LIB_SRC}.c
+    let so = PathBuf::from(&out_dir).join("libmid_exit.so");
+    let compiler = match cc::Build::new().try_get_compiler() {
+        Ok(compiler) => compiler,
+        Err(e) => {
+            println!("cargo:warning=build_shared_clib(): no C compiler: {}", e);
+            return;
+        }
+    };
+    let status = compiler
+        .to_command()
+        .arg("-shared")
+        .arg("-fPIC")
+        .arg("-Wno-div-by-zero")
+        .arg("-o")
+        .arg(&so)
+        .arg(format!("{}/mid_exit.c", src_dir))
+        .arg("-lm")
+        .status();
+    match status {
+        Ok(status) if status.success() => {
+            println!("# INFO: build_shared_clib() - linked '{}'", so.display());
+            println!("cargo:rustc-env=MID_EXIT_SO={}", so.display());
+        }
+        other => println!(
+            "cargo:warning=build_shared_clib(): could not build {}: {:?}",
+            so.display(),
+            other
+        ),
+    }
+}
+
 fn bind_gen_clibs() {
     println!("# INFO: bind_gen_clibs(entry)");
     println!("cargo:rerun-if-changed=./bad_c_libs/mid_exit.h");
@@ -0,0 +1,400 @@
+// src/alloc_accounting.rs
+//
+// Who is leaking?  With `cargo build --features malloc_wrap`, build.rs force-includes
+// bad_c_libs/malloc_wrap.h into the C library, which turns every malloc()/calloc()/
+// realloc()/free() IN THE C LIBRARY (not ours, not libc's own) into a call to one of the
+// `ffi_wrap_*()` hooks below, along with the __FILE__/__LINE__ of the call.  The hooks
+// call the real thing and keep a tally: bytes allocated, bytes freed, and every block
+// that is still outstanding (with where it was allocated).
+//
+// Process isolated calls report the tally of their worker back to the host (see
+// `isolation::call_isolated_report()`); `soak()` calls a function N times in-process
+// and reports how much the outstanding memory (and the RSS) grew.
+//
+// A worker also reports an allocation of the C library that FAILED, the moment it does,
+// as an 'M' side record: that is what tells an abort() over RLIMIT_AS apart from any other
+// abort() (see `SandboxLimits::explain()`).
+//
+// NOTE: without the feature, the hooks are simply never called and everything is 0.
+
+use std::collections::HashMap;
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::fmt;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutstandingBlock {
+    pub size: u64,
+    /// "<file>:<line>" of the malloc()/calloc()/realloc() call
+    pub site: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AllocReport {
+    pub bytes_allocated: u64,
+    pub bytes_freed: u64,
+    pub allocations: u64,
+    pub frees: u64,
+    pub outstanding: Vec<OutstandingBlock>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    size: u64,
+    // __FILE__ is a string literal, hence 'static
+    file: &'static CStr,
+    line: c_int,
+}
+
+#[derive(Default)]
+struct Tally {
+    bytes_allocated: u64,
+    bytes_freed: u64,
+    allocations: u64,
+    frees: u64,
+    blocks: HashMap<usize, Block>,
+}
+
+// NOTE: the HashMap allocates through Rust's allocator (the real malloc()), so there is
+// no recursion into the hooks here.  `static mut` only so that a forked worker can put a
+// fresh one in its place, see `reset_in_worker()`; everything else goes through `lock()`
+static mut TALLY: Mutex<Option<Tally>> = Mutex::new(None);
+
+// where a failed allocation gets reported; -1 = nowhere (i.e. in the host)
+static FAILURE_FD: AtomicI32 = AtomicI32::new(-1);
+
+const FAILURE_RECORD: &[u8] = b"M\n";
+
+/// Whether the C library was built with the wrapped allocator
+pub fn enabled() -> bool {
+    cfg!(feature = "malloc_wrap")
+}
+
+/// Forgets everything counted so far (outstanding blocks included)
+pub fn reset() {
+    *lock() = None;
+}
+
+/// `reset()` for a freshly forked worker, WITHOUT taking the lock: if another thread of
+/// the host was in a hook at fork() time, the child got the lock held by a thread that
+/// does not exist in the child, and would wait for it forever.  From now on, the first
+/// allocation that fails gets reported on `report_fd`
+pub(crate) fn reset_in_worker(report_fd: c_int) {
+    // SAFETY: right after fork(), the calling thread is the only one there is, and nothing
+    // holds on to the old tally; which is leaked rather than dropped, since its HashMap
+    // may have been half-way through an update
+    unsafe { std::ptr::addr_of_mut!(TALLY).write(Mutex::new(None)) };
+    FAILURE_FD.store(report_fd, Ordering::SeqCst);
+}
+
+/// Whether the worker's report says that an allocation of the C library failed
+pub(crate) fn allocation_failed(report: &str) -> bool {
+    report.lines().any(|line| line == "M")
+}
+
+pub fn snapshot() -> AllocReport {
+    let tally = lock();
+    let Some(tally) = tally.as_ref() else {
+        return AllocReport::default();
+    };
+    let mut outstanding: Vec<OutstandingBlock> = tally
+        .blocks
+        .values()
+        .map(|block| OutstandingBlock {
+            size: block.size,
+            site: format!("{}:{}", block.file.to_string_lossy(), block.line),
+        })
+        .collect();
+    outstanding.sort_by(|a, b| (&a.site, a.size).cmp(&(&b.site, b.size)));
+    AllocReport {
+        bytes_allocated: tally.bytes_allocated,
+        bytes_freed: tally.bytes_freed,
+        allocations: tally.allocations,
+        frees: tally.frees,
+        outstanding,
+    }
+}
+
+/// Calls `my_function` in-place and reports the C library's allocations during the call
+/// (NOT thread safe, in the sense that other threads calling into C get counted too)
+pub fn measure<T>(my_function: impl FnOnce() -> T) -> (T, AllocReport) {
+    reset();
+    let result = my_function();
+    (result, snapshot())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SoakReport {
+    pub calls: usize,
+    /// the tally over all `calls`
+    pub allocations: AllocReport,
+    pub rss_before: Option<u64>,
+    pub rss_after: Option<u64>,
+}
+
+/// Calls `my_function` `calls` times (in-place; this is about the growth of OUR memory)
+pub fn soak(calls: usize, my_function: impl Fn()) -> SoakReport {
+    let rss_before = resident_bytes();
+    let ((), allocations) = measure(|| {
+        for _ in 0..calls {
+            my_function();
+        }
+    });
+    SoakReport {
+        calls,
+        allocations,
+        rss_before,
+        rss_after: resident_bytes(),
+    }
+}
+
+// resident set size, from the 2nd field of /proc/self/statm (in pages)
+#[cfg(target_os = "linux")]
+fn resident_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * page_size as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_bytes() -> Option<u64> {
+    None
+}
+
+impl AllocReport {
+    pub fn outstanding_bytes(&self) -> u64 {
+        self.outstanding.iter().map(|block| block.size).sum()
+    }
+
+    /// Outstanding blocks grouped by call site: (site, blocks, bytes), biggest first
+    pub fn by_site(&self) -> Vec<(String, u64, u64)> {
+        let mut sites: HashMap<&str, (u64, u64)> = HashMap::new();
+        for block in &self.outstanding {
+            let entry = sites.entry(&block.site).or_default();
+            entry.0 += 1;
+            entry.1 += block.size;
+        }
+        let mut sites: Vec<(String, u64, u64)> = sites
+            .into_iter()
+            .map(|(site, (blocks, bytes))| (site.to_string(), blocks, bytes))
+            .collect();
+        sites.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+        sites
+    }
+
+    // One line for the report pipe (see isolation.rs):
+    //      A<allocated> <freed> <allocations> <frees>[;<size>@<site>]...
+    pub(crate) fn encode(&self) -> String {
+        let mut line = format!(
+            "A{} {} {} {}",
+            self.bytes_allocated, self.bytes_freed, self.allocations, self.frees
+        );
+        for block in &self.outstanding {
+            line.push_str(&format!(";{}@{}", block.size, block.site));
+        }
+        line
+    }
+
+    pub(crate) fn decode(line: &str) -> Option<AllocReport> {
+        let mut parts = line.strip_prefix('A')?.split(';');
+        let counters: Vec<u64> = parts
+            .next()?
+            .split_whitespace()
+            .map(|n| n.parse().ok())
+            .collect::<Option<_>>()?;
+        let [bytes_allocated, bytes_freed, allocations, frees] = counters[..] else {
+            return None;
+        };
+        let outstanding = parts
+            .map(|part| {
+                let (size, site) = part.split_once('@')?;
+                Some(OutstandingBlock {
+                    size: size.parse().ok()?,
+                    site: site.to_string(),
+                })
+            })
+            .collect::<Option<_>>()?;
+        Some(AllocReport {
+            bytes_allocated,
+            bytes_freed,
+            allocations,
+            frees,
+            outstanding,
+        })
+    }
+}
+
+impl fmt::Display for AllocReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "allocated {} bytes in {} blocks, freed {} bytes in {}, outstanding {} bytes in {} blocks",
+            self.bytes_allocated,
+            self.allocations,
+            self.bytes_freed,
+            self.frees,
+            self.outstanding_bytes(),
+            self.outstanding.len()
+        )?;
+        for (site, blocks, bytes) in self.by_site() {
+            write!(
+                f,
+                "\n    {} bytes in {} blocks from {}",
+                bytes, blocks, site
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SoakReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} calls: {}", self.calls, self.allocations)?;
+        match (self.rss_before, self.rss_after) {
+            (Some(before), Some(after)) => write!(
+                f,
+                "RSS {} KiB -> {} KiB ({:+} KiB)",
+                before / 1024,
+                after / 1024,
+                (after as i64 - before as i64) / 1024
+            ),
+            _ => write!(f, "RSS unknown"),
+        }
+    }
+}
+
+fn lock() -> std::sync::MutexGuard<'static, Option<Tally>> {
+    // a panic while holding it cannot leave the tally half-updated in any way that matters
+    // SAFETY: only ever written to by reset_in_worker(), see there
+    let tally = unsafe { &*std::ptr::addr_of!(TALLY) };
+    tally
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn record_alloc(ptr: *mut c_void, size: usize, file: *const c_char, line: c_int) {
+    if ptr.is_null() {
+        if size != 0 {
+            record_failure();
+        }
+        return;
+    }
+    let file: &'static CStr = if file.is_null() {
+        c"?"
+    } else {
+        // SAFETY: __FILE__ from malloc_wrap.h, a string literal in the C library
+        unsafe { CStr::from_ptr(file) }
+    };
+    let mut tally = lock();
+    let tally = tally.get_or_insert_with(Tally::default);
+    tally.bytes_allocated += size as u64;
+    tally.allocations += 1;
+    let block = Block {
+        size: size as u64,
+        file,
+        line,
+    };
+    tally.blocks.insert(ptr as usize, block);
+}
+
+// Nothing that allocates in here: we are out of memory, or close to it
+fn record_failure() {
+    let fd = FAILURE_FD.swap(-1, Ordering::SeqCst);
+    if fd >= 0 {
+        unsafe { libc::write(fd, FAILURE_RECORD.as_ptr().cast(), FAILURE_RECORD.len()) };
+    }
+}
+
+fn record_free(ptr: *mut c_void) {
+    if ptr.is_null() {
+        return;
+    }
+    let mut tally = lock();
+    let tally = tally.get_or_insert_with(Tally::default);
+    // blocks allocated before the last reset() (or by libc itself, i.e. strdup()) are not
+    // in the table; free them all the same, but they do not count
+    if let Some(block) = tally.blocks.remove(&(ptr as usize)) {
+        tally.bytes_freed += block.size;
+        tally.frees += 1;
+    }
+}
+
+// ############################## the hooks (see bad_c_libs/malloc_wrap.h)
+
+#[no_mangle]
+pub extern "C" fn ffi_wrap_malloc(size: usize, file: *const c_char, line: c_int) -> *mut c_void {
+    let ptr = unsafe { libc::malloc(size) };
+    record_alloc(ptr, size, file, line);
+    ptr
+}
+
+#[no_mangle]
+pub extern "C" fn ffi_wrap_calloc(
+    count: usize,
+    size: usize,
+    file: *const c_char,
+    line: c_int,
+) -> *mut c_void {
+    let ptr = unsafe { libc::calloc(count, size) };
+    record_alloc(ptr, count.saturating_mul(size), file, line);
+    ptr
+}
+
+/// # Safety
+/// `ptr` has to be NULL or a live block from malloc()/calloc()/realloc()
+#[no_mangle]
+pub unsafe extern "C" fn ffi_wrap_realloc(
+    ptr: *mut c_void,
+    size: usize,
+    file: *const c_char,
+    line: c_int,
+) -> *mut c_void {
+    let new_ptr = libc::realloc(ptr, size);
+    // on failure, the old block is left alone (unless size was 0, which frees it)
+    if !new_ptr.is_null() || size == 0 {
+        record_free(ptr);
+        record_alloc(new_ptr, size, file, line);
+    }
+    new_ptr
+}
+
+/// # Safety
+/// `ptr` has to be NULL or a live block from malloc()/calloc()/realloc()
+#[no_mangle]
+pub unsafe extern "C" fn ffi_wrap_free(ptr: *mut c_void, _file: *const c_char, _line: c_int) {
+    record_free(ptr);
+    libc::free(ptr);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // NOTE: one test only, since the tally is process wide and tests run in parallel
+    #[test]
+    fn test_hooks_tally_and_round_trip() {
+        let file = c"leaky.c".as_ptr();
+        let ((), report) = measure(|| unsafe {
+            let leaked = ffi_wrap_calloc(4, 16, file, 10);
+            let scratch = ffi_wrap_malloc(100, file, 20);
+            let scratch = ffi_wrap_realloc(scratch, 200, file, 21);
+            ffi_wrap_free(scratch, file, 22);
+            std::hint::black_box(leaked);
+        });
+        assert_eq!(report.bytes_allocated, 64 + 100 + 200);
+        assert_eq!(report.bytes_freed, 100 + 200);
+        assert_eq!(report.allocations, 3);
+        assert_eq!(report.frees, 2);
+        assert_eq!(
+            report.outstanding,
+            vec![OutstandingBlock {
+                size: 64,
+                site: "leaky.c:10".into()
+            }]
+        );
+        assert_eq!(AllocReport::decode(&report.encode()), Some(report));
+        reset();
+    }
+}
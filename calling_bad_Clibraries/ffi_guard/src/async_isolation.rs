@@ -0,0 +1,300 @@
+// src/async_isolation.rs
+//
+// Future-returning flavor of `isolation::call_isolated()` for async (tokio) hosts.
+// `fork_and_join_0arg()` and `call_isolated()` block the calling thread until the worker
+// is done, which in an async service means one of the (few) runtime threads is stuck
+// for as long as the C library takes.  Here the waiting is done on tokio's blocking
+// pool instead, and:
+//  - at most `max_concurrent` foreign calls are in flight at once (the rest wait their
+//    turn asynchronously)
+//  - dropping the future (i.e. `tokio::time::timeout()`, `select!`) SIGKILLs the worker
+//    process of `Fork`/`ReExec`/`ForkServer` calls (the fork server tells us its worker's
+//    pid, see zygote.rs); a `Thread` call has no process of its own to kill, so it is
+//    abandoned and runs to completion in the background
+//  - either way, a call keeps its slot (of the `max_concurrent`) until the C call is
+//    really over, not just until its future is gone
+//
+// NOTE: the tokio runtime is multithreaded, so prefer `ReExec` (or `ForkServer`) over
+// `Fork` here (see reexec.rs for why).
+
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[cfg(unix)]
+use crate::isolation::GuardedCall;
+use crate::isolation::{call_isolated_with, IsolationMode};
+use crate::outcome::CallOutcome;
+use crate::sandbox::SandboxLimits;
+
+#[derive(Debug, Clone)]
+pub struct AsyncIsolation {
+    permits: Arc<Semaphore>,
+}
+
+impl AsyncIsolation {
+    pub fn new(max_concurrent: usize) -> Self {
+        AsyncIsolation {
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+        }
+    }
+
+    pub async fn call(
+        &self,
+        mode: IsolationMode,
+        my_function: fn() -> anyhow::Result<String>,
+    ) -> CallOutcome {
+        self.call_with(mode, &SandboxLimits::default(), my_function)
+            .await
+    }
+
+    pub async fn call_with(
+        &self,
+        mode: IsolationMode,
+        limits: &SandboxLimits,
+        my_function: fn() -> anyhow::Result<String>,
+    ) -> CallOutcome {
+        // owned, so that it can go along with the blocking task (which outlives the future
+        // if the future gets dropped)
+        let permit = match self.permits.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => return CallOutcome::Failed("AsyncIsolation has been closed".into()),
+        };
+        match mode {
+            #[cfg(unix)]
+            IsolationMode::Fork | IsolationMode::ReExec => {
+                call_in_worker(mode, limits, my_function, permit).await
+            }
+            #[cfg(unix)]
+            IsolationMode::ForkServer if limits.is_unrestricted() => {
+                call_in_fork_server(my_function, permit).await
+            }
+            _ => {
+                let limits = limits.clone();
+                // ours, not the blocking thread's (see record_replay::with_recorder())
+                let recorder = crate::record_replay::current();
+                let blocking = tokio::task::spawn_blocking(move || {
+                    let _permit = permit;
+                    crate::record_replay::with_recorder(recorder, || {
+                        call_isolated_with(mode, &limits, my_function)
+                    })
+                });
+                blocking
+                    .await
+                    .unwrap_or_else(|e| CallOutcome::Failed(format!("blocking task: {}", e)))
+            }
+        }
+    }
+}
+
+// The pid of a worker that has not been reaped yet (None before the fork server has told
+// us, and once it has been reaped); shared between the future (which kills it if
+// dropped) and the blocking task (which reaps it, or has the fork server do so)
+#[cfg(unix)]
+#[derive(Default)]
+struct Unreaped {
+    pid: Option<libc::pid_t>,
+    dropped: bool,
+}
+
+#[cfg(unix)]
+impl Unreaped {
+    fn started(&mut self, pid: libc::pid_t) {
+        self.pid = Some(pid);
+        if self.dropped {
+            // the future is gone already, the worker goes right away
+            unsafe { libc::kill(pid, libc::SIGKILL) };
+        }
+    }
+}
+
+#[cfg(unix)]
+struct KillOnDrop(Arc<Mutex<Unreaped>>);
+
+#[cfg(unix)]
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let mut unreaped = self.0.lock().unwrap();
+        unreaped.dropped = true;
+        if let Some(pid) = unreaped.pid {
+            // the blocking task is still waiting for it, the reaping is done there
+            unsafe { libc::kill(pid, libc::SIGKILL) };
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn call_in_worker(
+    mode: IsolationMode,
+    limits: &SandboxLimits,
+    my_function: fn() -> anyhow::Result<String>,
+    permit: OwnedSemaphorePermit,
+) -> CallOutcome {
+    // same bookkeeping as `call_isolated_report()`; NOTE: the span is entered only around
+    // the synchronous bits, never across an .await
+    let call = GuardedCall::begin(mode, my_function);
+    if let Some(report) = call.replayed() {
+        return call.finish(report).outcome;
+    }
+    let worker = match call
+        .span
+        .in_scope(|| crate::isolation::start_worker(mode, limits, my_function))
+    {
+        Ok(worker) => worker,
+        Err(outcome) => return call.finish(outcome.into()).outcome,
+    };
+    let unreaped = Arc::new(Mutex::new(Unreaped::default()));
+    unreaped.lock().unwrap().started(worker.pid());
+    let kill_on_drop = KillOnDrop(unreaped.clone());
+
+    let blocking = tokio::task::spawn_blocking(move || {
+        // the slot is free once the worker is gone, not when the future is
+        let _permit = permit;
+        worker.wait_then_reap(|| {
+            unreaped.lock().unwrap().pid.take();
+        })
+    });
+    let report = match blocking.await {
+        Ok(Ok((status, report))) => call
+            .span
+            .in_scope(|| crate::isolation::conclude(limits, status, &report)),
+        Ok(Err(e)) => CallOutcome::Failed(format!("waitpid() failed: {}", e)).into(),
+        Err(e) => CallOutcome::Failed(format!("blocking task: {}", e)).into(),
+    };
+    drop(kill_on_drop); // already reaped by now, so this is a no-op
+
+    // NOTE: a dropped future never gets here, hence is not counted (it was the caller
+    // who gave up on it, not the C function that misbehaved); its span closes without
+    // an outcome
+    call.finish(report).outcome
+}
+
+// Same as `call_in_worker()`, but the worker gets forked off the fork server; the blocking
+// task learns its pid only once the request has gone out
+#[cfg(unix)]
+async fn call_in_fork_server(
+    my_function: fn() -> anyhow::Result<String>,
+    permit: OwnedSemaphorePermit,
+) -> CallOutcome {
+    let call = GuardedCall::begin(IsolationMode::ForkServer, my_function);
+    if let Some(report) = call.replayed() {
+        return call.finish(report).outcome;
+    }
+    let Some(server) = crate::zygote::global() else {
+        return call
+            .finish(CallOutcome::Failed("fork server has not been started".into()).into())
+            .outcome;
+    };
+    let unreaped = Arc::new(Mutex::new(Unreaped::default()));
+    let kill_on_drop = KillOnDrop(unreaped.clone());
+
+    let span = call.span.clone();
+    let blocking = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        span.in_scope(|| {
+            server.call_report_with(
+                my_function,
+                |pid| unreaped.lock().unwrap().started(pid),
+                || {
+                    unreaped.lock().unwrap().pid.take();
+                },
+            )
+        })
+    });
+    let report = blocking
+        .await
+        .unwrap_or_else(|e| CallOutcome::Failed(format!("blocking task: {}", e)).into());
+    drop(kill_on_drop); // already reaped by now, so this is a no-op
+
+    call.finish(report).outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn sleepy() -> anyhow::Result<String> {
+        std::thread::sleep(Duration::from_millis(200));
+        Ok("slept".into())
+    }
+
+    // leaves a file behind, named after the parent (the test process), if not killed first
+    fn sleepy_marker() -> anyhow::Result<String> {
+        std::thread::sleep(Duration::from_millis(300));
+        let ppid = unsafe { libc::getppid() };
+        let marker = std::env::temp_dir().join(format!("async_isolation_{}", ppid));
+        std::fs::write(marker, b"not killed")?;
+        Ok("should not get here".into())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrency_is_limited() {
+        let isolation = AsyncIsolation::new(2);
+        let start = Instant::now();
+        let calls: Vec<_> = (0..4)
+            .map(|_| {
+                let isolation = isolation.clone();
+                tokio::spawn(async move { isolation.call(IsolationMode::Thread, sleepy).await })
+            })
+            .collect();
+        for call in calls {
+            assert_eq!(call.await.unwrap(), CallOutcome::Returned("slept".into()));
+        }
+        // 4 calls, 2 at a time, 200 mSec each
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dropping_the_future_kills_the_worker() {
+        let marker = std::env::temp_dir().join(format!("async_isolation_{}", std::process::id()));
+        let _ = std::fs::remove_file(&marker);
+
+        let isolation = AsyncIsolation::new(1);
+        let call = isolation.call(IsolationMode::Fork, sleepy_marker);
+        assert!(tokio::time::timeout(Duration::from_millis(50), call)
+            .await
+            .is_err());
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!marker.exists());
+
+        // and the permit was given back
+        let outcome = isolation.call(IsolationMode::Fork, sleepy).await;
+        assert_eq!(outcome, CallOutcome::Returned("slept".into()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dropping_the_future_kills_the_fork_server_worker() {
+        // another test may have started it already; either way, it is THE fork server
+        let _ = crate::zygote::start_global(SandboxLimits::default());
+        let helper = crate::zygote::global().unwrap().pid();
+        // the worker's parent is the helper, so that is whom the marker is named after
+        let marker = std::env::temp_dir().join(format!("async_isolation_{}", helper));
+        let _ = std::fs::remove_file(&marker);
+
+        let isolation = AsyncIsolation::new(1);
+        let call = isolation.call(IsolationMode::ForkServer, sleepy_marker);
+        assert!(tokio::time::timeout(Duration::from_millis(50), call)
+            .await
+            .is_err());
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!marker.exists());
+
+        let outcome = isolation.call(IsolationMode::ForkServer, sleepy).await;
+        assert_eq!(outcome, CallOutcome::Returned("slept".into()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_abandoned_thread_call_keeps_its_slot() {
+        let isolation = AsyncIsolation::new(1);
+        let call = isolation.call(IsolationMode::Thread, sleepy);
+        assert!(tokio::time::timeout(Duration::from_millis(50), call)
+            .await
+            .is_err());
+        // sleepy() is still running on the blocking pool, and still has the only slot
+        let start = Instant::now();
+        let outcome = isolation.call(IsolationMode::Thread, sleepy).await;
+        assert_eq!(outcome, CallOutcome::Returned("slept".into()));
+        assert!(start.elapsed() >= Duration::from_millis(300));
+    }
+}
@@ -0,0 +1,428 @@
+// src/call_trace.rs
+//
+// Structured tracing (the `tracing` crate) for the guarded calls, written out as JSON
+// lines rather than println!()ed as "do_proc_exit(0): ..." markers:
+//
+//  - every guarded call is a span `ffi_call` carrying `function` (see
+//    `metrics::name_function()`), `mode`, `call_id` (same id as in the shared log) and,
+//    once it is known, `outcome`; see `call_span()`
+//  - events (`tracing::info!()` & co) inside the call are written with the spans they
+//    happened in, so a grep for `"call_id":7` finds everything call 7 did
+//
+// The catch is that most calls happen in ANOTHER process.  A worker does not write the
+// trace itself (two processes appending JSON to one file is asking for torn lines, and a
+// re-exec'ed worker does not even know where the trace goes); it sends each event back to
+// the host as a 'T' side record over the report pipe, the moment it happens, so whatever
+// the worker said before it crashed still makes it home.  The host then replays them
+// inside the call's span (`forward_worker_events()`), and they end up in the same trace
+// with `"origin":"worker"` and the worker's own pid and timestamp.
+//
+// One JSON object per line:
+//      {"type":"event","origin":"host","ts":1760000000.123,"level":"INFO",
+//       "target":"calling_bad_Clibraries","pid":4242,"message":"...",<fields>,
+//       "spans":[{"name":"ffi_call","function":"mid_exit","mode":"Fork","call_id":7}]}
+//      {"type":"span","ts":...,"name":"ffi_call",<fields>,"outcome":"...","elapsed_ms":1.2,
+//       "spans":[<the spans it is nested in>]}
+// (the span line is written when the span closes, which is when the outcome is known)
+//
+// Where the host writes them: `FFI_TRACE=<path>` (or `-` for stderr), else
+// `default_path()`.
+
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Span, Subscriber};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::isolation::IsolationMode;
+
+const TRACE_ENV: &str = "FFI_TRACE";
+// the field a replayed worker event travels in, see `forward_worker_events()`
+const WORKER_EVENT: &str = "worker_event";
+
+// The report pipe, if we are a worker (then events are sent to the host rather than
+// written); -1 in the host
+static WORKER_FD: AtomicI32 = AtomicI32::new(-1);
+
+/// The `tracing_subscriber` layer writing the JSON lines
+pub struct JsonLines {
+    sink: Mutex<Box<dyn Write + Send>>,
+}
+
+impl JsonLines {
+    pub fn new(sink: Box<dyn Write + Send>) -> Self {
+        JsonLines {
+            sink: Mutex::new(sink),
+        }
+    }
+
+    fn write_line(&self, line: &str) {
+        let mut sink = self
+            .sink
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // one write per line (the file is O_APPEND), then flushed right away: the next
+        // thing the C library does may well be to take the host down
+        let _ = sink.write_all(format!("{}\n", line).as_bytes());
+        let _ = sink.flush();
+    }
+}
+
+// What we keep of a span (in the registry's extensions)
+struct SpanData {
+    fields: JsonFields,
+    start: Instant,
+}
+
+impl<S> Layer<S> for JsonLines
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = JsonFields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanData {
+                fields,
+                start: Instant::now(),
+            });
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(&mut data.fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = JsonFields::default();
+        event.record(&mut fields);
+        let metadata = event.metadata();
+
+        let worker_fd = WORKER_FD.load(Ordering::SeqCst);
+        if worker_fd >= 0 {
+            // the host knows which call this is, and adds the spans when it replays it
+            let mut record = JsonFields::default();
+            record.push("ts", &timestamp());
+            record.push("level", &json_string(metadata.level().as_str()));
+            record.push("target", &json_string(metadata.target()));
+            record.push("pid", &std::process::id().to_string());
+            record.append(&fields);
+            send_to_host(worker_fd, &format!("T{{{}}}\n", record.pairs));
+            return;
+        }
+
+        let mut line = JsonFields::default();
+        line.push("type", "\"event\"");
+        match &fields.worker_event {
+            Some(worker_event) => {
+                line.push("origin", "\"worker\"");
+                // the worker's JSON object, minus its braces
+                let inner = worker_event
+                    .strip_prefix('{')
+                    .and_then(|inner| inner.strip_suffix('}'))
+                    .unwrap_or("");
+                line.push_raw(inner);
+            }
+            None => {
+                line.push("origin", "\"host\"");
+                line.push("ts", &timestamp());
+                line.push("level", &json_string(metadata.level().as_str()));
+                line.push("target", &json_string(metadata.target()));
+                line.push("pid", &std::process::id().to_string());
+                line.append(&fields);
+            }
+        }
+        let scope = ctx.event_scope(event).map(|scope| scope.from_root());
+        line.push("spans", &spans_json(scope));
+        self.write_line(&format!("{{{}}}", line.pairs));
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if WORKER_FD.load(Ordering::SeqCst) >= 0 {
+            // spans the worker inherited (forked while in a call) are the host's business
+            return;
+        }
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let mut line = JsonFields::default();
+        line.push("type", "\"span\"");
+        line.push("ts", &timestamp());
+        line.push("name", &json_string(span.name()));
+        if let Some(data) = span.extensions().get::<SpanData>() {
+            line.append(&data.fields);
+            let elapsed_ms = data.start.elapsed().as_secs_f64() * 1000.0;
+            line.push("elapsed_ms", &format!("{:.3}", elapsed_ms));
+        }
+        let parents = span.parent().map(|parent| parent.scope().from_root());
+        line.push("spans", &spans_json(parents));
+        self.write_line(&format!("{{{}}}", line.pairs));
+    }
+}
+
+// [{"name":..., <fields>}, ...], outermost first
+fn spans_json<'a, R>(
+    spans: Option<impl Iterator<Item = tracing_subscriber::registry::SpanRef<'a, R>>>,
+) -> String
+where
+    R: LookupSpan<'a> + 'a,
+{
+    let mut json = String::from("[");
+    for (i, span) in spans.into_iter().flatten().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let mut fields = JsonFields::default();
+        fields.push("name", &json_string(span.name()));
+        if let Some(data) = span.extensions().get::<SpanData>() {
+            fields.append(&data.fields);
+        }
+        let _ = write!(json, "{{{}}}", fields.pairs);
+    }
+    json.push(']');
+    json
+}
+
+#[derive(Default)]
+struct JsonFields {
+    // `"name":value` pairs, comma separated, without the braces
+    pairs: String,
+    // the worker event being replayed, if this is one (see `forward_worker_events()`)
+    worker_event: Option<String>,
+}
+
+impl JsonFields {
+    fn push(&mut self, name: &str, raw_value: &str) {
+        self.push_raw(&format!("{}:{}", json_string(name), raw_value));
+    }
+
+    fn push_raw(&mut self, pairs: &str) {
+        if pairs.is_empty() {
+            return;
+        }
+        if !self.pairs.is_empty() {
+            self.pairs.push(',');
+        }
+        self.pairs.push_str(pairs);
+    }
+
+    fn append(&mut self, other: &JsonFields) {
+        self.push_raw(&other.pairs);
+    }
+}
+
+impl Visit for JsonFields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field.name(), &json_string(&format!("{:?}", value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == WORKER_EVENT {
+            self.worker_event = Some(value.to_string());
+            return;
+        }
+        self.push(field.name(), &json_string(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field.name(), &value.to_string());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field.name(), &value.to_string());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field.name(), &value.to_string());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        // JSON has no NaN/inf
+        if value.is_finite() {
+            self.push(field.name(), &value.to_string());
+        } else {
+            self.push(field.name(), &json_string(&value.to_string()));
+        }
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("{}.{:03}", now.as_secs(), now.subsec_millis())
+}
+
+#[cfg(unix)]
+fn send_to_host(report_fd: libc::c_int, record: &str) {
+    // a single write(): short records are atomic on a pipe, even with other threads of
+    // the worker at it too
+    unsafe {
+        libc::write(
+            report_fd,
+            record.as_ptr() as *const libc::c_void,
+            record.len(),
+        )
+    };
+}
+
+#[cfg(not(unix))]
+fn send_to_host(_report_fd: i32, _record: &str) {}
+
+/// The span of one guarded call; `outcome` gets recorded once it is known
+pub fn call_span(function: &str, mode: IsolationMode, call_id: u64) -> Span {
+    tracing::info_span!(
+        "ffi_call",
+        function = function,
+        mode = ?mode,
+        call_id = call_id,
+        outcome = tracing::field::Empty
+    )
+}
+
+/// Replays the events the worker sent back (the 'T' side records of its report) into the
+/// current span, i.e. the call's
+pub(crate) fn forward_worker_events(records: &[&str]) {
+    for record in records {
+        let Some(worker_event) = record.strip_prefix('T') else {
+            continue;
+        };
+        let level = ["ERROR", "WARN", "INFO", "DEBUG", "TRACE"]
+            .into_iter()
+            .find(|level| worker_event.contains(&format!("\"level\":\"{}\"", level)));
+        match level {
+            Some("ERROR") => tracing::event!(Level::ERROR, worker_event = worker_event),
+            Some("WARN") => tracing::event!(Level::WARN, worker_event = worker_event),
+            Some("DEBUG") => tracing::event!(Level::DEBUG, worker_event = worker_event),
+            Some("TRACE") => tracing::event!(Level::TRACE, worker_event = worker_event),
+            _ => tracing::event!(Level::INFO, worker_event = worker_event),
+        }
+    }
+}
+
+/// `<journal::private_dir()>/<program>.trace.jsonl`
+pub fn default_path() -> io::Result<PathBuf> {
+    crate::private_file_of_program("trace.jsonl")
+}
+
+/// Where the host's trace goes: `$FFI_TRACE` if set (`-` = stderr), else `default_path()`
+pub fn configured_path() -> io::Result<Option<PathBuf>> {
+    match std::env::var_os(TRACE_ENV) {
+        Some(path) if path == "-" => Ok(None),
+        Some(path) => Ok(Some(PathBuf::from(path))),
+        None => default_path().map(Some),
+    }
+}
+
+/// Installs THE subscriber of the host, writing to `path` (appending; no symlink followed,
+/// 0600 if we create it, see journal.rs), or to stderr if `None`; call this before
+/// `zygote::start_global()`, like `shared_log::init_global()`
+pub fn init_global(path: Option<&Path>) -> io::Result<()> {
+    let sink: Box<dyn Write + Send> = match path {
+        Some(path) => Box::new(
+            crate::journal::private_file_options()
+                .create(true)
+                .append(true)
+                .open(path)?,
+        ),
+        None => Box::new(io::stderr()),
+    };
+    let subscriber = tracing_subscriber::registry().with(JsonLines::new(sink));
+    tracing::subscriber::set_global_default(subscriber).map_err(io::Error::other)
+}
+
+/// Worker side: from now on, events go to the host over `report_fd`.  A forked worker
+/// already has the host's subscriber; a re-exec'ed one gets one here
+pub(crate) fn enter_worker(report_fd: i32) {
+    WORKER_FD.store(report_fd, Ordering::SeqCst);
+    let subscriber = tracing_subscriber::registry().with(JsonLines::new(Box::new(io::sink())));
+    let _ = tracing::subscriber::set_global_default(subscriber);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    // the layer's sink, readable by the test
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_worker_events_end_up_in_the_call_span() {
+        let captured = Captured::default();
+        let subscriber =
+            tracing_subscriber::registry().with(JsonLines::new(Box::new(captured.clone())));
+        let outcome = tracing::subscriber::with_default(subscriber, || {
+            let fn_chatty = || -> anyhow::Result<String> {
+                tracing::warn!(answer = 42, "hello \"host\"");
+                unsafe { libc::_exit(7) }
+            };
+            crate::metrics::name_function(fn_chatty, "chatty");
+            crate::isolation::call_isolated(IsolationMode::Fork, fn_chatty)
+        });
+        assert_eq!(outcome, crate::outcome::CallOutcome::Exited(7));
+
+        let trace = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", trace);
+        // the worker's event, replayed by the host inside the call's span
+        let event = lines[0];
+        assert!(event.starts_with("{\"type\":\"event\",\"origin\":\"worker\",\"ts\":"));
+        assert!(event.contains("\"level\":\"WARN\""));
+        assert!(event.contains("\"message\":\"hello \\\"host\\\"\",\"answer\":42,"));
+        assert!(!event.contains(&format!("\"pid\":{},", std::process::id())));
+        assert!(event.contains(
+            "\"spans\":[{\"name\":\"ffi_call\",\"function\":\"chatty\",\"mode\":\"Fork\",\"call_id\":"
+        ));
+        // and the span itself, with its outcome
+        let span = lines[1];
+        assert!(span.starts_with("{\"type\":\"span\",\"ts\":"));
+        assert!(span.contains("\"function\":\"chatty\""));
+        assert!(span.contains("\"outcome\":\"exited with status 7\",\"elapsed_ms\":"));
+    }
+}
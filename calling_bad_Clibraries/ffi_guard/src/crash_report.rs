@@ -0,0 +1,461 @@
+// src/crash_report.rs
+//
+// "killed by signal 11" tells us that mid_access_violation() died, but not where or how.
+// Here the worker installs its own handler for the fatal signals (on an alternate stack,
+// in case the stack is what got smashed), which writes a one-line crash record to the
+// report pipe before letting the signal kill the worker as usual:
+//
+//      C<signal> <si_code> <fault address> <pc> <access> <load bias> <image size> <frame>...
+//      (all numbers in hex; <access> is 0 = read, 1 = write, 2 = unknown)
+//
+// The host then symbolizes those addresses against the executable's debug info (the C
+// library is statically linked into it, and cc compiles it with -g for debug builds):
+//
+//      null write in mid_access_violation at mid_exit.c:44
+//          #0 0x000055d1c2a4b1f4 mid_access_violation at ./bad_c_libs/mid_exit.c:44
+//          #1 0x000055d1c2a3e5a1 calling_bad_Clibraries::do_seg_fault at src/main.rs:380
+//          ...
+//
+// NOTE: the handler itself is Linux/glibc only (it needs backtrace(), and the register
+// layout of ucontext_t); the parsing/symbolizing side works anywhere.
+
+use std::fmt;
+use std::path::Path;
+
+const MAX_FRAMES: usize = 48;
+// si_code of an integer division by zero (<asm-generic/siginfo.h>; libc does not export it)
+const FPE_INTDIV: i32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrashReport {
+    pub signal: i32,
+    pub code: i32,
+    pub fault_address: u64,
+    pub pc: u64,
+    pub access: Access,
+    /// where the worker's executable was loaded (to undo ASLR)
+    pub load_bias: u64,
+    /// how far the executable's segments extend past `load_bias`; frames beyond that
+    /// are in shared libraries (libc) which we do not symbolize
+    pub image_size: u64,
+    /// raw return addresses, innermost (the faulting pc) first
+    pub frames: Vec<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub address: u64,
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "0x{:016x} {}",
+            self.address,
+            self.function.as_deref().unwrap_or("??")
+        )?;
+        if let Some(file) = &self.file {
+            write!(f, " at {}:{}", file, self.line.unwrap_or(0))?;
+        }
+        Ok(())
+    }
+}
+
+impl CrashReport {
+    /// Picks the crash record out of whatever the worker wrote to the report pipe
+    pub fn parse(report: &str) -> Option<CrashReport> {
+        let record = report.lines().find(|line| line.starts_with('C'))?;
+        let mut fields = record[1..]
+            .split_whitespace()
+            .map(|field| u64::from_str_radix(field, 16));
+        let mut next = || fields.next()?.ok();
+        let signal = next()? as i32;
+        let code = next()? as i32;
+        let fault_address = next()?;
+        let pc = next()?;
+        let access = match next()? {
+            0 => Access::Read,
+            1 => Access::Write,
+            _ => Access::Unknown,
+        };
+        let load_bias = next()?;
+        let image_size = next()?;
+        let mut frames = Vec::new();
+        while let Some(frame) = next() {
+            frames.push(frame);
+        }
+        Some(CrashReport {
+            signal,
+            code,
+            fault_address,
+            pc,
+            access,
+            load_bias,
+            image_size,
+            frames,
+        })
+    }
+
+    /// i.e. "null write", "divide by zero"
+    pub fn description(&self) -> String {
+        let access = match self.access {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Unknown => "access",
+        };
+        #[cfg(not(unix))]
+        return format!("signal {} ({})", self.signal, access);
+        #[cfg(unix)]
+        match self.signal {
+            libc::SIGSEGV if self.fault_address < 4096 => format!("null {}", access),
+            libc::SIGSEGV => format!("invalid {} at 0x{:x}", access, self.fault_address),
+            libc::SIGBUS => format!("bus error ({}) at 0x{:x}", access, self.fault_address),
+            libc::SIGFPE if self.code == FPE_INTDIV => "divide by zero".into(),
+            libc::SIGFPE => "arithmetic exception".into(),
+            libc::SIGILL => "illegal instruction".into(),
+            other => format!("signal {}", other),
+        }
+    }
+
+    /// Resolves the frames against the debug info of `executable` (which has to be the
+    /// same binary the worker was running, i.e. `std::env::current_exe()`)
+    pub fn symbolize(&self, executable: &Path) -> Vec<Frame> {
+        let loader = addr2line::Loader::new(executable).ok();
+        self.frames
+            .iter()
+            .enumerate()
+            .map(|(i, &address)| {
+                let mut frame = Frame {
+                    address,
+                    function: None,
+                    file: None,
+                    line: None,
+                };
+                let offset = address.wrapping_sub(self.load_bias);
+                let (Some(loader), true) = (&loader, offset < self.image_size) else {
+                    return frame;
+                };
+                // everything but the faulting pc is a return address, which points to
+                // the instruction AFTER the call (possibly on the next line already)
+                let probe = offset.wrapping_sub(if i == 0 { 0 } else { 1 });
+                if let Ok(mut inlined) = loader.find_frames(probe) {
+                    if let Ok(Some(innermost)) = inlined.next() {
+                        frame.function = innermost
+                            .function
+                            .and_then(|name| name.demangle().ok().map(|name| name.into_owned()));
+                    }
+                }
+                if frame.function.is_none() {
+                    frame.function = loader
+                        .find_symbol(probe)
+                        .map(|name| addr2line::demangle_auto(name.into(), None).into_owned());
+                }
+                if let Ok(Some(location)) = loader.find_location(probe) {
+                    frame.file = location.file.map(String::from);
+                    frame.line = location.line;
+                }
+                frame
+            })
+            .collect()
+    }
+
+    /// "null write in mid_access_violation at mid_exit.c:44", followed by the frame list
+    pub fn render(&self, frames: &[Frame]) -> String {
+        let mut text = self.description();
+        if let Some(top) = frames.first() {
+            if let Some(function) = &top.function {
+                text.push_str(&format!(" in {}", function));
+            }
+            if let Some(file) = &top.file {
+                let file_name = Path::new(file)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| file.clone());
+                text.push_str(&format!(" at {}:{}", file_name, top.line.unwrap_or(0)));
+            }
+        }
+        for (i, frame) in frames.iter().enumerate() {
+            text.push_str(&format!("\n    #{} {}", i, frame));
+        }
+        text
+    }
+}
+
+// ############################## worker side (signal handler)
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+pub(crate) mod handler {
+    use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+
+    use super::MAX_FRAMES;
+
+    static REPORT_FD: AtomicI32 = AtomicI32::new(-1);
+    static LOAD_BIAS: AtomicU64 = AtomicU64::new(0);
+    static IMAGE_SIZE: AtomicU64 = AtomicU64::new(0);
+    const FATAL_SIGNALS: [libc::c_int; 4] =
+        [libc::SIGSEGV, libc::SIGBUS, libc::SIGFPE, libc::SIGILL];
+    const ALT_STACK_SIZE: usize = 64 * 1024;
+
+    pub fn install(report_fd: libc::c_int) {
+        REPORT_FD.store(report_fd, Ordering::SeqCst);
+        prepare();
+        unsafe {
+            // the worker is short lived, so the alternate stack is simply leaked
+            let stack = Box::leak(vec![0u8; ALT_STACK_SIZE].into_boxed_slice());
+            let alt_stack = libc::stack_t {
+                ss_sp: stack.as_mut_ptr() as *mut libc::c_void,
+                ss_flags: 0,
+                ss_size: ALT_STACK_SIZE,
+            };
+            libc::sigaltstack(&alt_stack, std::ptr::null_mut());
+
+            let mut action: libc::sigaction = std::mem::zeroed();
+            let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                on_fatal_signal;
+            action.sa_sigaction = handler as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK | libc::SA_RESETHAND;
+            libc::sigemptyset(&mut action.sa_mask);
+            for signal in FATAL_SIGNALS {
+                libc::sigaction(signal, &action, std::ptr::null_mut());
+            }
+        }
+    }
+
+    /// Everything `crash_record()` needs that cannot be done inside a signal handler; also
+    /// used by thread_guard.rs, which writes the same record for a faulting thread
+    pub(crate) fn prepare() {
+        let (bias, size) = executable_image();
+        LOAD_BIAS.store(bias, Ordering::SeqCst);
+        IMAGE_SIZE.store(size, Ordering::SeqCst);
+        // the first backtrace() dlopen()s libgcc_s, which is NOT something to do in a
+        // signal handler, so get that out of the way now
+        let mut warm_up = [std::ptr::null_mut(); 2];
+        unsafe { libc::backtrace(warm_up.as_mut_ptr(), warm_up.len() as libc::c_int) };
+    }
+
+    // (load bias, end of the last PT_LOAD segment) of the executable
+    #[allow(clippy::unnecessary_cast)] // Elf32 on 32-bit targets
+    fn executable_image() -> (u64, u64) {
+        unsafe extern "C" fn first_object(
+            info: *mut libc::dl_phdr_info,
+            _size: libc::size_t,
+            data: *mut libc::c_void,
+        ) -> libc::c_int {
+            let image = &mut *(data as *mut (u64, u64));
+            image.0 = (*info).dlpi_addr as u64;
+            for i in 0..(*info).dlpi_phnum as usize {
+                let header = &*(*info).dlpi_phdr.add(i);
+                if header.p_type == libc::PT_LOAD {
+                    image.1 = image.1.max(header.p_vaddr as u64 + header.p_memsz as u64);
+                }
+            }
+            1 // the first one is always the executable itself; stop here
+        }
+        let mut image: (u64, u64) = (0, 0);
+        unsafe {
+            libc::dl_iterate_phdr(
+                Some(first_object),
+                &mut image as *mut (u64, u64) as *mut libc::c_void,
+            )
+        };
+        image
+    }
+
+    // Fixed size buffer + hex formatter; NO allocation allowed in here
+    pub(crate) struct Record {
+        buf: [u8; 64 + 17 * (MAX_FRAMES + 8)],
+        len: usize,
+    }
+
+    impl Record {
+        pub(crate) const fn new() -> Self {
+            Record {
+                buf: [0; 64 + 17 * (MAX_FRAMES + 8)],
+                len: 0,
+            }
+        }
+
+        pub(crate) fn as_bytes(&self) -> &[u8] {
+            &self.buf[..self.len]
+        }
+
+        fn push(&mut self, byte: u8) {
+            if self.len < self.buf.len() {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            }
+        }
+
+        fn push_hex(&mut self, value: u64) {
+            let mut digits = [0u8; 16];
+            let mut count = 0;
+            let mut value = value;
+            loop {
+                digits[count] = b"0123456789abcdef"[(value & 0xf) as usize];
+                count += 1;
+                value >>= 4;
+                if value == 0 {
+                    break;
+                }
+            }
+            while count > 0 {
+                count -= 1;
+                self.push(digits[count]);
+            }
+        }
+    }
+
+    pub(crate) unsafe fn faulting_pc_and_access(context: *mut libc::c_void) -> (u64, u64) {
+        let context = context as *mut libc::ucontext_t;
+        #[cfg(target_arch = "x86_64")]
+        {
+            let gregs = &(*context).uc_mcontext.gregs;
+            // bit 1 of the page-fault error code is "this was a write"
+            let access = (gregs[libc::REG_ERR as usize] as u64 >> 1) & 1;
+            (gregs[libc::REG_RIP as usize] as u64, access)
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            ((*context).uc_mcontext.pc, 2)
+        }
+        #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+        {
+            let _ = context;
+            (0, 2)
+        }
+    }
+
+    extern "C" fn on_fatal_signal(
+        signal: libc::c_int,
+        info: *mut libc::siginfo_t,
+        context: *mut libc::c_void,
+    ) {
+        let mut record = Record::new();
+        unsafe {
+            crash_record(signal, info, context, &mut record);
+            libc::write(
+                REPORT_FD.load(Ordering::SeqCst),
+                record.buf.as_ptr() as *const libc::c_void,
+                record.len,
+            );
+        }
+        // SA_RESETHAND put the default action back; returning re-runs the faulting
+        // instruction, which now kills us with the original signal
+    }
+
+    /// Formats the "C..." crash record of the signal being handled into `record`; only
+    /// async-signal-safe calls in here (backtrace() is, once `prepare()` warmed it up)
+    pub(crate) unsafe fn crash_record(
+        signal: libc::c_int,
+        info: *mut libc::siginfo_t,
+        context: *mut libc::c_void,
+        record: &mut Record,
+    ) {
+        unsafe {
+            let (pc, mut access) = faulting_pc_and_access(context);
+            if signal != libc::SIGSEGV && signal != libc::SIGBUS {
+                access = 2;
+            }
+            let mut frames = [std::ptr::null_mut(); MAX_FRAMES];
+            let count = libc::backtrace(frames.as_mut_ptr(), MAX_FRAMES as libc::c_int) as usize;
+            // skip ourselves and the signal trampoline, start at the faulting pc
+            let start = frames[..count]
+                .iter()
+                .position(|&frame| frame as u64 == pc)
+                .unwrap_or(count.min(2));
+
+            record.len = 0;
+            record.push(b'C');
+            let header = [
+                signal as u64,
+                (*info).si_code as u64,
+                (*info).si_addr() as u64,
+                pc,
+                access,
+                LOAD_BIAS.load(Ordering::SeqCst),
+                IMAGE_SIZE.load(Ordering::SeqCst),
+            ];
+            for (i, value) in header.into_iter().enumerate() {
+                if i > 0 {
+                    record.push(b' ');
+                }
+                record.push_hex(value);
+            }
+            if start >= count || frames[start] as u64 != pc {
+                // the unwinder did not make it through the signal frame; pc is all we have
+                record.push(b' ');
+                record.push_hex(pc);
+            }
+            for frame in &frames[start.min(count)..count] {
+                record.push(b' ');
+                record.push_hex(*frame as u64);
+            }
+            record.push(b'\n');
+        }
+    }
+}
+
+/// Installs the crash reporter in the CURRENT process; only to be called in a worker
+pub(crate) fn install(report_fd: libc::c_int) {
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    handler::install(report_fd);
+    #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+    let _ = report_fd;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_describe() {
+        let report = CrashReport::parse(
+            "Cb 1 0 55d1c2a4b1f4 1 55d1c2a00000 400000 55d1c2a4b1f4 55d1c2a3e5a1\n",
+        )
+        .unwrap();
+        assert_eq!(report.signal, libc::SIGSEGV);
+        assert_eq!(report.fault_address, 0);
+        assert_eq!(report.access, Access::Write);
+        assert_eq!(report.frames, vec![0x55d1c2a4b1f4, 0x55d1c2a3e5a1]);
+        assert_eq!(report.description(), "null write");
+
+        // a normal report is not a crash record
+        assert_eq!(CrashReport::parse("RSuccess!"), None);
+    }
+
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    #[test]
+    fn test_symbolized_crash_in_forked_worker() {
+        use crate::isolation::{call_isolated_report, IsolationMode};
+        use crate::outcome::CallOutcome;
+        use crate::sandbox::SandboxLimits;
+
+        #[inline(never)]
+        fn write_to_null() -> anyhow::Result<String> {
+            unsafe { std::ptr::write_volatile(std::ptr::null_mut::<u32>(), 42) };
+            Ok("unreachable".into())
+        }
+        let call = call_isolated_report(
+            IsolationMode::Fork,
+            &SandboxLimits::default(),
+            write_to_null,
+        );
+        assert_eq!(call.outcome, CallOutcome::Signaled(libc::SIGSEGV));
+        let report = call.crash.expect("crash record from the worker");
+        assert_eq!(report.description(), "null write");
+        let frames = report.symbolize(&std::env::current_exe().unwrap());
+        let rendered = report.render(&frames);
+        assert!(rendered.contains("write_to_null"), "{}", rendered);
+    }
+}
@@ -0,0 +1,422 @@
+// src/dyn_call.rs
+//
+// The `ffi` module only knows the functions bindgen saw in mid_exit.h, at build time.  To
+// poke at a new vendor `.so` we would rather not write a header and rebuild for every
+// function we want to try, hence `cargo run -- call <lib> <symbol> <signature> [args...]`:
+//
+//      $ cargo run -- call libmid_exit.so mid_checksum "int(int, int)" 10 3
+//
+// The library is dlopen()ed and the symbol dlsym()ed INSIDE the worker, so a library whose
+// constructor crashes (or exit()s) is just as contained as a function that does.
+//
+// How do we call a function whose signature we only learn at runtime, without libffi?  On
+// the two ABIs we care about (x86_64 SysV, aarch64 AAPCS64), integer/pointer arguments go
+// into integer registers in order (rdi, rsi, ... / x0, x1, ...) and double arguments go
+// into the float registers in order (xmm0, ... / v0, ...), each sequence independent of
+// the other.  So calling through
+//      extern "C" fn(i64 x 6, f64 x 8) -> i64 (or -> f64)
+// with the int arguments first-come-first-served in the i64 slots and the doubles in the
+// f64 slots puts every argument exactly where the callee looks for it; whatever is left
+// over is ignored by the callee.  The return value is in rax/x0 or xmm0/v0.
+// Hence the limits: at most 6 int-ish and 8 double arguments, no float, no structs by
+// value, no variadics (printf() and friends want the number of vector registers in al).
+
+use std::ffi::{c_void, CStr, CString};
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::isolation::{call_isolated_report, CallReport, IsolationMode};
+use crate::sandbox::SandboxLimits;
+
+const MAX_INT_ARGS: usize = 6;
+const MAX_DOUBLE_ARGS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CType {
+    Void,
+    Int,
+    UInt,
+    Long,
+    ULong,
+    Double,
+    /// NUL-terminated string (`char*`, `const char*`)
+    Str,
+    /// any other pointer; we can pass NULL or an address, and print what comes back
+    Ptr,
+}
+
+impl CType {
+    pub fn parse(name: &str) -> Result<CType, String> {
+        // "const char *" -> "constchar*", "unsigned  int" -> "unsignedint"
+        let name: String = name.split_whitespace().collect();
+        let name = name.strip_prefix("const").unwrap_or(&name);
+        Ok(match name {
+            "void" => CType::Void,
+            "int" | "int32_t" => CType::Int,
+            "unsigned" | "unsignedint" | "uint32_t" => CType::UInt,
+            "long" | "longlong" | "int64_t" | "ssize_t" => CType::Long,
+            "unsignedlong" | "unsignedlonglong" | "uint64_t" | "size_t" => CType::ULong,
+            "double" => CType::Double,
+            "char*" => CType::Str,
+            other if other.ends_with('*') => CType::Ptr,
+            other => return Err(format!("unsupported type {:?}", other)),
+        })
+    }
+}
+
+/// "int(int, int)", "double(double)", "void()", "char*(const char*)"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub ret: CType,
+    pub args: Vec<CType>,
+}
+
+impl Signature {
+    pub fn parse(spec: &str) -> Result<Signature, String> {
+        let (ret, rest) = spec
+            .split_once('(')
+            .ok_or_else(|| format!("{:?}: expected RET(ARG, ...)", spec))?;
+        let args = rest
+            .trim_end()
+            .strip_suffix(')')
+            .ok_or_else(|| format!("{:?}: missing ')'", spec))?;
+        let mut arg_types = Vec::new();
+        for arg in args.split(',').map(str::trim).filter(|arg| !arg.is_empty()) {
+            match CType::parse(arg)? {
+                // "int(void)" is "int()"
+                CType::Void if args.trim() == "void" => {}
+                CType::Void => return Err(format!("{:?}: void argument", spec)),
+                arg_type => arg_types.push(arg_type),
+            }
+        }
+        let signature = Signature {
+            ret: CType::parse(ret)?,
+            args: arg_types,
+        };
+        let doubles = signature.doubles();
+        if doubles > MAX_DOUBLE_ARGS || signature.args.len() - doubles > MAX_INT_ARGS {
+            return Err(format!(
+                "{:?}: at most {} int/pointer and {} double arguments",
+                spec, MAX_INT_ARGS, MAX_DOUBLE_ARGS
+            ));
+        }
+        Ok(signature)
+    }
+
+    fn doubles(&self) -> usize {
+        self.args
+            .iter()
+            .filter(|&&arg| arg == CType::Double)
+            .count()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    Int(i64),
+    Double(f64),
+    Str(CString),
+}
+
+impl Arg {
+    /// `text` as a `c_type`; pointers take "NULL" or an address ("0x7f...")
+    pub fn parse(c_type: CType, text: &str) -> Result<Arg, String> {
+        let bad = |e: &dyn fmt::Display| format!("{:?} as {:?}: {}", text, c_type, e);
+        Ok(match c_type {
+            CType::Int => Arg::Int(text.parse::<i32>().map_err(|e| bad(&e))? as i64),
+            CType::UInt => Arg::Int(text.parse::<u32>().map_err(|e| bad(&e))? as i64),
+            CType::Long => Arg::Int(text.parse::<i64>().map_err(|e| bad(&e))?),
+            CType::ULong => Arg::Int(text.parse::<u64>().map_err(|e| bad(&e))? as i64),
+            CType::Double => Arg::Double(text.parse().map_err(|e| bad(&e))?),
+            CType::Str => Arg::Str(CString::new(text).map_err(|e| bad(&e))?),
+            CType::Ptr if text == "NULL" || text == "0" => Arg::Int(0),
+            CType::Ptr => {
+                let hex = text
+                    .strip_prefix("0x")
+                    .ok_or_else(|| bad(&"expected NULL or 0x..."))?;
+                Arg::Int(u64::from_str_radix(hex, 16).map_err(|e| bad(&e))? as i64)
+            }
+            CType::Void => return Err(bad(&"void argument")),
+        })
+    }
+}
+
+/// Everything the worker needs to make the call
+#[derive(Debug, Clone, PartialEq)]
+pub struct DynCall {
+    pub library: String,
+    pub symbol: String,
+    pub signature: Signature,
+    pub args: Vec<Arg>,
+}
+
+impl DynCall {
+    /// From the command line: `<lib> <symbol> <signature> [args...]`
+    pub fn parse(
+        library: &str,
+        symbol: &str,
+        signature: &str,
+        args: &[String],
+    ) -> Result<DynCall, String> {
+        let signature = Signature::parse(signature)?;
+        if args.len() != signature.args.len() {
+            return Err(format!(
+                "{} takes {} arguments, got {}",
+                symbol,
+                signature.args.len(),
+                args.len()
+            ));
+        }
+        let args = signature
+            .args
+            .iter()
+            .zip(args)
+            .map(|(&c_type, text)| Arg::parse(c_type, text))
+            .collect::<Result<_, _>>()?;
+        Ok(DynCall {
+            library: library.to_string(),
+            symbol: symbol.to_string(),
+            signature,
+            args,
+        })
+    }
+
+    /// The arguments as `Display` has them, for the recording (see record_replay.rs)
+    pub fn arg_texts(&self) -> Vec<String> {
+        self.args.iter().map(Arg::to_string).collect()
+    }
+
+    /// Loads the library and makes the call, in-place; `call_isolated()` is the one that
+    /// does it in a worker
+    #[cfg(all(unix, any(target_arch = "x86_64", target_arch = "aarch64")))]
+    pub fn invoke(&self) -> anyhow::Result<String> {
+        #[rustfmt::skip]
+        type IntFn = unsafe extern "C" fn(i64, i64, i64, i64, i64, i64, f64, f64, f64, f64, f64, f64, f64, f64) -> i64;
+        #[rustfmt::skip]
+        type DoubleFn = unsafe extern "C" fn(i64, i64, i64, i64, i64, i64, f64, f64, f64, f64, f64, f64, f64, f64) -> f64;
+
+        let address = self.resolve()?;
+        let mut ints = [0i64; MAX_INT_ARGS];
+        let mut doubles = [0f64; MAX_DOUBLE_ARGS];
+        let (mut next_int, mut next_double) = (0, 0);
+        for arg in &self.args {
+            match arg {
+                Arg::Int(value) => {
+                    ints[next_int] = *value;
+                    next_int += 1;
+                }
+                Arg::Str(value) => {
+                    ints[next_int] = value.as_ptr() as i64;
+                    next_int += 1;
+                }
+                Arg::Double(value) => {
+                    doubles[next_double] = *value;
+                    next_double += 1;
+                }
+            }
+        }
+        let [i0, i1, i2, i3, i4, i5] = ints;
+        let [d0, d1, d2, d3, d4, d5, d6, d7] = doubles;
+
+        // SAFETY: none, really; we take the caller's word for the signature (see the top
+        // of this file for why the register layout works out), and we run in a worker
+        unsafe {
+            if self.signature.ret == CType::Double {
+                let function: DoubleFn = std::mem::transmute(address);
+                let value = function(i0, i1, i2, i3, i4, i5, d0, d1, d2, d3, d4, d5, d6, d7);
+                return Ok(value.to_string());
+            }
+            let function: IntFn = std::mem::transmute(address);
+            let value = function(i0, i1, i2, i3, i4, i5, d0, d1, d2, d3, d4, d5, d6, d7);
+            // only the low bits of the register are defined for the narrower types
+            Ok(match self.signature.ret {
+                CType::Void => "(void)".to_string(),
+                CType::Int => (value as i32).to_string(),
+                CType::UInt => (value as u32).to_string(),
+                CType::Long => value.to_string(),
+                CType::ULong => (value as u64).to_string(),
+                CType::Str if value == 0 => "NULL".to_string(),
+                CType::Str => format!(
+                    "{:?}",
+                    CStr::from_ptr(value as *const libc::c_char).to_string_lossy()
+                ),
+                CType::Ptr => format!("0x{:x}", value as u64),
+                CType::Double => unreachable!(),
+            })
+        }
+    }
+
+    #[cfg(not(all(unix, any(target_arch = "x86_64", target_arch = "aarch64"))))]
+    pub fn invoke(&self) -> anyhow::Result<String> {
+        anyhow::bail!("dynamic calls are only implemented for x86_64/aarch64 unix")
+    }
+
+    /// dlopen() + dlsym(); the library is never dlclose()d, the worker is short-lived
+    #[cfg(unix)]
+    fn resolve(&self) -> anyhow::Result<*const c_void> {
+        let library = CString::new(self.library.as_str())?;
+        let symbol = CString::new(self.symbol.as_str())?;
+        let dl_error = || {
+            let error = unsafe { libc::dlerror() };
+            if error.is_null() {
+                "unknown error".to_string()
+            } else {
+                unsafe { CStr::from_ptr(error) }
+                    .to_string_lossy()
+                    .into_owned()
+            }
+        };
+        let handle = unsafe { libc::dlopen(library.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            anyhow::bail!("dlopen({}): {}", self.library, dl_error());
+        }
+        let address = unsafe { libc::dlsym(handle, symbol.as_ptr()) };
+        if address.is_null() {
+            anyhow::bail!("dlsym({}): {}", self.symbol, dl_error());
+        }
+        Ok(address as *const c_void)
+    }
+}
+
+impl fmt::Display for DynCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}(", self.symbol)?;
+        for (i, arg) in self.args.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", arg)?;
+        }
+        write!(f, ") from {}", self.library)
+    }
+}
+
+impl fmt::Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arg::Int(value) => write!(f, "{}", value),
+            Arg::Double(value) => write!(f, "{}", value),
+            Arg::Str(value) => write!(f, "{:?}", value),
+        }
+    }
+}
+
+// Libraries that are not on the loader's search path, by the name they go by (i.e. the
+// demo's libmid_exit.so, which its build.rs leaves in OUT_DIR)
+static LIBRARIES: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+/// From now on, `find_library(name)` is `path`
+pub fn register_library(name: &str, path: &str) {
+    let mut libraries = LIBRARIES
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    libraries.retain(|(known, _)| known != name);
+    libraries.push((name.to_string(), path.to_string()));
+}
+
+/// `name` as dlopen() should get it: the path it was registered with (see
+/// `register_library()`), else `name` itself, which is left to dlopen() (a path, or a
+/// name looked up in LD_LIBRARY_PATH & co)
+pub fn find_library(name: &str) -> String {
+    let libraries = LIBRARIES
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    match libraries.iter().find(|(known, _)| known == name) {
+        Some((_, path)) => path.clone(),
+        None => name.to_string(),
+    }
+}
+
+// The call is handed to the worker by inheritance, same as the fuzzer's input (see fuzz.rs),
+// hence `IsolationMode::Fork`
+static CALL_LOCK: Mutex<()> = Mutex::new(());
+static NEXT_CALL: Mutex<Option<DynCall>> = Mutex::new(None);
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn run_next_call() -> anyhow::Result<String> {
+    let Some(call) = lock(&NEXT_CALL).clone() else {
+        anyhow::bail!("dyn_call worker started without a call");
+    };
+    call.invoke()
+}
+
+/// `call.invoke()` in a forked worker
+pub fn call_isolated(call: &DynCall, limits: &SandboxLimits) -> CallReport {
+    // one call at a time, else another thread could swap NEXT_CALL out from under our
+    // worker before it gets forked
+    let _serialized = lock(&CALL_LOCK);
+    *lock(&NEXT_CALL) = Some(call.clone());
+    crate::metrics::name_function(run_next_call, &call.symbol);
+    crate::record_replay::set_arguments(call.arg_texts());
+    let report = call_isolated_report(IsolationMode::Fork, limits, run_next_call);
+    *lock(&NEXT_CALL) = None;
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let signature = Signature::parse("char* ( const char *, double,unsigned int)").unwrap();
+        assert_eq!(signature.ret, CType::Str);
+        assert_eq!(signature.args, vec![CType::Str, CType::Double, CType::UInt]);
+        assert_eq!(Signature::parse("int(void)").unwrap().args, vec![]);
+        assert!(Signature::parse("int").is_err());
+        assert!(Signature::parse("int(float)").is_err());
+        assert!(Signature::parse("int(int,int,int,int,int,int,int)").is_err());
+
+        let args = ["5".to_string()];
+        let call = DynCall::parse("libm.so.6", "abs", "int(int)", &args).unwrap();
+        assert_eq!(call.args, vec![Arg::Int(5)]);
+        assert!(DynCall::parse("libm.so.6", "abs", "int(int)", &[]).is_err());
+        assert!(DynCall::parse("libm.so.6", "abs", "int(int)", &["x".to_string()]).is_err());
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn test_invoke_libc_and_libm() {
+        use crate::outcome::CallOutcome;
+
+        let call = |symbol: &str, signature: &str, args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            let call = DynCall::parse("libm.so.6", symbol, signature, &args).unwrap();
+            call_isolated(&call, &SandboxLimits::default()).outcome
+        };
+        // ints and doubles mixed: ldexp(double, int)
+        assert_eq!(
+            call("ldexp", "double(double, int)", &["1.5", "4"]),
+            CallOutcome::Returned("24".into())
+        );
+        assert_eq!(
+            call("pow", "double(double, double)", &["2", "10"]),
+            CallOutcome::Returned("1024".into())
+        );
+        // libm pulls in libc, dlsym() finds it through the handle's dependencies
+        assert_eq!(
+            call("strlen", "size_t(const char*)", &["hello"]),
+            CallOutcome::Returned("5".into())
+        );
+        assert_eq!(
+            call("abs", "int(int)", &["-7"]),
+            CallOutcome::Returned("7".into())
+        );
+        assert!(matches!(
+            call("no_such_symbol", "void()", &[]),
+            CallOutcome::Failed(_)
+        ));
+        assert_eq!(
+            call("strlen", "size_t(const char*)", &["NULL"]),
+            CallOutcome::Returned("4".into()) // "NULL" is a string here, not a pointer
+        );
+    }
+}
@@ -0,0 +1,474 @@
+// src/fuzz.rs
+//
+// Before we upgrade a vendor library, we would like to know what (else) it does with
+// arguments nobody thought of.  The fuzzer calls a bound C function (i.e. `mid_exit(int)`,
+// `mid_checksum(int, int)`) over and over with generated arguments, each attempt in its
+// own forked worker (so crashing is fine, that is the point), with a wall-clock limit (so
+// hanging is fine too).  Whatever did not come back normally is a finding:
+//
+//  - findings are bucketed by outcome ("killed by signal 11") and crash site (the top
+//    frame of the worker's crash report, see crash_report.rs), so that 300 inputs that
+//    hit the same bug are ONE finding
+//  - each bucket's input is then minimized: every argument is pulled towards 0 for as
+//    long as the call still lands in the same bucket, so the case reads as "what is the
+//    smallest thing that breaks it" rather than "-1863072150 did"
+//  - `save_case()` writes each bucket as a regression case (a small text file), which
+//    `Fuzzer::replay()` re-runs against the next version of the library
+//
+// NOTE: the input is handed to the worker by inheritance (it is in our memory when we
+// fork), hence `IsolationMode::Fork`; fine for a single threaded fuzzer run from main().
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::crash_report::CrashReport;
+use crate::isolation::{call_isolated_report, IsolationMode};
+use crate::outcome::CallOutcome;
+use crate::sandbox::SandboxLimits;
+
+/// A C function that takes `arity` ints
+#[derive(Debug, Clone, Copy)]
+pub struct FuzzTarget {
+    pub name: &'static str,
+    pub arity: usize,
+    /// calls the C function with `args` (always `arity` of them), inside the worker
+    pub call: fn(&[i32]) -> anyhow::Result<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FuzzConfig {
+    pub iterations: usize,
+    pub seed: u64,
+    /// the wall-clock limit is what turns a hang into a finding
+    pub limits: SandboxLimits,
+    /// max calls spent on minimizing each finding
+    pub minimize_attempts: usize,
+}
+
+impl Default for FuzzConfig {
+    fn default() -> Self {
+        FuzzConfig {
+            iterations: 500,
+            seed: 1,
+            limits: SandboxLimits {
+                wall_time_secs: Some(1),
+                ..Default::default()
+            },
+            minimize_attempts: 200,
+        }
+    }
+}
+
+/// What makes two findings the same: how the worker died, and where
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Bucket {
+    pub outcome: String,
+    /// top frame of the crash ("mid_checksum at mid_exit.c:111"), if the worker crashed
+    pub site: Option<String>,
+}
+
+impl fmt::Display for Bucket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.outcome)?;
+        if let Some(site) = &self.site {
+            write!(f, " in {}", site)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub bucket: Bucket,
+    /// the first generated input that landed in the bucket
+    pub original: Vec<i32>,
+    /// the simplest input found that still lands in the same bucket
+    pub minimized: Vec<i32>,
+    /// how many of the generated inputs landed in it
+    pub hits: usize,
+}
+
+/// A saved finding (see `save_case()`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Case {
+    pub target: String,
+    pub args: Vec<i32>,
+    pub bucket: Bucket,
+}
+
+pub struct Fuzzer {
+    config: FuzzConfig,
+    executable: PathBuf,
+    // crash site by pc (relative to the load bias); symbolizing means loading the debug
+    // info, which we would rather not do for every single crash
+    sites: HashMap<u64, String>,
+}
+
+impl Fuzzer {
+    pub fn new(config: FuzzConfig) -> Fuzzer {
+        Fuzzer {
+            config,
+            executable: std::env::current_exe().unwrap_or_default(),
+            sites: HashMap::new(),
+        }
+    }
+
+    /// Calls `target(args)` once, in a worker; `None` if it came back normally (returned,
+    /// or failed the Rust way), else the bucket it landed in
+    pub fn run(&mut self, target: &FuzzTarget, args: &[i32]) -> Option<Bucket> {
+        *lock_next_input() = Some((*target, args.to_vec()));
+        crate::metrics::name_function(run_next_input, target.name);
+        let report = call_isolated_report(IsolationMode::Fork, &self.config.limits, run_next_input);
+        lock_next_input().take();
+        if matches!(
+            report.outcome,
+            CallOutcome::Returned(_) | CallOutcome::Failed(_)
+        ) {
+            return None;
+        }
+        Some(Bucket {
+            outcome: report.outcome.to_string(),
+            site: report.crash.as_ref().map(|crash| self.crash_site(crash)),
+        })
+    }
+
+    /// `config.iterations` generated inputs, bucketed and minimized, in bucket order
+    pub fn fuzz(&mut self, target: &FuzzTarget) -> Vec<Finding> {
+        let mut rng = Rng::new(self.config.seed);
+        let mut found: HashMap<Bucket, (Vec<i32>, usize)> = HashMap::new();
+        for _ in 0..self.config.iterations {
+            let args: Vec<i32> = (0..target.arity).map(|_| rng.arg()).collect();
+            if let Some(bucket) = self.run(target, &args) {
+                found.entry(bucket).or_insert((args, 0)).1 += 1;
+            }
+        }
+        let mut findings: Vec<Finding> = found
+            .into_iter()
+            .map(|(bucket, (original, hits))| Finding {
+                minimized: self.minimize(target, &original, &bucket),
+                bucket,
+                original,
+                hits,
+            })
+            .collect();
+        findings.sort_by(|a, b| a.bucket.cmp(&b.bucket));
+        findings
+    }
+
+    /// Pulls the arguments towards 0, one at a time, for as long as `target` still lands
+    /// in `bucket` (and we have attempts left)
+    pub fn minimize(&mut self, target: &FuzzTarget, args: &[i32], bucket: &Bucket) -> Vec<i32> {
+        let mut best = args.to_vec();
+        let mut attempts = 0;
+        'improving: loop {
+            for i in 0..best.len() {
+                for candidate in shrink_candidates(best[i]) {
+                    if attempts == self.config.minimize_attempts {
+                        break 'improving;
+                    }
+                    attempts += 1;
+                    let mut args = best.clone();
+                    args[i] = candidate;
+                    if self.run(target, &args).as_ref() == Some(bucket) {
+                        best = args;
+                        continue 'improving;
+                    }
+                }
+            }
+            break;
+        }
+        best
+    }
+
+    /// Re-runs a saved case; `Ok(())` if it still lands in the same bucket, else where
+    /// it landed this time (`None` = it came back normally)
+    pub fn replay(&mut self, targets: &[FuzzTarget], case: &Case) -> Result<(), Option<Bucket>> {
+        let Some(target) = targets.iter().find(|target| target.name == case.target) else {
+            return Err(Some(Bucket {
+                outcome: format!("no fuzz target named {}", case.target),
+                site: None,
+            }));
+        };
+        if case.args.len() != target.arity {
+            return Err(Some(Bucket {
+                outcome: format!("{} takes {} arguments", target.name, target.arity),
+                site: None,
+            }));
+        }
+        match self.run(target, &case.args) {
+            Some(bucket) if bucket == case.bucket => Ok(()),
+            other => Err(other),
+        }
+    }
+
+    fn crash_site(&mut self, crash: &CrashReport) -> String {
+        let offset = crash.pc.wrapping_sub(crash.load_bias);
+        if let Some(site) = self.sites.get(&offset) {
+            return site.clone();
+        }
+        let top = crash.symbolize(&self.executable).into_iter().next();
+        let site = match top.and_then(|frame| Some((frame.function?, frame.file, frame.line))) {
+            Some((function, Some(file), line)) => {
+                let file_name = Path::new(&file)
+                    .file_name()
+                    .map_or(file.clone(), |name| name.to_string_lossy().into_owned());
+                format!("{} at {}:{}", function, file_name, line.unwrap_or(0))
+            }
+            Some((function, None, _)) => function,
+            None => format!("pc +0x{:x}", offset),
+        };
+        self.sites.insert(offset, site.clone());
+        site
+    }
+}
+
+// ############################## the worker side
+
+// The input of the next call; the forked worker inherits it (see the NOTE at the top)
+static NEXT_INPUT: Mutex<Option<(FuzzTarget, Vec<i32>)>> = Mutex::new(None);
+
+fn lock_next_input() -> std::sync::MutexGuard<'static, Option<(FuzzTarget, Vec<i32>)>> {
+    NEXT_INPUT
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn run_next_input() -> anyhow::Result<String> {
+    let Some((target, args)) = lock_next_input().clone() else {
+        anyhow::bail!("fuzz worker started without an input");
+    };
+    silence_stdio();
+    (target.call)(&args)
+}
+
+// 500 attempts would print 500 "mid_exit(): Calling exit() now..." otherwise
+#[cfg(unix)]
+fn silence_stdio() {
+    let null = unsafe { libc::open(c"/dev/null".as_ptr(), libc::O_WRONLY) };
+    if null >= 0 {
+        unsafe {
+            libc::dup2(null, libc::STDOUT_FILENO);
+            libc::dup2(null, libc::STDERR_FILENO);
+            libc::close(null);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn silence_stdio() {}
+
+// ############################## inputs
+
+// The usual suspects: boundaries, sizes, and the odd value mid_exit() has seen before
+const INTERESTING: [i32; 16] = [
+    0,
+    1,
+    -1,
+    2,
+    7,
+    13,
+    64,
+    255,
+    256,
+    -666,
+    1 << 16,
+    1 << 24,
+    -(1 << 24),
+    i32::MAX,
+    i32::MIN,
+    i32::MIN + 1,
+];
+
+// xorshift64*; no need for a crate, and the same seed gives the same run on every box
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // half interesting values, a quarter small ones, a quarter anything at all
+    fn arg(&mut self) -> i32 {
+        match self.next() % 4 {
+            0 | 1 => INTERESTING[(self.next() % INTERESTING.len() as u64) as usize],
+            2 => (self.next() % 256) as i32 - 128,
+            _ => self.next() as i32,
+        }
+    }
+}
+
+// Simpler values first: 0, 1, -1, then halfway to 0, a quarter of the way, ..., one step
+fn shrink_candidates(value: i32) -> Vec<i32> {
+    let mut candidates = vec![0, 1, -1];
+    let mut step = value / 2;
+    while step != 0 {
+        candidates.push(value - step);
+        step /= 2;
+    }
+    candidates.retain(|&candidate| is_simpler(candidate, value));
+    candidates.dedup();
+    candidates
+}
+
+// closer to 0 is simpler; of the same magnitude, positive is simpler
+fn is_simpler(a: i32, b: i32) -> bool {
+    (a.unsigned_abs(), a < 0) < (b.unsigned_abs(), b < 0)
+}
+
+// ############################## regression cases
+//
+// One small text file per bucket, named after the target and a hash of the bucket (so
+// that finding the same bug again overwrites its case):
+//
+//      target mid_checksum
+//      args 0 0
+//      outcome killed by signal 8
+//      site mid_checksum at mid_exit.c:111
+
+/// Writes `finding` as `<dir>/<target>_<bucket hash>.case`
+pub fn save_case(dir: &Path, target: &FuzzTarget, finding: &Finding) -> io::Result<PathBuf> {
+    let case = Case {
+        target: target.name.to_string(),
+        args: finding.minimized.clone(),
+        bucket: finding.bucket.clone(),
+    };
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}_{:016x}.case", target.name, case.bucket.hash()));
+    fs::write(&path, case.encode())?;
+    Ok(path)
+}
+
+/// All the cases in `dir` (sorted by file name); files that do not parse are skipped
+pub fn load_cases(dir: &Path) -> io::Result<Vec<(PathBuf, Case)>> {
+    let mut cases = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "case")
+        {
+            if let Some(case) = Case::decode(&fs::read_to_string(&path)?) {
+                cases.push((path, case));
+            }
+        }
+    }
+    cases.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(cases)
+}
+
+impl Bucket {
+    // FNV-1a; unlike DefaultHasher, guaranteed to give the same file name next year
+    fn hash(&self) -> u64 {
+        let text = format!("{}\n{}", self.outcome, self.site.as_deref().unwrap_or(""));
+        text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+}
+
+impl Case {
+    fn encode(&self) -> String {
+        let args: Vec<String> = self.args.iter().map(|arg| arg.to_string()).collect();
+        let mut text = format!(
+            "target {}\nargs {}\noutcome {}\n",
+            self.target,
+            args.join(" "),
+            self.bucket.outcome
+        );
+        if let Some(site) = &self.bucket.site {
+            text.push_str(&format!("site {}\n", site));
+        }
+        text
+    }
+
+    fn decode(text: &str) -> Option<Case> {
+        let mut fields: HashMap<&str, &str> = HashMap::new();
+        for line in text.lines() {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            fields.insert(key, value);
+        }
+        Some(Case {
+            target: fields.get("target")?.to_string(),
+            args: fields
+                .get("args")?
+                .split_whitespace()
+                .map(|arg| arg.parse().ok())
+                .collect::<Option<_>>()?,
+            bucket: Bucket {
+                outcome: fields.get("outcome")?.to_string(),
+                site: fields.get("site").map(|site| site.to_string()),
+            },
+        })
+    }
+}
+
+impl fmt::Display for Case {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let args: Vec<String> = self.args.iter().map(|arg| arg.to_string()).collect();
+        write!(f, "{}({}) -> {}", self.target, args.join(", "), self.bucket)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    // exits above 1000, aborts below -5, fine in between
+    fn picky(args: &[i32]) -> anyhow::Result<String> {
+        if args[0] > 1000 {
+            unsafe { libc::_exit(7) };
+        }
+        if args[0] < -5 {
+            std::process::abort();
+        }
+        Ok(format!("{} is fine", args[0]))
+    }
+
+    #[test]
+    fn test_buckets_minimization_and_cases() {
+        let target = FuzzTarget {
+            name: "picky",
+            arity: 1,
+            call: picky,
+        };
+        let mut fuzzer = Fuzzer::new(FuzzConfig {
+            iterations: 40,
+            ..Default::default()
+        });
+        let findings = fuzzer.fuzz(&target);
+        let mut minimized: Vec<(String, Vec<i32>)> = findings
+            .iter()
+            .map(|finding| (finding.bucket.outcome.clone(), finding.minimized.clone()))
+            .collect();
+        minimized.sort();
+        assert_eq!(
+            minimized,
+            vec![
+                ("exited with status 7".to_string(), vec![1001]),
+                (format!("killed by signal {}", libc::SIGABRT), vec![-6]),
+            ]
+        );
+
+        let dir = std::env::temp_dir().join(format!("fuzz_cases_{}", std::process::id()));
+        for finding in &findings {
+            save_case(&dir, &target, finding).unwrap();
+        }
+        let cases = load_cases(&dir).unwrap();
+        assert_eq!(cases.len(), 2);
+        for (_, case) in &cases {
+            assert_eq!(fuzzer.replay(&[target], case), Ok(()));
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,402 @@
+// src/heartbeat.rs
+//
+// Two things a worker process must not do: outlive the host, and hang without the host
+// noticing.
+//
+// Orphans: a worker is only of any use for as long as somebody waits on its report, so
+// every worker (and the fork server) asks the kernel to SIGKILL it the moment its parent
+// dies (PR_SET_PDEATHSIG, Linux only), and then checks that the parent did not die before
+// it got to ask.  Where there is no PR_SET_PDEATHSIG, the heartbeat thread below keeps
+// checking getppid() instead; and everywhere, it dies when nobody listens to it (EPIPE).
+//
+// NOTE: PR_SET_PDEATHSIG fires when the THREAD that started the worker exits, not the
+// process.  The thread that starts a worker is the one that waits on it (or, for
+// async_isolation.rs, a runtime thread that lives as long as the runtime), so in
+// practice that is the same thing.
+//
+// Hangs: every worker also gets a thread of its own, which writes one byte down a
+// heartbeat pipe every `interval`.  The host polls that pipe along with the report pipe,
+// and SIGKILLs a worker that has been quiet for `timeout` (stopped, frozen by a cgroup,
+// stuck in the kernel...); that comes back as `LimitExceeded(ResourceLimit::Heartbeat)`.
+// The heartbeat only says that the worker process is still being scheduled, not that
+// the C call makes any progress; a C function spinning in a loop is what
+// `wall_time_secs` is for.  Since the host should hear of a dead worker before the
+// per-call deadline, `timeout` is capped at half of `wall_time_secs`.
+//
+// A re-exec'ed worker gets (see reexec.rs):
+//      env:  FFI_WORKER_HEARTBEAT=<parent pid>[,<heartbeat fd>,<interval msec>]
+
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::sandbox::SandboxLimits;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatPolicy {
+    /// how often the worker beats
+    pub interval: Duration,
+    /// how long the host puts up with not hearing a beat before it SIGKILLs the worker
+    pub timeout: Duration,
+}
+
+const DEFAULT_POLICY: HeartbeatPolicy = HeartbeatPolicy {
+    interval: Duration::from_millis(200),
+    timeout: Duration::from_secs(2),
+};
+
+impl Default for HeartbeatPolicy {
+    fn default() -> Self {
+        DEFAULT_POLICY
+    }
+}
+
+// None = no heartbeat (the workers still die with their parent)
+static POLICY: Mutex<Option<HeartbeatPolicy>> = Mutex::new(Some(DEFAULT_POLICY));
+
+/// Sets the heartbeat of the workers started from now on; None turns it off.  A fork
+/// server keeps the policy it was started with.
+pub fn set_policy(policy: Option<HeartbeatPolicy>) {
+    *POLICY.lock().unwrap() = policy;
+}
+
+pub fn policy() -> Option<HeartbeatPolicy> {
+    *POLICY.lock().unwrap()
+}
+
+/// What the heartbeat thread needs (`SandboxLimits::minimal_syscalls()` has them); an
+/// allow-list without them gets no heartbeat
+#[cfg(target_os = "linux")]
+pub const HEARTBEAT_SYSCALLS: [libc::c_long; 3] = [
+    libc::SYS_write,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+];
+
+/// The policy for a worker under `limits`, if it gets a heartbeat at all
+fn policy_for(limits: &SandboxLimits) -> Option<HeartbeatPolicy> {
+    let mut policy = policy()?;
+    #[cfg(target_os = "linux")]
+    if let Some(allowed) = &limits.allowed_syscalls {
+        if !HEARTBEAT_SYSCALLS.iter().all(|nr| allowed.contains(nr)) {
+            return None;
+        }
+    }
+    if let Some(secs) = limits.wall_time_secs {
+        policy.timeout = policy.timeout.min(Duration::from_millis(secs.max(1) * 500));
+    }
+    Some(policy)
+}
+
+// ############################## worker side
+
+/// The worker's half of the deal: who its parent is, and where/how often to beat
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct WorkerSide {
+    pub(crate) parent: libc::pid_t,
+    /// write end of the heartbeat pipe and the interval; None if there is no heartbeat
+    pub(crate) beat: Option<(libc::c_int, Duration)>,
+}
+
+impl WorkerSide {
+    pub(crate) fn to_env_value(self) -> String {
+        match self.beat {
+            Some((fd, interval)) => format!("{},{},{}", self.parent, fd, interval.as_millis()),
+            None => self.parent.to_string(),
+        }
+    }
+
+    pub(crate) fn from_env_value(value: &str) -> Option<WorkerSide> {
+        let mut fields = value.split(',');
+        let parent = fields.next()?.parse().ok()?;
+        let beat = match (fields.next(), fields.next()) {
+            (Some(fd), Some(msec)) => {
+                Some((fd.parse().ok()?, Duration::from_millis(msec.parse().ok()?)))
+            }
+            _ => None,
+        };
+        Some(WorkerSide { parent, beat })
+    }
+
+    /// Called first thing in the worker: die with the parent, and start beating
+    pub(crate) fn start(self) {
+        die_with_parent(self.parent);
+        let Some((fd, interval)) = self.beat else {
+            return;
+        };
+        let parent = self.parent;
+        let _ = std::thread::Builder::new()
+            .name("heartbeat".into())
+            .spawn(move || beat(fd, parent, interval));
+    }
+}
+
+/// SIGKILL for us as soon as `parent` is gone (which it might already be)
+pub(crate) fn die_with_parent(parent: libc::pid_t) {
+    #[cfg(target_os = "linux")]
+    unsafe {
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+    }
+    if unsafe { libc::getppid() } != parent {
+        unsafe { libc::raise(libc::SIGKILL) };
+    }
+}
+
+#[cfg_attr(target_os = "linux", allow(unused_variables))] // parent: see the top of this file
+fn beat(fd: libc::c_int, parent: libc::pid_t, interval: Duration) {
+    // whatever signals the C library is expecting, they are not for this thread
+    unsafe {
+        let mut all: libc::sigset_t = std::mem::zeroed();
+        libc::sigfillset(&mut all);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &all, std::ptr::null_mut());
+    }
+    loop {
+        std::thread::sleep(interval);
+        #[cfg(not(target_os = "linux"))]
+        if unsafe { libc::getppid() } != parent {
+            unsafe { libc::raise(libc::SIGKILL) };
+        }
+        let written = unsafe { libc::write(fd, b".".as_ptr() as *const libc::c_void, 1) };
+        if written < 0 {
+            match std::io::Error::last_os_error().raw_os_error() {
+                Some(libc::EINTR) | Some(libc::EAGAIN) => {}
+                // nobody is listening any more (EPIPE), or the C library closed our fd
+                // (EBADF), in which case the host stops listening as well
+                Some(libc::EPIPE) => unsafe {
+                    libc::raise(libc::SIGKILL);
+                },
+                _ => return,
+            }
+        }
+    }
+}
+
+// ############################## host side
+
+/// The host's half: the read end of the heartbeat pipe
+#[derive(Debug)]
+pub(crate) struct HostSide {
+    beats: File,
+    timeout: Duration,
+}
+
+/// A heartbeat pipe for a worker under `limits` (if it gets a heartbeat at all); both
+/// ends are close-on-exec (a re-exec'ed worker gets its end made inheritable in the child,
+/// see reexec.rs), and the host has to close the worker side's fd once the worker has it
+pub(crate) fn pipe(limits: &SandboxLimits) -> std::io::Result<(Option<HostSide>, WorkerSide)> {
+    let parent = unsafe { libc::getpid() };
+    let Some(policy) = policy_for(limits) else {
+        return Ok((None, WorkerSide { parent, beat: None }));
+    };
+    let (read_fd, write_fd) = crate::isolation::cloexec_pipe()?;
+    unsafe { libc::fcntl(read_fd, libc::F_SETFL, libc::O_NONBLOCK) };
+    let host = HostSide {
+        beats: unsafe { File::from_raw_fd(read_fd) },
+        timeout: policy.timeout,
+    };
+    let worker = WorkerSide {
+        parent,
+        beat: Some((write_fd, policy.interval)),
+    };
+    Ok((Some(host), worker))
+}
+
+/// Reads the worker's report to the end, like `read_to_string()` would, but SIGKILLs
+/// the worker if its heartbeat goes quiet for too long; in which case the report starts
+/// with an 'H' record (see `was_lost()`)
+pub(crate) fn read_report(
+    report: &mut File,
+    heartbeat: Option<&mut HostSide>,
+    pid: libc::pid_t,
+) -> String {
+    let mut bytes = Vec::new();
+    let Some(heartbeat) = heartbeat else {
+        let _ = report.read_to_end(&mut bytes);
+        return String::from_utf8_lossy(&bytes).into_owned();
+    };
+    let mut last_beat = Instant::now();
+    let mut listening = true;
+    let mut chunk = [0u8; 4096];
+    loop {
+        let mut fds = [
+            libc::pollfd {
+                fd: report.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                // a negative fd is skipped by poll()
+                fd: if listening {
+                    heartbeat.beats.as_raw_fd()
+                } else {
+                    -1
+                },
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        let wait = heartbeat.timeout.saturating_sub(last_beat.elapsed());
+        let wait_msec = if listening {
+            wait.as_millis().max(1) as libc::c_int
+        } else {
+            -1
+        };
+        if unsafe { libc::poll(fds.as_mut_ptr(), 2, wait_msec) } < 0 {
+            if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            break;
+        }
+        if fds[1].revents != 0 {
+            match heartbeat.beats.read(&mut chunk) {
+                Ok(0) => listening = false,
+                Ok(_) => last_beat = Instant::now(),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(_) => listening = false,
+            }
+        }
+        if fds[0].revents != 0 {
+            match report.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(count) => bytes.extend_from_slice(&chunk[..count]),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+        if listening && last_beat.elapsed() >= heartbeat.timeout {
+            unsafe { libc::kill(pid, libc::SIGKILL) };
+            let _ = report.read_to_end(&mut bytes);
+            let mut report = format!("H{}\n", heartbeat.timeout.as_millis());
+            report.push_str(&String::from_utf8_lossy(&bytes));
+            return report;
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// True if the worker got SIGKILLed by `read_report()` for going quiet
+pub(crate) fn was_lost(report: &str) -> bool {
+    let (records, _) = crate::isolation::split_side_records(report);
+    records.iter().any(|record| record.starts_with('H'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isolation::{call_isolated_report, IsolationMode};
+    use crate::outcome::CallOutcome;
+    use crate::sandbox::ResourceLimit;
+
+    #[test]
+    fn test_quiet_worker_is_killed_before_the_deadline() {
+        // SIGSTOP stops the heartbeat thread along with everything else
+        let fn_freeze = || {
+            unsafe { libc::raise(libc::SIGSTOP) };
+            Ok("thawed".to_string())
+        };
+        let limits = SandboxLimits {
+            wall_time_secs: Some(30),
+            ..SandboxLimits::default()
+        };
+        let start = Instant::now();
+        let call = call_isolated_report(IsolationMode::Fork, &limits, fn_freeze);
+        assert_eq!(
+            call.outcome,
+            CallOutcome::LimitExceeded(ResourceLimit::Heartbeat)
+        );
+        assert!(start.elapsed() < Duration::from_secs(15));
+
+        // a slow call that keeps beating is left alone
+        let fn_slow = || {
+            std::thread::sleep(DEFAULT_POLICY.timeout * 2);
+            Ok("slow".to_string())
+        };
+        let outcome = call_isolated_report(IsolationMode::Fork, &limits, fn_slow).outcome;
+        assert_eq!(outcome, CallOutcome::Returned("slow".into()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_kill_the_parent_leaves_no_workers_behind() {
+        use crate::isolation::start_worker;
+        use crate::zygote::ForkServer;
+
+        // dead = gone, or a zombie waiting for init to reap it
+        fn is_alive(pid: libc::pid_t) -> bool {
+            match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+                Ok(stat) => !stat
+                    .rsplit(')')
+                    .next()
+                    .unwrap_or("")
+                    .trim_start()
+                    .starts_with('Z'),
+                Err(_) => false,
+            }
+        }
+        fn children_of(parent: libc::pid_t) -> Vec<libc::pid_t> {
+            let mut children = Vec::new();
+            for entry in std::fs::read_dir("/proc").unwrap().flatten() {
+                let Ok(pid) = entry.file_name().to_string_lossy().parse::<libc::pid_t>() else {
+                    continue;
+                };
+                let Ok(stat) = std::fs::read_to_string(entry.path().join("stat")) else {
+                    continue;
+                };
+                let ppid = stat
+                    .rsplit(')')
+                    .next()
+                    .unwrap_or("")
+                    .split_whitespace()
+                    .nth(1);
+                if ppid.and_then(|ppid| ppid.parse().ok()) == Some(parent) {
+                    children.push(pid);
+                }
+            }
+            children
+        }
+
+        let fn_forever = || loop {
+            std::thread::sleep(Duration::from_secs(1));
+        };
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let host = unsafe { libc::fork() };
+        if host == 0 {
+            // a host with a fork server and a forked worker, both busy forever
+            let server = ForkServer::start(SandboxLimits::default()).unwrap();
+            let worker =
+                start_worker(IsolationMode::Fork, &SandboxLimits::default(), fn_forever).unwrap();
+            let pids = [server.pid(), worker.pid()];
+            unsafe { libc::write(fds[1], pids.as_ptr() as *const libc::c_void, 8) };
+            std::thread::spawn(move || server.call(fn_forever));
+            let _ = worker.wait();
+            unsafe { libc::_exit(0) };
+        }
+        unsafe { libc::close(fds[1]) };
+        let mut pids = [0 as libc::pid_t; 2];
+        let mut pipe = unsafe { File::from_raw_fd(fds[0]) };
+        pipe.read_exact(unsafe { std::slice::from_raw_parts_mut(pids.as_mut_ptr() as *mut u8, 8) })
+            .unwrap();
+        // the fork server's own worker
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut workers = children_of(pids[0]);
+        while workers.is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(20));
+            workers = children_of(pids[0]);
+        }
+        assert_eq!(workers.len(), 1);
+        workers.extend(pids);
+
+        unsafe { libc::kill(host, libc::SIGKILL) };
+        let mut status = 0;
+        unsafe { libc::waitpid(host, &mut status, 0) };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while workers.iter().any(|&pid| is_alive(pid)) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let left_behind: Vec<_> = workers.into_iter().filter(|&pid| is_alive(pid)).collect();
+        assert_eq!(left_behind, Vec::<libc::pid_t>::new());
+    }
+}
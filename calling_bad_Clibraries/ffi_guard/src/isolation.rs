@@ -0,0 +1,684 @@
+// src/isolation.rs
+//
+// Where (and how) a guarded foreign call gets to run.  As the README explains, a
+// spawned thread cannot survive `exit()` or a seg-fault in the C library (the whole
+// process goes down), hence we also need to be able to run the call in a separate
+// (forked) process, in which the host only gets to see how the child died.
+
+use std::any::Any;
+use std::panic;
+
+use crate::alloc_accounting::{self, AllocReport};
+use crate::crash_report::CrashReport;
+use crate::leak_check::{LeakReport, Snapshot};
+use crate::outcome::CallOutcome;
+use crate::sandbox::{ResourceLimit, SandboxLimits};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationMode {
+    /// `std::thread::spawn()` + `catch_unwind()`, same as `fork_and_join_0arg()`; only
+    /// panics are contained, `exit()` and signals still take the host down
+    Thread,
+    /// Same as `Thread`, but a SIGSEGV/SIGFPE of the C library only ends the thread, and
+    /// gets reported as `CallOutcome::Signaled`; `exit()` (and faults thread_guard.rs
+    /// cannot contain) still take the host down.  Cheaper than a process, but whatever the
+    /// thread was in the middle of (locks, memory) is lost along with it
+    GuardedThread,
+    /// `fork()` a child process per call; the child's death is reported as
+    /// `CallOutcome::Exited`/`CallOutcome::Signaled` rather than killing the host
+    Fork,
+    /// Same as `Fork`, but the child is forked off the (small, single-threaded) fork
+    /// server rather than off the host; see zygote.rs and `zygote::start_global()`
+    ForkServer,
+    /// Spawns (exec() right after the fork, none of the host's code runs in the child) a
+    /// fresh copy of our own executable as the worker; safe for multithreaded hosts, see
+    /// reexec.rs
+    ReExec,
+}
+
+/// Runs `my_function` under the given isolation and reports what happened to it
+pub fn call_isolated(
+    mode: IsolationMode,
+    my_function: fn() -> anyhow::Result<String>,
+) -> CallOutcome {
+    call_isolated_with(mode, &SandboxLimits::default(), my_function)
+}
+
+/// Same as `call_isolated()`, but the worker also gets the given sandbox limits
+pub fn call_isolated_with(
+    mode: IsolationMode,
+    limits: &SandboxLimits,
+    my_function: fn() -> anyhow::Result<String>,
+) -> CallOutcome {
+    call_isolated_report(mode, limits, my_function).outcome
+}
+
+/// Everything the host gets to know about an isolated call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallReport {
+    pub outcome: CallOutcome,
+    /// faulting address and stack trace, if the worker got killed by SIGSEGV/SIGBUS/
+    /// SIGFPE/SIGILL (see crash_report.rs)
+    pub crash: Option<CrashReport>,
+    /// the C library's malloc()/free() tally of the worker, if it was built with
+    /// `--features malloc_wrap` and lived to report it (see alloc_accounting.rs)
+    pub allocations: Option<AllocReport>,
+    /// fds left open by the call (as seen by the worker itself), and temp files left
+    /// behind if the call went through `LeakCheck::call_isolated()` (see leak_check.rs)
+    pub leaks: Option<LeakReport>,
+    /// what the worker printed on stdout, if it got captured (only while recording, see
+    /// record_replay.rs)
+    pub output: Option<String>,
+}
+
+impl From<CallOutcome> for CallReport {
+    fn from(outcome: CallOutcome) -> Self {
+        CallReport {
+            outcome,
+            crash: None,
+            allocations: None,
+            leaks: None,
+            output: None,
+        }
+    }
+}
+
+/// Same as `call_isolated_with()`, plus whatever else the worker had to report
+pub fn call_isolated_report(
+    mode: IsolationMode,
+    limits: &SandboxLimits,
+    my_function: fn() -> anyhow::Result<String>,
+) -> CallReport {
+    let call = GuardedCall::begin(mode, my_function);
+    let span = call.span.clone();
+    let _entered = span.enter();
+    if let Some(report) = call.replayed() {
+        return call.finish(report);
+    }
+    let report = match mode {
+        IsolationMode::Thread if limits.is_unrestricted() => call_in_thread(my_function).into(),
+        IsolationMode::GuardedThread if limits.is_unrestricted() => {
+            call_in_guarded_thread(my_function)
+        }
+        IsolationMode::Thread | IsolationMode::GuardedThread => {
+            // setrlimit()/seccomp on a thread would apply to the whole host
+            CallOutcome::Failed("sandbox limits require process isolation".into()).into()
+        }
+        IsolationMode::Fork => call_in_fork(limits, my_function),
+        IsolationMode::ForkServer if limits.is_unrestricted() => call_in_fork_server(my_function),
+        IsolationMode::ForkServer => {
+            // the fork server got its limits when it was started
+            CallOutcome::Failed("fork server limits are set by zygote::start_global()".into())
+                .into()
+        }
+        IsolationMode::ReExec => call_in_reexec(limits, my_function),
+    };
+    call.finish(report)
+}
+
+// What goes on around every guarded call, whichever way it gets made (here, or in
+// async_isolation.rs): the call id, the trace span, the metrics and the recording, or the
+// replay in place of the call itself
+pub(crate) struct GuardedCall {
+    function: String,
+    arguments: Vec<String>,
+    mode: IsolationMode,
+    /// the call's span; NOT entered, that is up to the caller (async code must not hold
+    /// it entered across an .await)
+    pub(crate) span: tracing::Span,
+    start: std::time::Instant,
+    /// the caller's; the call may well end on another thread (async_isolation.rs)
+    recorder: std::sync::Arc<crate::record_replay::Recorder>,
+}
+
+impl GuardedCall {
+    pub(crate) fn begin(mode: IsolationMode, my_function: fn() -> anyhow::Result<String>) -> Self {
+        // the worker inherits it, see shared_log.rs
+        let call_id = crate::shared_log::begin_call();
+        let function = crate::metrics::global().function_name(my_function);
+        let span = crate::call_trace::call_span(&function, mode, call_id);
+        GuardedCall {
+            arguments: crate::record_replay::take_arguments(),
+            function,
+            mode,
+            span,
+            start: std::time::Instant::now(),
+            recorder: crate::record_replay::current(),
+        }
+    }
+
+    /// The recorded report, if we are replaying; the call is not to be made then
+    pub(crate) fn replayed(&self) -> Option<CallReport> {
+        self.recorder.replay(&self.function, &self.arguments)
+    }
+
+    /// Counts it, closes the span with its outcome and records it (if we are recording)
+    pub(crate) fn finish(self, report: CallReport) -> CallReport {
+        let elapsed = self.start.elapsed();
+        crate::metrics::global().record(&self.function, &report.outcome, Some(elapsed));
+        self.span
+            .record("outcome", tracing::field::display(&report.outcome));
+        self.recorder
+            .record(&self.function, &self.arguments, self.mode, &report);
+        report
+    }
+}
+
+/// Runs the function in-place, converting both `Err` and panics into an outcome
+pub(crate) fn call_in_place(my_function: fn() -> anyhow::Result<String>) -> CallOutcome {
+    match panic::catch_unwind(my_function) {
+        Ok(Ok(value)) => CallOutcome::Returned(value),
+        Ok(Err(e)) => CallOutcome::Failed(format!("{:?}", e)),
+        Err(panic_value) => CallOutcome::Panicked(panic_message(&panic_value)),
+    }
+}
+
+pub(crate) fn panic_message(panic_value: &Box<dyn Any + Send>) -> String {
+    if let Some(msg) = panic_value.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = panic_value.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "<non-string panic payload>".into()
+    }
+}
+
+fn call_in_thread(my_function: fn() -> anyhow::Result<String>) -> CallOutcome {
+    let call_id = crate::shared_log::current_call().unwrap_or(0);
+    let span = tracing::Span::current();
+    let handle = std::thread::spawn(move || {
+        crate::shared_log::set_current_call(call_id);
+        let _entered = span.enter();
+        call_in_place(my_function)
+    });
+    match handle.join() {
+        Ok(outcome) => outcome,
+        Err(panic_value) => CallOutcome::Panicked(panic_message(&panic_value)),
+    }
+}
+
+fn call_in_guarded_thread(my_function: fn() -> anyhow::Result<String>) -> CallReport {
+    use crate::thread_guard::{spawn_guarded, ThreadEnd};
+
+    let call_id = crate::shared_log::current_call().unwrap_or(0);
+    let span = tracing::Span::current();
+    let thread = spawn_guarded(move || {
+        crate::shared_log::set_current_call(call_id);
+        let _entered = span.enter();
+        call_in_place(my_function)
+    });
+    match thread.join() {
+        ThreadEnd::Returned(outcome) => outcome.into(),
+        ThreadEnd::Panicked(panic_value) => {
+            CallOutcome::Panicked(panic_message(&panic_value)).into()
+        }
+        ThreadEnd::Faulted { signal, crash } => CallReport {
+            crash,
+            ..CallReport::from(CallOutcome::Signaled(signal))
+        },
+    }
+}
+
+#[cfg(unix)]
+fn call_in_fork(limits: &SandboxLimits, my_function: fn() -> anyhow::Result<String>) -> CallReport {
+    match fork_and_wait(limits, my_function) {
+        Ok((status, report)) => conclude(limits, status, &report),
+        Err(outcome) => outcome.into(),
+    }
+}
+
+/// The worker side of process isolation (forked or re-exec'ed): call it, report back
+/// over `report_fd` (if we ever get back) and get out WITHOUT running any of the
+/// parent's atexit()/destructors (hence _exit() rather than exit())
+#[cfg(unix)]
+pub(crate) fn run_as_worker(
+    limits: &SandboxLimits,
+    my_function: fn() -> anyhow::Result<String>,
+    report_fd: libc::c_int,
+    heartbeat: crate::heartbeat::WorkerSide,
+) -> ! {
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+
+    // whatever the C library allocated before (i.e. in the host, for a forked worker) is
+    // not this call's doing; and the host's lock on the tally is none of our business.
+    // First thing, while we are still the only thread
+    alloc_accounting::reset_in_worker(report_fd);
+    // before the sandbox, which may not allow for starting a thread
+    heartbeat.start();
+    crate::crash_report::install(report_fd);
+    crate::shared_log::enter_worker();
+    crate::call_trace::enter_worker(report_fd);
+    // listing /proc/self/fd takes syscalls that a seccomp allow-list may not have, and
+    // has to be done before the sandbox is in place
+    let fds_before = limits.allowed_syscalls.is_none().then(Snapshot::fds_only);
+    let outcome = match limits.apply_to_self() {
+        Ok(()) => call_in_place(my_function),
+        Err(e) => CallOutcome::Failed(format!("sandbox setup failed: {}", e)),
+    };
+    let mut report = String::new();
+    if alloc_accounting::enabled() {
+        report.push_str(&alloc_accounting::snapshot().encode());
+        report.push('\n');
+    }
+    if let Some(fds_before) = fds_before {
+        let leaked = fds_before.leaks_since(&Snapshot::fds_only()).fds;
+        report.push_str(&LeakReport::encode_fds(&leaked));
+        report.push('\n');
+    }
+    report.push_str(&encode_report(&outcome));
+    let mut pipe = unsafe { std::fs::File::from_raw_fd(report_fd) };
+    let _ = pipe.write_all(report.as_bytes());
+    let _ = std::io::stdout().flush();
+    unsafe {
+        libc::fflush(std::ptr::null_mut()); // C side printf() buffers
+        libc::_exit(0);
+    }
+}
+
+/// A worker process that has been started (forked or re-exec'ed) but not reaped yet
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct Worker {
+    pub(crate) pid: libc::pid_t,
+    pub(crate) report: std::fs::File,
+    /// None if the worker does not beat (see heartbeat.rs)
+    pub(crate) heartbeat: Option<crate::heartbeat::HostSide>,
+    /// where its stdout goes, if it gets captured (see record_replay.rs)
+    pub(crate) output: Option<std::fs::File>,
+    pub(crate) reaped: bool,
+}
+
+#[cfg(unix)]
+impl Worker {
+    pub fn pid(&self) -> libc::pid_t {
+        self.pid
+    }
+
+    /// Blocks until the worker is dead; returns the raw `waitpid()` status along with
+    /// whatever the worker reported back (nothing if it died before reporting back)
+    pub fn wait(self) -> std::io::Result<(libc::c_int, String)> {
+        self.wait_then_reap(|| {})
+    }
+
+    /// Same as `wait()`, but `before_reap` gets called once the worker is dead and
+    /// before it gets reaped; until it is reaped, its pid cannot be recycled, hence
+    /// anybody else holding on to the pid (i.e. to kill() it) can still do so safely
+    #[cfg(target_os = "linux")]
+    pub(crate) fn wait_then_reap(
+        mut self,
+        before_reap: impl FnOnce(),
+    ) -> std::io::Result<(libc::c_int, String)> {
+        let report = self.read_report();
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let options = libc::WEXITED | libc::WNOWAIT;
+        if unsafe { libc::waitid(libc::P_PID, self.pid as libc::id_t, &mut info, options) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        before_reap();
+        let mut status: libc::c_int = 0;
+        if unsafe { libc::waitpid(self.pid, &mut status, 0) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        self.reaped = true;
+        Ok((status, report))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn wait_then_reap(
+        mut self,
+        before_reap: impl FnOnce(),
+    ) -> std::io::Result<(libc::c_int, String)> {
+        // no WNOWAIT here; the window between the two is as small as we can make it
+        let report = self.read_report();
+        let mut status: libc::c_int = 0;
+        before_reap();
+        if unsafe { libc::waitpid(self.pid, &mut status, 0) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        self.reaped = true;
+        Ok((status, report))
+    }
+
+    // the report pipe only gets to EOF once the worker is dead (or has closed it on its
+    // way out), and only then is its output complete
+    fn read_report(&mut self) -> String {
+        let report =
+            crate::heartbeat::read_report(&mut self.report, self.heartbeat.as_mut(), self.pid);
+        match self.output.take() {
+            Some(output) => crate::record_replay::output_record(output) + &report,
+            None => report,
+        }
+    }
+}
+
+// A worker nobody waited for would be left as a zombie (or, worse, keep running)
+#[cfg(unix)]
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if !self.reaped {
+            let mut status: libc::c_int = 0;
+            unsafe {
+                libc::kill(self.pid, libc::SIGKILL);
+                libc::waitpid(self.pid, &mut status, 0);
+            }
+        }
+    }
+}
+
+/// Starts (but does not wait for) a worker process calling `my_function`; only the
+/// process modes (`Fork` and `ReExec`) have a worker of their own to hand out
+#[cfg(unix)]
+pub fn start_worker(
+    mode: IsolationMode,
+    limits: &SandboxLimits,
+    my_function: fn() -> anyhow::Result<String>,
+) -> Result<Worker, CallOutcome> {
+    match mode {
+        IsolationMode::Fork => fork_worker(limits, my_function),
+        IsolationMode::ReExec => crate::reexec::spawn_worker(limits, my_function)
+            .map_err(|e| CallOutcome::Failed(format!("could not re-exec worker: {}", e))),
+        other => Err(CallOutcome::Failed(format!(
+            "{:?} does not have a worker process of its own",
+            other
+        ))),
+    }
+}
+
+/// Forks a child to call `my_function` (under `limits`), and waits for it to die
+#[cfg(unix)]
+pub(crate) fn fork_and_wait(
+    limits: &SandboxLimits,
+    my_function: fn() -> anyhow::Result<String>,
+) -> Result<(libc::c_int, String), CallOutcome> {
+    fork_worker(limits, my_function)?
+        .wait()
+        .map_err(|e| CallOutcome::Failed(format!("waitpid() failed: {}", e)))
+}
+
+#[cfg(unix)]
+fn fork_worker(
+    limits: &SandboxLimits,
+    my_function: fn() -> anyhow::Result<String>,
+) -> Result<Worker, CallOutcome> {
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+
+    // flush whatever we have buffered, else the child inherits (and prints) it as well
+    let _ = std::io::stdout().flush();
+    let _ = std::io::stderr().flush();
+
+    // both ends stay close-on-exec, in the child as well: it does not exec() (the C
+    // library might, and that program has no business with our report pipe)
+    let (read_fd, write_fd) = match cloexec_pipe() {
+        Ok(fds) => fds,
+        Err(e) => return Err(CallOutcome::Failed(format!("pipe() failed: {}", e))),
+    };
+    let (heartbeat, worker_side) = match crate::heartbeat::pipe(limits) {
+        Ok(heartbeat) => heartbeat,
+        Err(e) => {
+            unsafe {
+                libc::close(read_fd);
+                libc::close(write_fd);
+            }
+            return Err(CallOutcome::Failed(format!(
+                "heartbeat pipe() failed: {}",
+                e
+            )));
+        }
+    };
+    let close_beat_fd = || {
+        if let Some((beat_fd, _)) = worker_side.beat {
+            unsafe { libc::close(beat_fd) };
+        }
+    };
+    let output = crate::record_replay::output_file();
+
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        unsafe {
+            libc::close(read_fd);
+            libc::close(write_fd);
+        }
+        close_beat_fd();
+        return Err(CallOutcome::Failed(format!(
+            "fork() failed: {}",
+            std::io::Error::last_os_error()
+        )));
+    }
+
+    if pid == 0 {
+        unsafe { libc::close(read_fd) };
+        drop(heartbeat);
+        if let Some(output) = &output {
+            use std::os::unix::io::AsRawFd;
+            if let Err(e) = redirect_stdout(output.as_raw_fd()) {
+                eprintln!("fork_worker: stdout not captured: {}", e);
+            }
+        }
+        run_as_worker(limits, my_function, write_fd, worker_side);
+    }
+
+    unsafe { libc::close(write_fd) };
+    close_beat_fd();
+    Ok(Worker {
+        pid,
+        report: unsafe { std::fs::File::from_raw_fd(read_fd) },
+        heartbeat,
+        output,
+        reaped: false,
+    })
+}
+
+/// A pipe (read end, write end) whose ends are both close-on-exec from the start, so
+/// that no other thread's fork()+exec() in the meantime can take a copy along
+#[cfg(unix)]
+pub(crate) fn cloexec_pipe() -> std::io::Result<(libc::c_int, libc::c_int)> {
+    let mut fds = [0 as libc::c_int; 2];
+    #[cfg(not(target_vendor = "apple"))]
+    let result = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+    // no pipe2() there; the window between pipe() and fcntl() is back, but only there
+    #[cfg(target_vendor = "apple")]
+    let result = unsafe {
+        let result = libc::pipe(fds.as_mut_ptr());
+        if result == 0 {
+            libc::fcntl(fds[0], libc::F_SETFD, libc::FD_CLOEXEC);
+            libc::fcntl(fds[1], libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+        result
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok((fds[0], fds[1]))
+}
+
+/// Clears close-on-exec on `fd`; only ever in the child, between fork() and exec() (i.e.
+/// from a `pre_exec()`), so fcntl() is all it does
+#[cfg(unix)]
+pub(crate) fn inherit_fd(fd: libc::c_int) -> std::io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// glibc's `FILE *stdout`, in a module of its own so that it does not get in the way of
+// all the locals called `stdout`
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+mod c_stdio {
+    extern "C" {
+        pub static mut stdout: *mut libc::FILE;
+    }
+}
+
+/// In a worker: from now on, its stdout (ours and the C library's) goes to `fd`, and
+/// unbuffered, else whatever the C library printed right before it crashed would die in
+/// its stdio buffer
+#[cfg(unix)]
+pub(crate) fn redirect_stdout(fd: libc::c_int) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let _ = std::io::stdout().flush();
+    unsafe {
+        libc::fflush(std::ptr::null_mut());
+        #[cfg(all(target_os = "linux", target_env = "gnu"))]
+        libc::setvbuf(c_stdio::stdout, std::ptr::null_mut(), libc::_IONBF, 0);
+        if fd != libc::STDOUT_FILENO && libc::dup2(fd, libc::STDOUT_FILENO) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn call_in_fork(
+    _limits: &SandboxLimits,
+    _my_function: fn() -> anyhow::Result<String>,
+) -> CallReport {
+    let outcome = CallOutcome::Failed("IsolationMode::Fork is only available on unix".into());
+    outcome.into()
+}
+
+#[cfg(unix)]
+fn call_in_fork_server(my_function: fn() -> anyhow::Result<String>) -> CallReport {
+    match crate::zygote::global() {
+        Some(server) => server.call_report(my_function),
+        None => CallOutcome::Failed("fork server has not been started".into()).into(),
+    }
+}
+
+#[cfg(unix)]
+fn call_in_reexec(
+    limits: &SandboxLimits,
+    my_function: fn() -> anyhow::Result<String>,
+) -> CallReport {
+    let worker = match start_worker(IsolationMode::ReExec, limits, my_function) {
+        Ok(worker) => worker,
+        Err(outcome) => return outcome.into(),
+    };
+    match worker.wait() {
+        Ok((status, report)) => conclude(limits, status, &report),
+        Err(e) => CallOutcome::Failed(format!("waitpid() failed: {}", e)).into(),
+    }
+}
+
+#[cfg(not(unix))]
+fn call_in_reexec(
+    _limits: &SandboxLimits,
+    _my_function: fn() -> anyhow::Result<String>,
+) -> CallReport {
+    let outcome = CallOutcome::Failed("IsolationMode::ReExec is only available on unix".into());
+    outcome.into()
+}
+
+#[cfg(not(unix))]
+fn call_in_fork_server(_my_function: fn() -> anyhow::Result<String>) -> CallReport {
+    let outcome = CallOutcome::Failed("IsolationMode::ForkServer is only available on unix".into());
+    outcome.into()
+}
+
+// The report is one tag character followed by the payload:
+//      'R' -> Returned, 'F' -> Failed, 'P' -> Panicked
+// optionally preceded by side records, one line each:
+//      'A' -> allocation tally (see alloc_accounting.rs)
+//      'M' -> an allocation failed, sent as it happened (see alloc_accounting.rs)
+//      'L' -> fds left open (see leak_check.rs)
+//      'T' -> a tracing event, sent as it happened (see call_trace.rs)
+//      'H' -> the worker went quiet and got SIGKILLed (added by the host, see heartbeat.rs)
+//      'O' -> what the worker printed, escaped (added by the host, see record_replay.rs)
+// or replaced by a crash record ('C', see crash_report.rs) if the worker never got that far
+pub(crate) fn encode_report(outcome: &CallOutcome) -> String {
+    match outcome {
+        CallOutcome::Returned(value) => format!("R{}", value),
+        CallOutcome::Failed(msg) => format!("F{}", msg),
+        CallOutcome::Panicked(msg) => format!("P{}", msg),
+        other => format!("F{}", other), // should not happen inside the child
+    }
+}
+
+pub(crate) fn decode_report(report: &str) -> Option<CallOutcome> {
+    let (_, report) = split_side_records(report);
+    let mut chars = report.chars();
+    let tag = chars.next()?;
+    let payload = chars.as_str().to_string();
+    match tag {
+        'R' => Some(CallOutcome::Returned(payload)),
+        'F' => Some(CallOutcome::Failed(payload)),
+        'P' => Some(CallOutcome::Panicked(payload)),
+        _ => None,
+    }
+}
+
+pub(crate) fn split_side_records(report: &str) -> (Vec<&str>, &str) {
+    let mut records = Vec::new();
+    let mut rest = report;
+    while let Some((line, remainder)) = rest.split_once('\n') {
+        if !matches!(line.chars().next(), Some('A' | 'M' | 'L' | 'T' | 'H' | 'O')) {
+            break;
+        }
+        records.push(line);
+        rest = remainder;
+    }
+    (records, rest)
+}
+
+/// `classify_child()` + `SandboxLimits::explain()`, along with the crash record and the
+/// side records (if the worker wrote them)
+#[cfg(unix)]
+pub(crate) fn conclude(limits: &SandboxLimits, status: libc::c_int, report: &str) -> CallReport {
+    let outcome = outcome_of(limits, status, report);
+    let crash = match outcome {
+        CallOutcome::Signaled(_) | CallOutcome::LimitExceeded(_) => CrashReport::parse(report),
+        _ => None,
+    };
+    let (records, _) = split_side_records(report);
+    crate::call_trace::forward_worker_events(&records);
+    CallReport {
+        outcome,
+        crash,
+        allocations: records.iter().find_map(|line| AllocReport::decode(line)),
+        leaks: records
+            .iter()
+            .find_map(|line| LeakReport::decode_fds(line))
+            .map(|fds| LeakReport {
+                fds,
+                ..LeakReport::default()
+            }),
+        output: records
+            .iter()
+            .find_map(|line| crate::record_replay::decode_output(line)),
+    }
+}
+
+/// `classify_child()` + `SandboxLimits::explain()`, unless the SIGKILL was our own doing
+/// (for a worker that missed its heartbeat, see heartbeat.rs)
+#[cfg(unix)]
+pub(crate) fn outcome_of(limits: &SandboxLimits, status: libc::c_int, report: &str) -> CallOutcome {
+    if libc::WIFSIGNALED(status)
+        && libc::WTERMSIG(status) == libc::SIGKILL
+        && crate::heartbeat::was_lost(report)
+    {
+        return CallOutcome::LimitExceeded(ResourceLimit::Heartbeat);
+    }
+    limits.explain(
+        classify_child(status, report),
+        alloc_accounting::allocation_failed(report),
+    )
+}
+
+/// Maps the `waitpid()` status (and the report, if any) of a dead child into an outcome
+#[cfg(unix)]
+pub(crate) fn classify_child(status: libc::c_int, report: &str) -> CallOutcome {
+    if libc::WIFSIGNALED(status) {
+        return CallOutcome::Signaled(libc::WTERMSIG(status));
+    }
+    let exit_status = libc::WEXITSTATUS(status);
+    match decode_report(report) {
+        // a child that reported back and then _exit(0)'ed is a normal return
+        Some(outcome) if exit_status == 0 => outcome,
+        // either died before reporting, or the C library called exit() on the way out
+        _ => CallOutcome::Exited(exit_status),
+    }
+}
@@ -0,0 +1,512 @@
+// src/journal.rs
+//
+// Isolation only protects the host from calls that go through a worker; an in-place call
+// to mid_exit() (or a seg-fault) still takes the host down, without running a single
+// destructor.  Whatever the host was holding at that moment stays behind: temp files,
+// lock files ("Log files that was about to log something and locked the file", see the
+// top of the README)...
+//
+// So the host writes down what it acquires BEFORE it gets to use it, and what it released
+// after it did, in an append-only journal:
+//
+//      + temp 12345 /tmp/report_12345.csv
+//      + lockfile 12345 /tmp/calling_bad_Clibraries.lock
+//      + flock 12345 /tmp/calling_bad_Clibraries.log
+//      - temp 12345 /tmp/report_12345.csv
+//
+// (<acquired/released> <kind> <pid of the holder> <path>).  At the next startup,
+// `Journal::open_and_recover()` replays it: anything acquired and never released by a
+// process that is no longer alive is cleaned up (temp and lock files get deleted; an
+// flock() is released by the kernel when its holder dies, so that one is only reported),
+// and the journal is rewritten with only what the live processes still hold.
+//
+// The journal says which files to delete, so whoever can write it can have us delete
+// anything we are allowed to: it lives in a directory only we can get into
+// (`$XDG_RUNTIME_DIR`, else `<temp_dir>/<uid>`, created 0700), and `recover()` refuses a
+// journal that is not ours, or that somebody else could have written to.
+//
+// NOTE: one host (and its workers) per journal; two hosts recovering the same journal at
+// the same time would step on each other.  Also, a recycled pid looks alive, which only
+// means that the leftover gets cleaned up one startup later.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    TempFile,
+    LockFile,
+    AdvisoryLock,
+}
+
+impl ResourceKind {
+    fn tag(self) -> &'static str {
+        match self {
+            ResourceKind::TempFile => "temp",
+            ResourceKind::LockFile => "lockfile",
+            ResourceKind::AdvisoryLock => "flock",
+        }
+    }
+
+    fn from_tag(tag: &str) -> Option<ResourceKind> {
+        match tag {
+            "temp" => Some(ResourceKind::TempFile),
+            "lockfile" => Some(ResourceKind::LockFile),
+            "flock" => Some(ResourceKind::AdvisoryLock),
+            _ => None,
+        }
+    }
+}
+
+/// Something a previous run acquired and never released
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Leftover {
+    pub kind: ResourceKind,
+    pub pid: u32,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// left behind by dead processes, and dealt with
+    pub recovered: Vec<(Leftover, String)>,
+    /// still held by live processes; kept in the journal
+    pub still_held: Vec<Leftover>,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    /// `<private_dir()>/<program>.journal`
+    pub fn default_path() -> io::Result<PathBuf> {
+        crate::private_file_of_program("journal")
+    }
+
+    /// Cleans up after whoever used the journal before us, then opens it for our own use
+    pub fn open_and_recover(path: &Path) -> io::Result<(Journal, RecoveryReport)> {
+        let report = recover(path)?;
+        let file = private_file_options()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let journal = Journal {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        };
+        Ok((journal, report))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn acquired(&self, kind: ResourceKind, path: &Path) -> io::Result<()> {
+        self.append('+', kind, path)
+    }
+
+    pub fn released(&self, kind: ResourceKind, path: &Path) -> io::Result<()> {
+        self.append('-', kind, path)
+    }
+
+    fn append(&self, op: char, kind: ResourceKind, path: &Path) -> io::Result<()> {
+        let line = format!(
+            "{} {} {} {}\n",
+            op,
+            kind.tag(),
+            std::process::id(),
+            path.display()
+        );
+        let mut file = self
+            .file
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // one write() per line on an O_APPEND file: the line is either all there or not
+        // at all, even if we get killed right after
+        file.write_all(line.as_bytes())?;
+        file.sync_data()
+    }
+
+    /// Creates a temp file (`<temp_dir>/<prefix>_<pid>_<n>`) which gets deleted when the
+    /// returned guard is dropped, or at the next startup if we never get that far
+    pub fn temp_file(&self, prefix: &str) -> io::Result<JournaledTempFile<'_>> {
+        static COUNTER: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+        let n = COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let path = std::env::temp_dir().join(format!("{}_{}_{}", prefix, std::process::id(), n));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // journaled only once it is ours, else recovery would delete somebody else's
+        if let Err(e) = self.acquired(ResourceKind::TempFile, &path) {
+            let _ = std::fs::remove_file(&path);
+            return Err(e);
+        }
+        Ok(JournaledTempFile {
+            journal: self,
+            path,
+            file,
+        })
+    }
+
+    /// Takes a lock FILE (created exclusively, with our pid in it); fails with
+    /// `AlreadyExists` if somebody else has it
+    pub fn lock_file(&self, path: &Path) -> io::Result<JournaledLockFile<'_>> {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        // journaled only once it is ours, else recovery would delete somebody else's
+        self.acquired(ResourceKind::LockFile, path)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(JournaledLockFile {
+            journal: self,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// flock(LOCK_EX) on `path` (created if need be), blocking until we get it
+    #[cfg(unix)]
+    pub fn advisory_lock(&self, path: &Path) -> io::Result<JournaledFlock<'_>> {
+        use std::os::unix::io::AsRawFd;
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.acquired(ResourceKind::AdvisoryLock, path)?;
+        Ok(JournaledFlock {
+            journal: self,
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+}
+
+pub struct JournaledTempFile<'a> {
+    journal: &'a Journal,
+    path: PathBuf,
+    pub file: File,
+}
+
+impl JournaledTempFile<'_> {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for JournaledTempFile<'_> {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+        let _ = self.journal.released(ResourceKind::TempFile, &self.path);
+    }
+}
+
+pub struct JournaledLockFile<'a> {
+    journal: &'a Journal,
+    path: PathBuf,
+}
+
+impl Drop for JournaledLockFile<'_> {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+        let _ = self.journal.released(ResourceKind::LockFile, &self.path);
+    }
+}
+
+pub struct JournaledFlock<'a> {
+    journal: &'a Journal,
+    path: PathBuf,
+    pub file: File,
+}
+
+#[cfg(unix)]
+impl Drop for JournaledFlock<'_> {
+    fn drop(&mut self) {
+        use std::os::unix::io::AsRawFd;
+
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+        let _ = self
+            .journal
+            .released(ResourceKind::AdvisoryLock, &self.path);
+    }
+}
+
+/// Replays the journal at `path` (if there is one), cleans up after dead processes, and
+/// rewrites it with whatever the live ones still hold.  Fails with `PermissionDenied`,
+/// without touching anything, if the journal is not ours or others could write to it
+pub fn recover(path: &Path) -> io::Result<RecoveryReport> {
+    let file = match private_file_options().read(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(RecoveryReport::default()),
+        Err(e) => return Err(e),
+    };
+    check_private(&file.metadata()?, path)?;
+    // still held, in the order acquired
+    let mut held: Vec<Leftover> = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let mut fields = line.splitn(4, ' ');
+        let (Some(op), Some(kind), Some(pid), Some(resource)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            continue; // torn or garbage line
+        };
+        let (Some(kind), Ok(pid)) = (ResourceKind::from_tag(kind), pid.parse()) else {
+            continue;
+        };
+        let entry = Leftover {
+            kind,
+            pid,
+            path: PathBuf::from(resource),
+        };
+        match op {
+            "+" => held.push(entry),
+            "-" => held.retain(|other| *other != entry),
+            _ => {}
+        }
+    }
+
+    let mut report = RecoveryReport::default();
+    for leftover in held {
+        if is_alive(leftover.pid) {
+            report.still_held.push(leftover);
+            continue;
+        }
+        let action = match leftover.kind {
+            ResourceKind::TempFile | ResourceKind::LockFile => {
+                match std::fs::remove_file(&leftover.path) {
+                    Ok(()) => "deleted".to_string(),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => "already gone".to_string(),
+                    Err(e) => format!("could not delete: {}", e),
+                }
+            }
+            ResourceKind::AdvisoryLock => "released by the kernel when its holder died".into(),
+        };
+        report.recovered.push((leftover, action));
+    }
+
+    // rewrite it with only the live entries (write + rename, so it is never half there)
+    let rewritten = path.with_extension("journal.tmp");
+    {
+        let mut file = private_file_options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&rewritten)?;
+        for leftover in &report.still_held {
+            writeln!(
+                file,
+                "+ {} {} {}",
+                leftover.kind.tag(),
+                leftover.pid,
+                leftover.path.display()
+            )?;
+        }
+        file.sync_all()?;
+    }
+    std::fs::rename(&rewritten, path)?;
+    Ok(report)
+}
+
+/// `$XDG_RUNTIME_DIR` if set (it is ours and 0700 by definition), else `<temp_dir>/<uid>`,
+/// created 0700 if need be, and refused if it already exists and is not ours or not private
+#[cfg(unix)]
+pub fn private_dir() -> io::Result<PathBuf> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir));
+    }
+    let uid = unsafe { libc::geteuid() };
+    let dir = std::env::temp_dir().join(uid.to_string());
+    match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e),
+    }
+    // somebody may have made it (or a symlink by that name) before we did
+    let metadata = std::fs::symlink_metadata(&dir)?;
+    if !metadata.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not a directory", dir.display()),
+        ));
+    }
+    check_private(&metadata, &dir)?;
+    Ok(dir)
+}
+
+#[cfg(not(unix))]
+pub fn private_dir() -> io::Result<PathBuf> {
+    Ok(std::env::temp_dir())
+}
+
+/// Opens without following a symlink, and creates with 0600
+#[cfg(unix)]
+pub(crate) fn private_file_options() -> OpenOptions {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut options = OpenOptions::new();
+    options.mode(0o600).custom_flags(libc::O_NOFOLLOW);
+    options
+}
+
+#[cfg(not(unix))]
+pub(crate) fn private_file_options() -> OpenOptions {
+    OpenOptions::new()
+}
+
+/// Ours, and not writable by the group or the world
+#[cfg(unix)]
+fn check_private(metadata: &std::fs::Metadata, path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    if metadata.uid() != unsafe { libc::geteuid() } {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} belongs to uid {}, not to us",
+                path.display(),
+                metadata.uid()
+            ),
+        ));
+    }
+    if metadata.mode() & 0o022 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} is writable by others (mode {:o})",
+                path.display(),
+                metadata.mode() & 0o777
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_private(_metadata: &std::fs::Metadata, _path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_alive(pid: u32) -> bool {
+    if pid == std::process::id() {
+        return true;
+    }
+    // signal 0: no signal is sent, only the existence (and permission) check is done
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_alive(pid: u32) -> bool {
+    // no cheap way to tell; only our own entries are known to be alive
+    pid == std::process::id()
+}
+
+impl fmt::Display for Leftover {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} (pid {})",
+            self.kind.tag(),
+            self.path.display(),
+            self.pid
+        )
+    }
+}
+
+impl RecoveryReport {
+    pub fn is_empty(&self) -> bool {
+        self.recovered.is_empty() && self.still_held.is_empty()
+    }
+}
+
+impl fmt::Display for RecoveryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} left behind by dead processes, {} still held",
+            self.recovered.len(),
+            self.still_held.len()
+        )?;
+        for (leftover, action) in &self.recovered {
+            write!(f, "\n    {}: {}", leftover, action)?;
+        }
+        for leftover in &self.still_held {
+            write!(f, "\n    {}: still held", leftover)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recovery_after_the_holder_died() {
+        let dir = std::env::temp_dir().join(format!("journal_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let journal_path = dir.join("test.journal");
+        let lock_path = dir.join("test.lock");
+        let released_path = dir.join("released.lock");
+
+        // the "previous run": takes a lock file, releases another one, and dies
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            let (journal, _) = Journal::open_and_recover(&journal_path).unwrap();
+            drop(journal.lock_file(&released_path).unwrap());
+            std::mem::forget(journal.lock_file(&lock_path).unwrap());
+            unsafe { libc::_exit(0) };
+        }
+        let mut status = 0;
+        unsafe { libc::waitpid(pid, &mut status, 0) };
+        assert!(lock_path.exists());
+
+        let (journal, report) = Journal::open_and_recover(&journal_path).unwrap();
+        assert_eq!(report.still_held, vec![]);
+        assert_eq!(report.recovered.len(), 1);
+        assert_eq!(report.recovered[0].0.path, lock_path);
+        assert_eq!(report.recovered[0].1, "deleted");
+        assert!(!lock_path.exists());
+
+        // and ours are kept, since we are alive
+        let _lock = journal.lock_file(&lock_path).unwrap();
+        let report = recover(&journal_path).unwrap();
+        assert_eq!(report.still_held.len(), 1);
+        assert!(lock_path.exists());
+        drop(_lock);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_refuses_a_journal_others_can_write() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("journal_test_mode_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let journal_path = dir.join("test.journal");
+        let victim = dir.join("victim");
+        std::fs::write(&victim, "").unwrap();
+        // "written by somebody else": a dead pid holding a file we care about
+        std::fs::write(
+            &journal_path,
+            format!("+ temp 999999999 {}\n", victim.display()),
+        )
+        .unwrap();
+        std::fs::set_permissions(&journal_path, std::fs::Permissions::from_mode(0o666)).unwrap();
+
+        let e = recover(&journal_path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert!(victim.exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
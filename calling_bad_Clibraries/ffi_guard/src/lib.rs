@@ -0,0 +1,162 @@
+//! Guarded calls into C libraries that do not play nice: the ones that `exit()` on you,
+//! seg-fault, leak memory, fds and temp files, hang, or crash every other call.
+//!
+//! A spawned thread cannot survive `exit()` or a seg-fault in the C library (the whole
+//! process goes down with it), hence the guarded call runs in a worker process of its
+//! own, and the host only gets to see how the worker died, as a [`CallOutcome`]:
+//!
+//! ```no_run
+//! use ffi_guard::isolation::{call_isolated, IsolationMode};
+//! use ffi_guard::outcome::CallOutcome;
+//!
+//! extern "C" {
+//!     fn vendor_checksum(count: i32) -> i32;
+//! }
+//!
+//! fn checksum() -> anyhow::Result<String> {
+//!     Ok(unsafe { vendor_checksum(42) }.to_string())
+//! }
+//!
+//! fn main() {
+//!     // first thing in main(), if any call is to go through IsolationMode::ReExec
+//!     ffi_guard::reexec::worker_entry();
+//!
+//!     match call_isolated(IsolationMode::Fork, checksum) {
+//!         CallOutcome::Returned(sum) => println!("checksum: {}", sum),
+//!         other => eprintln!("vendor_checksum() {}", other), // i.e. "killed by signal 11"
+//!     }
+//! }
+//! ```
+//!
+//! This crate knows nothing about any particular C library; the functions to call are
+//! plain `fn() -> anyhow::Result<String>` pointers (or, for [`dyn_call`], a symbol
+//! dlsym()ed at runtime).  The `calling_bad_Clibraries` demo is built on top of it.
+//!
+//! Where to go from here:
+//!  - [`isolation`]: the isolation modes and [`isolation::call_isolated_report()`], which
+//!    everything else ends up calling
+//!  - [`thread_guard`]: a cheaper, thread-only guard for faults that stay within one thread
+//!  - [`sandbox`]: rlimits, a wall-clock timeout and a seccomp allow-list for the worker
+//!  - [`quarantine`], [`retry`] and [`shutdown`]: what to do about a function that keeps
+//!    crashing, crashes now and then, or means it when it calls `exit()`
+//!  - [`metrics`], [`call_trace`] and [`shared_log`]: seeing what the calls did
+//!  - [`record_replay`]: the calls, recorded for later, and replayed without the C library
+
+// NOTE: most of the header comments of the modules below predate this crate, and use the
+// demo's C library (mid_exit() & co) for their examples
+
+/// Tally of the C library's malloc()/free() (`--features malloc_wrap`)
+pub mod alloc_accounting;
+/// Guarded calls from async code, without blocking the executor
+pub mod async_isolation;
+/// Tracing spans for the guarded calls, written as JSON lines
+pub mod call_trace;
+/// Where (and how) a worker crashed: faulting address and stack trace
+pub mod crash_report;
+/// Calling any exported C symbol, by a signature only known at runtime
+pub mod dyn_call;
+/// Fuzzing a C function in isolated workers, with crash bucketing and minimization
+pub mod fuzz;
+/// Workers that die with the host, and a host that notices workers going quiet
+#[cfg(unix)]
+pub mod heartbeat;
+/// The isolation modes, and the guarded call itself
+pub mod isolation;
+/// Crash-recovery journal of the calls in flight
+pub mod journal;
+/// Fds and temp files left behind by a call
+pub mod leak_check;
+/// Per-function counters and latencies, in the Prometheus text format
+pub mod metrics;
+/// What a guarded call ended up as
+pub mod outcome;
+/// Circuit breaker for functions that keep on crashing
+pub mod quarantine;
+/// Workers that are a fresh copy of the executable rather than a fork of it
+#[cfg(unix)]
+pub mod reexec;
+/// Recording the guarded calls to a file, and answering them out of it later on
+pub mod record_replay;
+/// Retrying calls that crash now and then, with exponential backoff
+pub mod retry;
+/// Limits the worker runs under
+pub mod sandbox;
+/// Declarative TOML scenario files, and a runner for them
+#[cfg(unix)]
+pub mod scenario_file;
+/// A log file the host and all of its workers can append to
+pub mod shared_log;
+/// Big input/output buffers in shared memory rather than through the report pipe
+#[cfg(unix)]
+pub mod shared_mem;
+/// Draining the calls in flight when the library calls `exit()`
+pub mod shutdown;
+/// Threads whose SIGSEGV/SIGFPE end only the thread, not the process
+pub mod thread_guard;
+/// Fork server, forked off while the host is still small and single-threaded
+#[cfg(unix)]
+pub mod zygote;
+
+pub use isolation::{call_isolated, call_isolated_report, CallReport, IsolationMode};
+pub use outcome::CallOutcome;
+pub use sandbox::SandboxLimits;
+
+/// `std::env::temp_dir()/<program>.<suffix>`, where `<program>` is the file name of our
+/// executable (i.e. `calling_bad_Clibraries.log` for the demo)
+pub(crate) fn temp_file_of_program(suffix: &str) -> std::path::PathBuf {
+    let program = std::env::current_exe()
+        .ok()
+        .and_then(|exe| {
+            exe.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+        })
+        .unwrap_or_else(|| "ffi_guard".to_string());
+    std::env::temp_dir().join(format!("{}.{}", program, suffix))
+}
+
+/// The same name as `temp_file_of_program()`, but in `journal::private_dir()`: for the
+/// files we keep appending to, which nobody else gets to plant a symlink in place of
+pub(crate) fn private_file_of_program(suffix: &str) -> std::io::Result<std::path::PathBuf> {
+    let in_temp_dir = temp_file_of_program(suffix);
+    let name = in_temp_dir.file_name().unwrap_or_default();
+    Ok(journal::private_dir()?.join(name))
+}
+
+/// A file without a name, for a worker's stdout to go to: nobody else can open it, and
+/// there is no path for a planted symlink to redirect.  `name` only shows up in
+/// /proc/<pid>/fd
+#[cfg(target_os = "linux")]
+pub(crate) fn anonymous_file(name: &str) -> std::io::Result<std::fs::File> {
+    use std::os::unix::io::FromRawFd;
+
+    let name = std::ffi::CString::new(name).unwrap_or_default();
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { std::fs::File::from_raw_fd(fd) })
+}
+
+/// No memfd_create() here: created exclusively (which does not follow a symlink) in our
+/// private directory, and unlinked right away
+#[cfg(all(unix, not(target_os = "linux")))]
+pub(crate) fn anonymous_file(name: &str) -> std::io::Result<std::fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    let path = journal::private_dir()?.join(format!(
+        "{}.{}.{}",
+        name,
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    let _ = std::fs::remove_file(&path);
+    Ok(file)
+}
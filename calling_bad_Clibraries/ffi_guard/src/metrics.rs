@@ -0,0 +1,357 @@
+// src/metrics.rs
+//
+// Now that the foreign calls are guarded, we would also like to SEE them: how often each
+// C function gets called, how it comes back (returned, exit()ed, got killed by a signal,
+// ran out of time, was refused by the circuit breaker), and how long it takes.  Every
+// isolated call is recorded into the global `Metrics` by the isolation layer itself (see
+// `call_isolated_report()` and `async_isolation::call_in_worker()`, which is where the
+// outcomes get decided), so nobody has to remember to do it.
+//
+// The one thing the isolation layer does not know is the NAME of the C function; all it
+// gets is a `fn()` pointer (usually a closure around the C call).  `name_function()`
+// tells it; calls through a pointer nobody named are counted as "unnamed".
+// `CircuitBreaker::call()` names its functions by their breaker name.
+//
+// Exposed in the Prometheus text format (version 0.0.4):
+//  - `dump_to_file()`: for node_exporter's textfile collector (written to a temp file and
+//    renamed into place, so the collector never reads half a dump)
+//  - `serve()`: a tiny HTTP listener for `GET /metrics`; it only binds to loopback
+//    addresses, these numbers are nobody else's business
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::outcome::CallOutcome;
+use crate::sandbox::ResourceLimit;
+
+/// Upper bounds (seconds) of the latency histogram buckets; +Inf is implied
+pub const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0];
+
+/// The `outcome` label values, in the order they are rendered
+pub const OUTCOMES: [&str; 9] = [
+    "returned",
+    "failed",
+    "panicked",
+    "exited",
+    "signaled",
+    "timeout",
+    "limit_exceeded",
+    "quarantined",
+    "shutting_down",
+];
+
+/// The `outcome` label of a call; CPU and wall-clock limits (and a lost heartbeat) are all
+/// timeouts
+pub fn outcome_label(outcome: &CallOutcome) -> &'static str {
+    match outcome {
+        CallOutcome::Returned(_) => "returned",
+        CallOutcome::Failed(_) => "failed",
+        CallOutcome::Panicked(_) => "panicked",
+        CallOutcome::Exited(_) => "exited",
+        CallOutcome::Signaled(_) => "signaled",
+        CallOutcome::LimitExceeded(
+            ResourceLimit::CpuTime | ResourceLimit::WallTime | ResourceLimit::Heartbeat,
+        ) => "timeout",
+        CallOutcome::LimitExceeded(_) => "limit_exceeded",
+        CallOutcome::Quarantined => "quarantined",
+        CallOutcome::ShuttingDown => "shutting_down",
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FunctionMetrics {
+    /// per `outcome_label()`
+    pub outcomes: BTreeMap<&'static str, u64>,
+    /// per bucket of `LATENCY_BUCKETS` (NOT cumulative, `render()` adds them up), plus
+    /// one for +Inf; only calls that actually ran (not refused ones) are timed
+    pub latency_buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    pub latency_sum: Duration,
+    pub latency_count: u64,
+}
+
+impl FunctionMetrics {
+    pub fn calls(&self) -> u64 {
+        self.outcomes.values().sum()
+    }
+
+    pub fn count(&self, outcome: &str) -> u64 {
+        self.outcomes.get(outcome).copied().unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    functions: Mutex<BTreeMap<String, FunctionMetrics>>,
+    // fn pointer -> name, see `name_function()`
+    names: Mutex<BTreeMap<usize, String>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Calls through `my_function` are recorded as calls to `name` from now on
+    pub fn name_function(&self, my_function: fn() -> anyhow::Result<String>, name: &str) {
+        lock(&self.names).insert(my_function as usize, name.to_string());
+    }
+
+    pub fn function_name(&self, my_function: fn() -> anyhow::Result<String>) -> String {
+        lock(&self.names)
+            .get(&(my_function as usize))
+            .cloned()
+            .unwrap_or_else(|| "unnamed".to_string())
+    }
+
+    /// One call to `function`; `latency` is None if it was refused without running
+    pub fn record(&self, function: &str, outcome: &CallOutcome, latency: Option<Duration>) {
+        let mut functions = lock(&self.functions);
+        let metrics = functions.entry(function.to_string()).or_default();
+        *metrics.outcomes.entry(outcome_label(outcome)).or_default() += 1;
+        if let Some(latency) = latency {
+            let seconds = latency.as_secs_f64();
+            let bucket = LATENCY_BUCKETS
+                .iter()
+                .position(|&le| seconds <= le)
+                .unwrap_or(LATENCY_BUCKETS.len());
+            metrics.latency_buckets[bucket] += 1;
+            metrics.latency_sum += latency;
+            metrics.latency_count += 1;
+        }
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, FunctionMetrics> {
+        lock(&self.functions).clone()
+    }
+
+    /// Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let functions = self.snapshot();
+        let mut text = String::new();
+        let _ = writeln!(
+            text,
+            "# HELP ffi_calls_total Guarded foreign calls, by function."
+        );
+        let _ = writeln!(text, "# TYPE ffi_calls_total counter");
+        for (function, metrics) in &functions {
+            let _ = writeln!(
+                text,
+                "ffi_calls_total{{function=\"{}\"}} {}",
+                escape(function),
+                metrics.calls()
+            );
+        }
+        let _ = writeln!(
+            text,
+            "# HELP ffi_call_outcomes_total Guarded foreign calls, by function and by how they came back."
+        );
+        let _ = writeln!(text, "# TYPE ffi_call_outcomes_total counter");
+        for (function, metrics) in &functions {
+            // all of them, zeros included: a counter that appears out of nowhere the
+            // first time a function crashes is a pain to alert on
+            for outcome in OUTCOMES {
+                let _ = writeln!(
+                    text,
+                    "ffi_call_outcomes_total{{function=\"{}\",outcome=\"{}\"}} {}",
+                    escape(function),
+                    outcome,
+                    metrics.count(outcome)
+                );
+            }
+        }
+        let _ = writeln!(
+            text,
+            "# HELP ffi_call_duration_seconds Wall-clock time of the guarded foreign calls, isolation overhead included."
+        );
+        let _ = writeln!(text, "# TYPE ffi_call_duration_seconds histogram");
+        for (function, metrics) in &functions {
+            let mut cumulative = 0;
+            for (i, count) in metrics.latency_buckets.iter().enumerate() {
+                cumulative += count;
+                let le = LATENCY_BUCKETS
+                    .get(i)
+                    .map_or("+Inf".to_string(), |le| le.to_string());
+                let _ = writeln!(
+                    text,
+                    "ffi_call_duration_seconds_bucket{{function=\"{}\",le=\"{}\"}} {}",
+                    escape(function),
+                    le,
+                    cumulative
+                );
+            }
+            let _ = writeln!(
+                text,
+                "ffi_call_duration_seconds_sum{{function=\"{}\"}} {}",
+                escape(function),
+                metrics.latency_sum.as_secs_f64()
+            );
+            let _ = writeln!(
+                text,
+                "ffi_call_duration_seconds_count{{function=\"{}\"}} {}",
+                escape(function),
+                metrics.latency_count
+            );
+        }
+        text
+    }
+
+    /// `render()` into `path`, atomically (i.e. for node_exporter's textfile collector,
+    /// which wants a `*.prom` file)
+    pub fn dump_to_file(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("prom.tmp");
+        fs::write(&tmp, self.render())?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Serves `GET /metrics` on `addr` from a background thread, until the process ends;
+    /// returns the address actually bound (port 0 picks a free one)
+    pub fn serve(&'static self, addr: SocketAddr) -> io::Result<SocketAddr> {
+        if !addr.ip().is_loopback() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: the metrics listener is local-only", addr),
+            ));
+        }
+        let listener = TcpListener::bind(addr)?;
+        let bound = listener.local_addr()?;
+        std::thread::Builder::new()
+            .name("metrics".into())
+            .spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    // one scrape at a time is plenty; a scraper that hangs up early is
+                    // its own problem
+                    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+                    let _ = self.respond(stream);
+                }
+            })?;
+        Ok(bound)
+    }
+
+    fn respond(&self, stream: std::net::TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // the headers do not interest us, but the client wants them read
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+        let mut stream = reader.into_inner();
+        let (status, content_type, body) = match request_line.split_whitespace().nth(1) {
+            Some("/metrics") if request_line.starts_with("GET ") => (
+                "200 OK",
+                "text/plain; version=0.0.4; charset=utf-8",
+                self.render(),
+            ),
+            _ => (
+                "404 Not Found",
+                "text/plain",
+                "try GET /metrics\n".to_string(),
+            ),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        )?;
+        stream.flush()
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// label values: backslash, double quote and newline have to be escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+static GLOBAL: OnceLock<Metrics> = OnceLock::new();
+
+/// THE metrics the isolation layer records into
+pub fn global() -> &'static Metrics {
+    GLOBAL.get_or_init(Metrics::new)
+}
+
+/// `global().name_function()`
+pub fn name_function(my_function: fn() -> anyhow::Result<String>, name: &str) {
+    global().name_function(my_function, name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        let ms = Duration::from_millis;
+        metrics.record("mid_exit", &CallOutcome::Exited(102), Some(ms(3)));
+        metrics.record(
+            "mid_exit",
+            &CallOutcome::Returned("ok".into()),
+            Some(ms(30)),
+        );
+        metrics.record(
+            "mid_exit",
+            &CallOutcome::LimitExceeded(ResourceLimit::WallTime),
+            Some(ms(20_000)),
+        );
+        metrics.record("mid_exit", &CallOutcome::Quarantined, None);
+        metrics.record("say \"hi\"", &CallOutcome::Signaled(11), Some(ms(1)));
+
+        let text = metrics.render();
+        for line in [
+            "ffi_calls_total{function=\"mid_exit\"} 4",
+            "ffi_call_outcomes_total{function=\"mid_exit\",outcome=\"exited\"} 1",
+            "ffi_call_outcomes_total{function=\"mid_exit\",outcome=\"timeout\"} 1",
+            "ffi_call_outcomes_total{function=\"mid_exit\",outcome=\"quarantined\"} 1",
+            "ffi_call_outcomes_total{function=\"mid_exit\",outcome=\"signaled\"} 0",
+            "ffi_call_duration_seconds_bucket{function=\"mid_exit\",le=\"0.001\"} 0",
+            "ffi_call_duration_seconds_bucket{function=\"mid_exit\",le=\"0.005\"} 1",
+            "ffi_call_duration_seconds_bucket{function=\"mid_exit\",le=\"0.05\"} 2",
+            "ffi_call_duration_seconds_bucket{function=\"mid_exit\",le=\"10\"} 2",
+            "ffi_call_duration_seconds_bucket{function=\"mid_exit\",le=\"+Inf\"} 3",
+            "ffi_call_duration_seconds_sum{function=\"mid_exit\"} 20.033",
+            "ffi_call_duration_seconds_count{function=\"mid_exit\"} 3",
+            "ffi_call_outcomes_total{function=\"say \\\"hi\\\"\",outcome=\"signaled\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{} not in\n{}", line, text);
+        }
+    }
+
+    #[test]
+    fn test_serve_is_local_only() {
+        let metrics: &'static Metrics = Box::leak(Box::new(Metrics::new()));
+        metrics.record(
+            "mid_checksum",
+            &CallOutcome::Returned("11".into()),
+            Some(Duration::ZERO),
+        );
+        assert!(metrics.serve("0.0.0.0:0".parse().unwrap()).is_err());
+
+        let addr = metrics.serve("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        write!(stream, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\nffi_calls_total{function=\"mid_checksum\"} 1\n"));
+    }
+}
@@ -0,0 +1,66 @@
+// src/outcome.rs
+//
+// What a guarded foreign call ended up as, seen from the caller (host) side.
+// `anyhow::Result<String>` can only say "it worked" or "it didn't", but when a C
+// library calls `exit()` or seg-faults inside an isolated worker, we want to know
+// *how* it didn't work (so that we can decide whether to retry, quarantine, etc).
+
+use std::fmt;
+
+use crate::sandbox::ResourceLimit;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallOutcome {
+    /// The wrapped function came back with `Ok(value)`
+    Returned(String),
+    /// The wrapped function came back with `Err(..)` (the C call returned, but reported failure)
+    Failed(String),
+    /// The wrapped function panicked (caught via `catch_unwind`)
+    Panicked(String),
+    /// The worker called `exit(status)` (i.e. `mid_exit()`)
+    Exited(i32),
+    /// The worker got killed by a signal (i.e. SIGSEGV from `mid_access_violation()`)
+    Signaled(i32),
+    /// The worker went over one of the sandbox limits it was given (see sandbox.rs)
+    LimitExceeded(ResourceLimit),
+    /// The circuit breaker refused to run the call (see quarantine.rs)
+    Quarantined,
+    /// The host is shutting down and refused to run the call (see shutdown.rs)
+    ShuttingDown,
+}
+
+impl CallOutcome {
+    /// True if the worker died abnormally (as opposed to returning, successfully or not)
+    pub fn is_crash(&self) -> bool {
+        matches!(
+            self,
+            CallOutcome::Exited(_) | CallOutcome::Signaled(_) | CallOutcome::LimitExceeded(_)
+        )
+    }
+
+    /// For those who are still on the `anyhow::Result<String>` path; the `Err` side
+    /// can be downcast back via `err.downcast_ref::<CallOutcome>()`
+    pub fn into_result(self) -> anyhow::Result<String> {
+        match self {
+            CallOutcome::Returned(value) => Ok(value),
+            other => Err(anyhow::Error::new(other)),
+        }
+    }
+}
+
+impl fmt::Display for CallOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallOutcome::Returned(value) => write!(f, "returned: {}", value),
+            CallOutcome::Failed(msg) => write!(f, "failed: {}", msg),
+            CallOutcome::Panicked(msg) => write!(f, "panicked: {}", msg),
+            CallOutcome::Exited(status) => write!(f, "exited with status {}", status),
+            CallOutcome::Signaled(signal) => write!(f, "killed by signal {}", signal),
+            CallOutcome::LimitExceeded(limit) => write!(f, "exceeded sandbox limit {:?}", limit),
+            CallOutcome::Quarantined => write!(f, "quarantined"),
+            CallOutcome::ShuttingDown => write!(f, "refused, shutting down"),
+        }
+    }
+}
+
+impl std::error::Error for CallOutcome {}
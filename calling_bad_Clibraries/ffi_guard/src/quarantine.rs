@@ -0,0 +1,184 @@
+// src/quarantine.rs
+//
+// Circuit breaker for foreign functions that keep on crashing their isolated worker.
+// Once a function has crashed `max_crashes` times within `window`, it is quarantined:
+// calls to it will fail fast with `CallOutcome::Quarantined` (without even forking)
+// until `cooldown` has passed, or until an operator calls `reset()`.  The idea is
+// that one bad input should not turn into a crash storm in the worker pool.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::isolation::{call_isolated, IsolationMode};
+use crate::outcome::CallOutcome;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuarantinePolicy {
+    /// how many crashes (within `window`) trips the breaker
+    pub max_crashes: usize,
+    /// sliding window in which the crashes are counted
+    pub window: Duration,
+    /// how long the function stays quarantined once tripped
+    pub cooldown: Duration,
+}
+
+impl Default for QuarantinePolicy {
+    fn default() -> Self {
+        QuarantinePolicy {
+            max_crashes: 3,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(300),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    crashes: VecDeque<Instant>,
+    quarantined_until: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    default_policy: QuarantinePolicy,
+    policies: HashMap<String, QuarantinePolicy>,
+    states: Mutex<HashMap<String, BreakerState>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(default_policy: QuarantinePolicy) -> Self {
+        CircuitBreaker {
+            default_policy,
+            ..Default::default()
+        }
+    }
+
+    /// Per-function override of the default policy
+    pub fn with_policy(mut self, function_name: &str, policy: QuarantinePolicy) -> Self {
+        self.policies.insert(function_name.to_string(), policy);
+        self
+    }
+
+    pub fn policy(&self, function_name: &str) -> QuarantinePolicy {
+        *self
+            .policies
+            .get(function_name)
+            .unwrap_or(&self.default_policy)
+    }
+
+    /// True if calls to `function_name` are (still) being refused
+    pub fn is_quarantined(&self, function_name: &str) -> bool {
+        let mut states = self.states.lock().unwrap();
+        match states.get_mut(function_name) {
+            Some(state) => match state.quarantined_until {
+                Some(until) if Instant::now() < until => true,
+                Some(_) => {
+                    // cooled down; give it a clean slate
+                    *state = BreakerState::default();
+                    false
+                }
+                None => false,
+            },
+            None => false,
+        }
+    }
+
+    /// Feeds the outcome of a call to `function_name` into the breaker
+    pub fn record(&self, function_name: &str, outcome: &CallOutcome) {
+        if !outcome.is_crash() {
+            return;
+        }
+        let policy = self.policy(function_name);
+        let now = Instant::now();
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(function_name.to_string()).or_default();
+        state.crashes.push_back(now);
+        while let Some(oldest) = state.crashes.front() {
+            if now.duration_since(*oldest) > policy.window {
+                state.crashes.pop_front();
+            } else {
+                break;
+            }
+        }
+        if state.crashes.len() >= policy.max_crashes {
+            state.quarantined_until = Some(now + policy.cooldown);
+        }
+    }
+
+    /// Operator override: forget the crash history and lift the quarantine
+    pub fn reset(&self, function_name: &str) {
+        self.states.lock().unwrap().remove(function_name);
+    }
+
+    /// Runs `my_function` isolated unless `function_name` is quarantined
+    pub fn call(
+        &self,
+        function_name: &str,
+        mode: IsolationMode,
+        my_function: fn() -> anyhow::Result<String>,
+    ) -> CallOutcome {
+        crate::metrics::name_function(my_function, function_name);
+        if self.is_quarantined(function_name) {
+            crate::metrics::global().record(function_name, &CallOutcome::Quarantined, None);
+            return CallOutcome::Quarantined;
+        }
+        let outcome = call_isolated(mode, my_function);
+        self.record(function_name, &outcome);
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_crashes: usize, cooldown: Duration) -> QuarantinePolicy {
+        QuarantinePolicy {
+            max_crashes,
+            window: Duration::from_secs(60),
+            cooldown,
+        }
+    }
+
+    #[test]
+    fn test_trips_after_max_crashes() {
+        let breaker = CircuitBreaker::new(policy(2, Duration::from_secs(60)));
+        breaker.record(
+            "mid_access_violation",
+            &CallOutcome::Signaled(libc::SIGSEGV),
+        );
+        assert!(!breaker.is_quarantined("mid_access_violation"));
+        breaker.record(
+            "mid_access_violation",
+            &CallOutcome::Signaled(libc::SIGSEGV),
+        );
+        assert!(breaker.is_quarantined("mid_access_violation"));
+        // other functions are not affected
+        assert!(!breaker.is_quarantined("mid_exit"));
+    }
+
+    #[test]
+    fn test_non_crashes_do_not_count() {
+        let breaker = CircuitBreaker::new(policy(1, Duration::from_secs(60)));
+        breaker.record("mid_exit", &CallOutcome::Returned("ok".into()));
+        breaker.record("mid_exit", &CallOutcome::Failed("nope".into()));
+        assert!(!breaker.is_quarantined("mid_exit"));
+    }
+
+    #[test]
+    fn test_per_function_policy_cooldown_and_reset() {
+        let breaker = CircuitBreaker::new(policy(1, Duration::from_secs(60)))
+            .with_policy("mid_exit", policy(1, Duration::ZERO));
+        breaker.record("mid_exit", &CallOutcome::Exited(102));
+        assert!(!breaker.is_quarantined("mid_exit")); // zero cooldown, already over
+
+        breaker.record(
+            "mid_access_violation",
+            &CallOutcome::Signaled(libc::SIGSEGV),
+        );
+        assert!(breaker.is_quarantined("mid_access_violation"));
+        breaker.reset("mid_access_violation");
+        assert!(!breaker.is_quarantined("mid_access_violation"));
+    }
+}
@@ -0,0 +1,559 @@
+// src/record_replay.rs
+//
+// Recording the traffic of the guarded calls, and replaying it without the C library.
+//
+// While recording, every call that goes through `call_isolated_report()` gets appended
+// to a TOML file, as it comes back: which function, its arguments, the outcome and what
+// it printed on stdout.  One `[[call]]` table per call, appended as soon as the call is
+// over, so that a host that goes down half-way still leaves a readable recording:
+//
+//      [[call]]
+//      function = "mid_checksum"           # see metrics::name_function()
+//      arguments = ["13", "1"]             # see set_arguments(); dyn_call.rs sets them
+//      mode = "Fork"                       # for the reader only
+//      outcome = "exited"                  # returned, failed, panicked, exited,
+//      status = 13                         # signaled (status = the signal),
+//      output = "mid_checksum(): unlucky count, bailing out\n"  # limit_exceeded (+ limit)
+//
+// While replaying, a call is NOT made; it is answered out of the recording instead, by
+// function and arguments, in the order the calls were recorded (the second call of
+// `mid_checksum(13, 1)` gets the second answer recorded for it).  A call that was never
+// recorded (or is made more often than it was recorded) comes back as `Failed`, rather
+// than quietly going to a library that may not even be there.  So a CI machine without
+// the vendor's .so can still put the Rust side through a captured trace, as long as the
+// calls are dlopen()ed (dyn_call.rs, scenario_file.rs): an executable LINKED against the
+// library does not get to main() without it.  Since the recording is plain TOML, a field
+// incident can also be edited down to the calls that matter, or made up by hand.
+//
+// The output is only captured for a worker process of our own (Fork and ReExec, and the
+// fork server if it was started while recording): its stdout goes to an anonymous file
+// of the host's, which the host reads back (and prints, so that nothing goes missing on the
+// terminal) once the worker is dead, crashed or not.  Thread modes share our stdout with
+// everybody else, so there is nothing to tell their output apart by.  Crash reports are
+// not recorded.  The calls of async_isolation.rs are recorded (and replayed) all the same,
+// see `isolation::GuardedCall`.
+//
+// The demo turns it on with FFI_RECORD=<file> or FFI_REPLAY=<file>, see `init_from_env()`.
+// That is the process-wide `Recorder`; a thread can have one of its own for a while
+// (`with_recorder()`), which the calls of the other threads know nothing about.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::isolation::{CallReport, IsolationMode};
+use crate::outcome::CallOutcome;
+use crate::sandbox::ResourceLimit;
+
+const RECORD_ENV: &str = "FFI_RECORD";
+const REPLAY_ENV: &str = "FFI_REPLAY";
+
+/// One guarded call, as kept in the recording
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordedCall {
+    /// the name given to `metrics::name_function()` (the symbol, for dyn_call.rs)
+    pub function: String,
+    #[serde(default)]
+    pub arguments: Vec<String>,
+    /// the isolation mode it was called in; replay does not care
+    #[serde(default)]
+    pub mode: String,
+    /// "returned", "failed", "panicked", "exited", "signaled" or "limit_exceeded"
+    pub outcome: String,
+    /// the returned value, or what it failed/panicked with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// the exit status, or the signal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<i32>,
+    /// the `ResourceLimit` that was exceeded, by name (i.e. "WallTime")
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<String>,
+    /// what it printed on stdout, if that was captured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+}
+
+const LIMITS: [ResourceLimit; 7] = [
+    ResourceLimit::AddressSpace,
+    ResourceLimit::CpuTime,
+    ResourceLimit::FileSize,
+    ResourceLimit::OpenFiles,
+    ResourceLimit::Syscall,
+    ResourceLimit::WallTime,
+    ResourceLimit::Heartbeat,
+];
+
+impl RecordedCall {
+    pub fn new(
+        function: &str,
+        arguments: &[String],
+        mode: IsolationMode,
+        report: &CallReport,
+    ) -> RecordedCall {
+        let mut call = RecordedCall {
+            function: function.to_string(),
+            arguments: arguments.to_vec(),
+            mode: format!("{:?}", mode),
+            outcome: String::new(),
+            value: None,
+            status: None,
+            limit: None,
+            output: report.output.clone(),
+        };
+        call.outcome = match &report.outcome {
+            CallOutcome::Returned(value) => {
+                call.value = Some(value.clone());
+                "returned"
+            }
+            CallOutcome::Failed(msg) => {
+                call.value = Some(msg.clone());
+                "failed"
+            }
+            CallOutcome::Panicked(msg) => {
+                call.value = Some(msg.clone());
+                "panicked"
+            }
+            CallOutcome::Exited(status) => {
+                call.status = Some(*status);
+                "exited"
+            }
+            CallOutcome::Signaled(signal) => {
+                call.status = Some(*signal);
+                "signaled"
+            }
+            CallOutcome::LimitExceeded(limit) => {
+                call.limit = Some(format!("{:?}", limit));
+                "limit_exceeded"
+            }
+            // refused before they got anywhere near `call_isolated_report()`
+            CallOutcome::Quarantined => "quarantined",
+            CallOutcome::ShuttingDown => "shutting_down",
+        }
+        .to_string();
+        call
+    }
+
+    /// The report the call came back with (minus the crash report and the tallies)
+    pub fn report(&self) -> Result<CallReport, String> {
+        let value = || self.value.clone().unwrap_or_default();
+        let status = || {
+            self.status
+                .ok_or_else(|| format!("{} of {} without a status", self.outcome, self.function))
+        };
+        let outcome = match self.outcome.as_str() {
+            "returned" => CallOutcome::Returned(value()),
+            "failed" => CallOutcome::Failed(value()),
+            "panicked" => CallOutcome::Panicked(value()),
+            "exited" => CallOutcome::Exited(status()?),
+            "signaled" => CallOutcome::Signaled(status()?),
+            "limit_exceeded" => {
+                let name = self.limit.as_deref().unwrap_or_default();
+                match LIMITS.iter().find(|limit| format!("{:?}", limit) == name) {
+                    Some(limit) => CallOutcome::LimitExceeded(*limit),
+                    None => return Err(format!("unknown limit {:?}", name)),
+                }
+            }
+            "quarantined" => CallOutcome::Quarantined,
+            "shutting_down" => CallOutcome::ShuttingDown,
+            other => return Err(format!("unknown outcome {:?}", other)),
+        };
+        Ok(CallReport {
+            output: self.output.clone(),
+            ..CallReport::from(outcome)
+        })
+    }
+}
+
+#[derive(Default, Deserialize)]
+struct Recording {
+    #[serde(default)]
+    call: Vec<RecordedCall>,
+}
+
+/// Loads a recording, in the order the calls were made
+pub fn load(path: &Path) -> anyhow::Result<Vec<RecordedCall>> {
+    let text =
+        std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+    let recording: Recording =
+        toml::from_str(&text).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+    Ok(recording.call)
+}
+
+/// The answers of a recording, yet to be given
+#[derive(Debug)]
+pub struct Replay {
+    path: PathBuf,
+    answers: HashMap<(String, Vec<String>), VecDeque<RecordedCall>>,
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> anyhow::Result<Replay> {
+        let mut answers: HashMap<_, VecDeque<_>> = HashMap::new();
+        for call in load(path)? {
+            // better now than half-way through the replay
+            call.report()
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+            let key = (call.function.clone(), call.arguments.clone());
+            answers.entry(key).or_default().push_back(call);
+        }
+        Ok(Replay {
+            path: path.to_path_buf(),
+            answers,
+        })
+    }
+
+    /// The next recorded answer for `function(arguments)`
+    pub fn answer(&mut self, function: &str, arguments: &[String]) -> CallReport {
+        let call = format!("{}({})", function, arguments.join(", "));
+        let key = (function.to_string(), arguments.to_vec());
+        let missing = match self.answers.get_mut(&key).map(VecDeque::pop_front) {
+            Some(Some(recorded)) => {
+                // checked by load()
+                return recorded
+                    .report()
+                    .unwrap_or_else(|e| CallOutcome::Failed(e).into());
+            }
+            Some(None) => "was called more often than in",
+            None => "is not in",
+        };
+        let msg = format!("{} {} the recording {}", call, missing, self.path.display());
+        CallOutcome::Failed(msg).into()
+    }
+
+    /// How many of the recorded calls have not been answered yet
+    pub fn remaining(&self) -> usize {
+        self.answers.values().map(VecDeque::len).sum()
+    }
+}
+
+#[derive(Default)]
+enum Mode {
+    #[default]
+    Off,
+    Record(PathBuf),
+    Replay(Replay),
+}
+
+/// Where the guarded calls get recorded to, or answered from.  There is one for the whole
+/// process (the one `start_recording()` & co. work on), and a thread can have one of its
+/// own for a while instead, see `with_recorder()`
+#[derive(Default)]
+pub struct Recorder {
+    mode: Mutex<Mode>,
+}
+
+static GLOBAL: OnceLock<Arc<Recorder>> = OnceLock::new();
+
+thread_local! {
+    static SCOPED: RefCell<Option<Arc<Recorder>>> = const { RefCell::new(None) };
+}
+
+/// The recorder the guarded calls of this thread go to: its own (within
+/// `with_recorder()`), else the process-wide one
+pub fn current() -> Arc<Recorder> {
+    SCOPED
+        .with(|scoped| scoped.borrow().clone())
+        .unwrap_or_else(|| GLOBAL.get_or_init(Default::default).clone())
+}
+
+/// Runs `f` with the guarded calls it makes (on this thread) going to `recorder` rather
+/// than to the process-wide one; the calls of every other thread are left alone
+pub fn with_recorder<R>(recorder: Arc<Recorder>, f: impl FnOnce() -> R) -> R {
+    let previous = SCOPED.with(|scoped| scoped.replace(Some(recorder)));
+    let result = f();
+    SCOPED.with(|scoped| *scoped.borrow_mut() = previous);
+    result
+}
+
+impl Recorder {
+    fn lock_mode(&self) -> std::sync::MutexGuard<'_, Mode> {
+        self.mode
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// From now on, every guarded call gets appended to `path` (which starts out empty)
+    pub fn start_recording(&self, path: &Path) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        writeln!(
+            file,
+            "# guarded calls, as recorded by ffi_guard (see record_replay.rs)"
+        )?;
+        *self.lock_mode() = Mode::Record(path.to_path_buf());
+        Ok(())
+    }
+
+    /// From now on, every guarded call gets answered out of the recording at `path`, and
+    /// the C library is left alone; returns how many calls the recording has
+    pub fn start_replay(&self, path: &Path) -> anyhow::Result<usize> {
+        let replay = Replay::load(path)?;
+        let calls = replay.remaining();
+        *self.lock_mode() = Mode::Replay(replay);
+        Ok(calls)
+    }
+
+    /// Back to making the calls, without recording them; returns the replay, if there
+    /// was one (i.e. to see if all of its calls got made)
+    pub fn stop(&self) -> Option<Replay> {
+        match std::mem::take(&mut *self.lock_mode()) {
+            Mode::Replay(replay) => Some(replay),
+            _ => None,
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        matches!(*self.lock_mode(), Mode::Record(_))
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        matches!(*self.lock_mode(), Mode::Replay(_))
+    }
+}
+
+/// `start_recording()` on the current recorder (the process-wide one, unless within
+/// `with_recorder()`)
+pub fn start_recording(path: &Path) -> io::Result<()> {
+    current().start_recording(path)
+}
+
+/// `start_replay()` on the current recorder
+pub fn start_replay(path: &Path) -> anyhow::Result<usize> {
+    current().start_replay(path)
+}
+
+/// `stop()` on the current recorder
+pub fn stop() -> Option<Replay> {
+    current().stop()
+}
+
+/// `start_recording($FFI_RECORD)` or `start_replay($FFI_REPLAY)`, whichever is set (and
+/// neither, if none is); call it before `zygote::start_global()`, for the output of the
+/// fork server's workers to be recorded too
+pub fn init_from_env() -> anyhow::Result<()> {
+    if let Some(path) = std::env::var_os(REPLAY_ENV) {
+        start_replay(Path::new(&path))?;
+    } else if let Some(path) = std::env::var_os(RECORD_ENV) {
+        start_recording(Path::new(&path))
+            .map_err(|e| anyhow::anyhow!("{}: {}", Path::new(&path).display(), e))?;
+    }
+    Ok(())
+}
+
+pub fn is_recording() -> bool {
+    current().is_recording()
+}
+
+pub fn is_replaying() -> bool {
+    current().is_replaying()
+}
+
+thread_local! {
+    static ARGUMENTS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The arguments of the next guarded call made by this thread, for the recording (and
+/// for the replay to tell the calls apart by); a plain `fn()` has none, unless its
+/// caller says so
+pub fn set_arguments(arguments: Vec<String>) {
+    ARGUMENTS.with(|next| *next.borrow_mut() = arguments);
+}
+
+pub(crate) fn take_arguments() -> Vec<String> {
+    ARGUMENTS.with(|next| std::mem::take(&mut *next.borrow_mut()))
+}
+
+impl Recorder {
+    /// The recorded answer, if we are replaying; prints what the call printed back then
+    pub(crate) fn replay(&self, function: &str, arguments: &[String]) -> Option<CallReport> {
+        let report = match &mut *self.lock_mode() {
+            Mode::Replay(replay) => replay.answer(function, arguments),
+            _ => return None,
+        };
+        if let Some(output) = &report.output {
+            print!("{}", output);
+            let _ = io::stdout().flush();
+        }
+        Some(report)
+    }
+
+    /// Appends the call to the recording, if we are recording
+    pub(crate) fn record(
+        &self,
+        function: &str,
+        arguments: &[String],
+        mode: IsolationMode,
+        report: &CallReport,
+    ) {
+        let mode_guard = self.lock_mode();
+        let Mode::Record(path) = &*mode_guard else {
+            return;
+        };
+        #[derive(Serialize)]
+        struct OneCall<'a> {
+            call: [&'a RecordedCall; 1],
+        }
+        let call = RecordedCall::new(function, arguments, mode, report);
+        let appended = toml::to_string(&OneCall { call: [&call] })
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            .and_then(|text| {
+                let mut file = OpenOptions::new().append(true).open(path)?;
+                writeln!(file)?;
+                file.write_all(text.as_bytes())
+            });
+        if let Err(e) = appended {
+            eprintln!("record_replay: could not record {}: {}", function, e);
+        }
+    }
+}
+
+/// A file for a worker's stdout to go to, if we are recording (an anonymous one, see
+/// `crate::anonymous_file()`); a pipe would need draining while the call is running
+#[cfg(unix)]
+pub(crate) fn output_file() -> Option<File> {
+    if !current().is_recording() {
+        return None;
+    }
+    match crate::anonymous_file("output") {
+        Ok(file) => Some(file),
+        Err(e) => {
+            eprintln!("record_replay: no output capture: {}", e);
+            None
+        }
+    }
+}
+
+/// Once the worker is dead: what it printed (which we print on its behalf), as the 'O'
+/// side record of the report, i.e. with the newlines escaped
+#[cfg(unix)]
+pub(crate) fn output_record(mut file: File) -> String {
+    use std::io::{Read, Seek};
+
+    let mut output = String::new();
+    let _ = file.rewind();
+    let _ = file.read_to_string(&mut output);
+    print!("{}", output);
+    let _ = io::stdout().flush();
+    let mut record = String::from("O");
+    for c in output.chars() {
+        match c {
+            '\\' => record.push_str("\\\\"),
+            '\n' => record.push_str("\\n"),
+            c => record.push(c),
+        }
+    }
+    record.push('\n');
+    record
+}
+
+pub(crate) fn decode_output(line: &str) -> Option<String> {
+    let escaped = line.strip_prefix('O')?;
+    let mut output = String::new();
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => output.push('\n'),
+            Some(c) => output.push(c),
+            None => output.push('\\'),
+        }
+    }
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_answers_in_recorded_order() {
+        let path = crate::temp_file_of_program(&format!("replay.{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[[call]]\nfunction = \"f\"\narguments = [\"1\"]\noutcome = \"returned\"\nvalue = \"one\"\n\n\
+             [[call]]\nfunction = \"f\"\narguments = [\"2\"]\noutcome = \"exited\"\nstatus = 2\n\
+             output = \"bye\\n\"\n\n\
+             [[call]]\nfunction = \"f\"\narguments = [\"1\"]\noutcome = \"limit_exceeded\"\n\
+             limit = \"WallTime\"\n",
+        )
+        .unwrap();
+        let mut replay = Replay::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let args = |arg: &str| vec![arg.to_string()];
+
+        assert_eq!(replay.remaining(), 3);
+        let report = replay.answer("f", &args("2"));
+        assert_eq!(report.outcome, CallOutcome::Exited(2));
+        assert_eq!(report.output.as_deref(), Some("bye\n"));
+        assert_eq!(
+            replay.answer("f", &args("1")).outcome,
+            CallOutcome::Returned("one".into())
+        );
+        assert_eq!(
+            replay.answer("f", &args("1")).outcome,
+            CallOutcome::LimitExceeded(ResourceLimit::WallTime)
+        );
+        assert!(
+            matches!(replay.answer("f", &args("1")).outcome, CallOutcome::Failed(msg)
+            if msg.contains("more often"))
+        );
+        assert!(
+            matches!(replay.answer("g", &[]).outcome, CallOutcome::Failed(msg)
+            if msg.contains("is not in"))
+        );
+        assert_eq!(replay.remaining(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_record_a_forked_call() {
+        use crate::isolation::call_isolated_report;
+        use crate::sandbox::SandboxLimits;
+
+        // straight to fd 1, like the C library's printf(); libtest would capture println!()
+        fn fn_print_and_exit() -> anyhow::Result<String> {
+            let text = "about to exit \\ with 3\nno newline";
+            unsafe {
+                libc::write(libc::STDOUT_FILENO, text.as_ptr().cast(), text.len());
+                libc::_exit(3)
+            }
+        }
+        crate::metrics::name_function(fn_print_and_exit, "record_replay_test");
+
+        // a recorder of our own: the calls the other tests make at the same time neither
+        // get recorded nor get their stdout captured
+        let recorder = Arc::new(Recorder::default());
+        let path = crate::temp_file_of_program(&format!("record.{}.toml", std::process::id()));
+        recorder.start_recording(&path).unwrap();
+        let live = with_recorder(recorder.clone(), || {
+            set_arguments(vec!["3".into()]);
+            call_isolated_report(
+                IsolationMode::Fork,
+                &SandboxLimits::default(),
+                fn_print_and_exit,
+            )
+        });
+        recorder.stop();
+        assert!(!is_recording());
+        assert_eq!(live.outcome, CallOutcome::Exited(3));
+        assert_eq!(
+            live.output.as_deref(),
+            Some("about to exit \\ with 3\nno newline")
+        );
+
+        let mut replay = Replay::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let replayed = replay.answer("record_replay_test", &["3".to_string()]);
+        assert_eq!(replayed.outcome, live.outcome);
+        assert_eq!(replayed.output, live.output);
+    }
+}
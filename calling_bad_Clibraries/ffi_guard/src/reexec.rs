@@ -0,0 +1,140 @@
+// src/reexec.rs
+//
+// Re-exec worker mode: instead of fork()ing the host, spawn a brand new copy of our own
+// executable with a hidden worker entry point, and let THAT call the C function.
+// multiple_threads_apartment() shows that we are multithreaded, and fork() in a
+// multithreaded process only copies the calling thread; if any other thread was holding
+// a lock at that moment (malloc()'s arena lock, stdout's lock, ...), the child inherits it
+// locked, with nobody left to unlock it, and deadlocks on its first println!() or Box.
+// A fresh exec has no such baggage.  The child std::process::Command forks only runs a
+// couple of fcntl()s (see `spawn_worker()`) before its exec(), so the host's memory is
+// never copied either.
+//
+// Since the worker is a different process image, the host cannot just send it a fn
+// pointer (ASLR puts the executable at a different base address each time).  But it is
+// the SAME executable, so the distance between any two functions is the same in both;
+// we send the offset of the function from `worker_main()` and the worker adds it back.
+//
+// The worker gets:
+//      argv: <exe> __ffi_worker <offset from worker_main> <report fd>
+//      env:  FFI_WORKER_LIMITS=<SandboxLimits::to_env_value()>
+//            FFI_WORKER_HEARTBEAT=<parent pid>[,<heartbeat fd>,<interval>] (see heartbeat.rs)
+//            FFI_WORKER_CALL_ID=<call id>, FFI_SHARED_LOG=<path> (see shared_log.rs)
+//            FFI_WORKER_OUTPUT=1 if its stdout gets captured (see record_replay.rs)
+// and reports back over the inherited <report fd> exactly like a forked child would.
+
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use crate::heartbeat::WorkerSide;
+use crate::isolation::{cloexec_pipe, inherit_fd, run_as_worker, Worker};
+use crate::sandbox::SandboxLimits;
+
+const WORKER_ARG: &str = "__ffi_worker";
+const LIMITS_ENV: &str = "FFI_WORKER_LIMITS";
+const HEARTBEAT_ENV: &str = "FFI_WORKER_HEARTBEAT";
+const OUTPUT_ENV: &str = "FFI_WORKER_OUTPUT";
+
+// The report and heartbeat pipes are created close-on-exec, and only the worker's copies
+// of its ends are made inheritable, between fork() and exec() (see `spawn_worker()`); so
+// another worker spawned at the same time never gets them (it would keep our pipes open,
+// and us blocked on read, for as long as it lives).
+
+/// Hidden entry point; main() has to call this before doing anything else.  Returns (and
+/// does nothing) if we were not started as a worker, else never returns.
+pub fn worker_entry() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 4 || args[1] != WORKER_ARG {
+        return;
+    }
+    let (Ok(offset), Ok(report_fd)) = (args[2].parse::<isize>(), args[3].parse::<libc::c_int>())
+    else {
+        eprintln!("worker_entry(): malformed worker arguments {:?}", args);
+        unsafe { libc::_exit(127) };
+    };
+    let limits = SandboxLimits::from_env_value(&std::env::var(LIMITS_ENV).unwrap_or_default());
+    // without it (i.e. started by hand), at least die along with whoever started us
+    let heartbeat = std::env::var(HEARTBEAT_ENV)
+        .ok()
+        .and_then(|value| WorkerSide::from_env_value(&value))
+        .unwrap_or_else(|| WorkerSide {
+            parent: unsafe { libc::getppid() },
+            beat: None,
+        });
+    // they are ours now; whatever the C library might exec() does not get them
+    unsafe { libc::fcntl(report_fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    if let Some((beat_fd, _)) = heartbeat.beat {
+        unsafe { libc::fcntl(beat_fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    crate::shared_log::from_worker_env();
+    // our stdout already is the host's capture file, it only needs to be unbuffered
+    if std::env::var_os(OUTPUT_ENV).is_some() {
+        let _ = crate::isolation::redirect_stdout(libc::STDOUT_FILENO);
+    }
+
+    let address = (worker_main as fn() as usize).wrapping_add_signed(offset);
+    // SAFETY: the offset was taken against the same executable, see the top of this file
+    let my_function: fn() -> anyhow::Result<String> = unsafe { std::mem::transmute(address) };
+    run_as_worker(&limits, my_function, report_fd, heartbeat);
+}
+
+// Only its address matters; it is the anchor for the function offsets
+#[inline(never)]
+fn worker_main() {
+    std::hint::black_box(WORKER_ARG);
+}
+
+/// Spawns (but does not wait for) a worker to call `my_function`
+pub(crate) fn spawn_worker(
+    limits: &SandboxLimits,
+    my_function: fn() -> anyhow::Result<String>,
+) -> std::io::Result<Worker> {
+    let exe = std::env::current_exe()?;
+    let offset = (my_function as usize).wrapping_sub(worker_main as fn() as usize) as isize;
+
+    let (read_fd, write_fd) = cloexec_pipe()?;
+    let report = unsafe { std::fs::File::from_raw_fd(read_fd) };
+    let (heartbeat, worker_side) = match crate::heartbeat::pipe(limits) {
+        Ok(heartbeat) => heartbeat,
+        Err(e) => {
+            unsafe { libc::close(write_fd) };
+            return Err(e);
+        }
+    };
+
+    let output = crate::record_replay::output_file();
+
+    let mut command = Command::new(exe);
+    command
+        .arg(WORKER_ARG)
+        .arg(offset.to_string())
+        .arg(write_fd.to_string())
+        .env(LIMITS_ENV, limits.to_env_value())
+        .env(HEARTBEAT_ENV, worker_side.to_env_value())
+        .envs(crate::shared_log::worker_env());
+    if let Some(stdout) = output.as_ref().and_then(|output| output.try_clone().ok()) {
+        command.stdout(stdout).env(OUTPUT_ENV, "1");
+    }
+    let beat_fd = worker_side.beat.map(|(beat_fd, _)| beat_fd);
+    // SAFETY: inherit_fd() is a couple of fcntl()s, fine between fork() and exec()
+    unsafe {
+        command.pre_exec(move || {
+            inherit_fd(write_fd)?;
+            beat_fd.map_or(Ok(()), inherit_fd)
+        });
+    }
+    let spawned = command.spawn();
+    unsafe { libc::close(write_fd) };
+    if let Some((beat_fd, _)) = worker_side.beat {
+        unsafe { libc::close(beat_fd) };
+    }
+    // NOTE: the `Child` itself is dropped without wait(), the `Worker` reaps it by pid
+    Ok(Worker {
+        pid: spawned?.id() as libc::pid_t,
+        report,
+        heartbeat,
+        output,
+        reaped: false,
+    })
+}
@@ -0,0 +1,276 @@
+// src/retry.rs
+//
+// Some vendor calls crash now and then for no reason of ours (a race inside the library),
+// and the same call goes through fine a moment later.  Since the crash only takes the
+// worker down, trying again is cheap; `call_isolated_with_retry()` does that, up to
+// `max_attempts` times, sleeping `initial_backoff`, then `multiplier` times longer after
+// every attempt (up to `max_backoff`).
+//
+// Only the outcomes listed in `retry_on` are retried.  A signal is usually the race; an
+// `exit(n)` usually means the library made up its mind (see shutdown.rs), and calling it
+// again only gets the same answer, hence the default is signals only.  Quarantined and
+// ShuttingDown are never retried, whatever the policy says: both are the host saying no.
+//
+// Every attempt (its report, how long it took, and how long we waited after it) ends up
+// in the `RetriedCall`, so that a success that took three tries does not look like one
+// that took one.  Each attempt is a call of its own as far as the metrics and the trace
+// are concerned.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::isolation::{call_isolated_report, CallReport, IsolationMode};
+use crate::outcome::CallOutcome;
+use crate::sandbox::{ResourceLimit, SandboxLimits};
+
+/// An outcome (or a family of them) worth another attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryOn {
+    AnySignal,
+    Signal(i32),
+    AnyExit,
+    Exit(i32),
+    /// the worker went over its wall-clock or CPU time limit, or went quiet (see heartbeat.rs)
+    Timeout,
+    Panicked,
+    Failed,
+}
+
+impl RetryOn {
+    pub fn matches(&self, outcome: &CallOutcome) -> bool {
+        match (self, outcome) {
+            (RetryOn::AnySignal, CallOutcome::Signaled(_)) => true,
+            (RetryOn::Signal(wanted), CallOutcome::Signaled(signal)) => wanted == signal,
+            (RetryOn::AnyExit, CallOutcome::Exited(_)) => true,
+            (RetryOn::Exit(wanted), CallOutcome::Exited(status)) => wanted == status,
+            (
+                RetryOn::Timeout,
+                CallOutcome::LimitExceeded(
+                    ResourceLimit::WallTime | ResourceLimit::CpuTime | ResourceLimit::Heartbeat,
+                ),
+            ) => true,
+            (RetryOn::Panicked, CallOutcome::Panicked(_)) => true,
+            (RetryOn::Failed, CallOutcome::Failed(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// including the first one; 1 means no retries
+    pub max_attempts: u32,
+    /// how long to wait after the first failed attempt
+    pub initial_backoff: Duration,
+    /// each wait is this many times longer than the one before
+    pub multiplier: u32,
+    pub max_backoff: Duration,
+    pub retry_on: Vec<RetryOn>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            multiplier: 2,
+            max_backoff: Duration::from_secs(5),
+            retry_on: vec![RetryOn::AnySignal],
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn is_retryable(&self, outcome: &CallOutcome) -> bool {
+        !matches!(
+            outcome,
+            CallOutcome::Quarantined | CallOutcome::ShuttingDown
+        ) && self
+            .retry_on
+            .iter()
+            .any(|retry_on| retry_on.matches(outcome))
+    }
+
+    /// How long to wait after attempt number `attempt` (1 based) failed
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = self
+            .multiplier
+            .checked_pow(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+/// One attempt of a retried call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attempt {
+    pub report: CallReport,
+    pub elapsed: Duration,
+    /// how long we waited before the next attempt; None for the last one
+    pub backoff: Option<Duration>,
+}
+
+/// Every attempt of a call, the last one being the one that counts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetriedCall {
+    pub attempts: Vec<Attempt>,
+}
+
+impl RetriedCall {
+    /// The outcome of the last attempt
+    pub fn outcome(&self) -> &CallOutcome {
+        &self.last().report.outcome
+    }
+
+    pub fn last(&self) -> &Attempt {
+        self.attempts
+            .last()
+            .expect("a retried call has at least one attempt")
+    }
+
+    /// True if it took more than one attempt to get where we got
+    pub fn was_retried(&self) -> bool {
+        self.attempts.len() > 1
+    }
+}
+
+impl fmt::Display for RetriedCall {
+    /// "returned: 42 after 3 attempts (killed by signal 11, killed by signal 11, returned: 42)"
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.outcome())?;
+        if self.was_retried() {
+            let outcomes: Vec<String> = self
+                .attempts
+                .iter()
+                .map(|attempt| attempt.report.outcome.to_string())
+                .collect();
+            write!(
+                f,
+                " after {} attempts ({})",
+                self.attempts.len(),
+                outcomes.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Same as `call_isolated_report()`, but tries again (after a backoff) for as long as
+/// the outcome is retryable and there are attempts left
+pub fn call_isolated_with_retry(
+    mode: IsolationMode,
+    limits: &SandboxLimits,
+    policy: &RetryPolicy,
+    my_function: fn() -> anyhow::Result<String>,
+) -> RetriedCall {
+    let mut attempts: Vec<Attempt> = Vec::new();
+    loop {
+        let start = Instant::now();
+        let report = call_isolated_report(mode, limits, my_function);
+        let attempt = attempts.len() as u32 + 1;
+        let retry = attempt < policy.max_attempts.max(1) && policy.is_retryable(&report.outcome);
+        let backoff = retry.then(|| policy.backoff(attempt));
+        if let Some(backoff) = backoff {
+            tracing::warn!(
+                attempt,
+                backoff_ms = backoff.as_millis() as u64,
+                outcome = %report.outcome,
+                "retrying"
+            );
+        }
+        attempts.push(Attempt {
+            report,
+            elapsed: start.elapsed(),
+            backoff,
+        });
+        match backoff {
+            Some(backoff) => std::thread::sleep(backoff),
+            None => return RetriedCall { attempts },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    fn test_backoff_and_retryable() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            multiplier: 3,
+            max_backoff: Duration::from_secs(1),
+            retry_on: vec![RetryOn::Signal(libc::SIGSEGV), RetryOn::Exit(75)],
+        };
+        let backoffs: Vec<u128> = (1..=5).map(|n| policy.backoff(n).as_millis()).collect();
+        assert_eq!(backoffs, vec![100, 300, 900, 1000, 1000]);
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(1));
+
+        assert!(policy.is_retryable(&CallOutcome::Signaled(libc::SIGSEGV)));
+        assert!(!policy.is_retryable(&CallOutcome::Signaled(libc::SIGFPE)));
+        assert!(policy.is_retryable(&CallOutcome::Exited(75)));
+        assert!(!policy.is_retryable(&CallOutcome::Exited(102)));
+        assert!(!policy.is_retryable(&CallOutcome::Returned("75".into())));
+        let anything = RetryPolicy {
+            retry_on: vec![RetryOn::AnySignal, RetryOn::AnyExit, RetryOn::Failed],
+            ..Default::default()
+        };
+        assert!(!anything.is_retryable(&CallOutcome::ShuttingDown));
+    }
+
+    static FLAKY_CALLS: AtomicU32 = AtomicU32::new(0);
+
+    // thread mode, so that the counter is ours
+    fn panics_twice() -> anyhow::Result<String> {
+        match FLAKY_CALLS.fetch_add(1, Ordering::SeqCst) {
+            0 | 1 => panic!("lost the race"),
+            _ => Ok("third time lucky".into()),
+        }
+    }
+
+    fn exits() -> anyhow::Result<String> {
+        std::process::exit(3);
+    }
+
+    #[test]
+    fn test_every_attempt_is_recorded() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            retry_on: vec![RetryOn::Panicked, RetryOn::AnySignal],
+            ..Default::default()
+        };
+        let limits = SandboxLimits::default();
+        let call = call_isolated_with_retry(IsolationMode::Thread, &limits, &policy, panics_twice);
+        assert_eq!(
+            call.outcome(),
+            &CallOutcome::Returned("third time lucky".into())
+        );
+        assert_eq!(call.attempts.len(), 3);
+        let backoffs: Vec<_> = call
+            .attempts
+            .iter()
+            .map(|attempt| attempt.backoff)
+            .collect();
+        assert_eq!(
+            backoffs,
+            vec![
+                Some(Duration::from_millis(1)),
+                Some(Duration::from_millis(2)),
+                None
+            ]
+        );
+        assert!(call
+            .to_string()
+            .starts_with("returned: third time lucky after 3 attempts (panicked: lost the race, "));
+
+        // exits are not on the list
+        let call = call_isolated_with_retry(IsolationMode::Fork, &limits, &policy, exits);
+        assert_eq!(call.outcome(), &CallOutcome::Exited(3));
+        assert!(!call.was_retried());
+    }
+}
@@ -0,0 +1,407 @@
+// src/sandbox.rs
+//
+// Optional limits for the forked child that runs a foreign call, so that a misbehaving
+// vendor library cannot fork-bomb, eat all the memory, or write files all over the place.
+// All of these are applied INSIDE the child (after fork(), before calling the C function),
+// hence they only make sense for process isolation; setrlimit() on a thread would limit
+// the entire host.
+//
+// NOTE: this is Linux only (seccomp and prctl() are Linux specific); elsewhere, asking
+// for any limit at all is an error.
+
+use crate::outcome::CallOutcome;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceLimit {
+    /// RLIMIT_AS
+    AddressSpace,
+    /// RLIMIT_CPU (child gets SIGXCPU)
+    CpuTime,
+    /// RLIMIT_FSIZE (child gets SIGXFSZ)
+    FileSize,
+    /// RLIMIT_NOFILE
+    OpenFiles,
+    /// seccomp allow-list (child gets SIGSYS)
+    Syscall,
+    /// alarm() (child gets SIGALRM)
+    WallTime,
+    /// no heartbeat from the worker for too long (the host SIGKILLs it, see heartbeat.rs)
+    Heartbeat,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SandboxLimits {
+    /// max bytes of virtual address space
+    pub address_space: Option<u64>,
+    /// max seconds of CPU time
+    pub cpu_time_secs: Option<u64>,
+    /// max seconds of wall-clock time, which also catches a child that hangs without
+    /// burning any CPU (i.e. waiting on a lock that will never be released)
+    pub wall_time_secs: Option<u64>,
+    /// max bytes of any file the child writes
+    pub file_size: Option<u64>,
+    /// max number of open file descriptors
+    pub open_files: Option<u64>,
+    /// set PR_SET_NO_NEW_PRIVS (implied when `allowed_syscalls` is set)
+    pub no_new_privs: bool,
+    /// if set, any syscall NOT in this list kills the child with SIGSYS
+    pub allowed_syscalls: Option<Vec<libc::c_long>>,
+}
+
+impl SandboxLimits {
+    pub fn is_unrestricted(&self) -> bool {
+        *self == SandboxLimits::default()
+    }
+
+    /// The bare minimum for a child to call into the C library, printf(), write to the
+    /// shared log (if it was opened before the sandbox was applied) and report back to the
+    /// parent over the pipe.  Notice that fork/clone/execve/open are NOT here.
+    #[cfg(target_os = "linux")]
+    pub fn minimal_syscalls() -> Vec<libc::c_long> {
+        vec![
+            libc::SYS_read,
+            libc::SYS_write,
+            libc::SYS_close,
+            libc::SYS_fstat,
+            libc::SYS_newfstatat,
+            libc::SYS_lseek,
+            libc::SYS_pread64, // shared_log.rs, along with flock
+            libc::SYS_flock,
+            libc::SYS_ioctl, // isatty() from stdio
+            libc::SYS_brk,
+            libc::SYS_mmap,
+            libc::SYS_munmap,
+            libc::SYS_mremap,
+            libc::SYS_mprotect,
+            libc::SYS_madvise,
+            libc::SYS_futex,
+            libc::SYS_rt_sigreturn,
+            libc::SYS_rt_sigaction,
+            libc::SYS_rt_sigprocmask,
+            libc::SYS_sigaltstack,
+            libc::SYS_getpid,
+            libc::SYS_gettid,
+            libc::SYS_tgkill, // abort()
+            libc::SYS_clock_gettime,
+            libc::SYS_nanosleep, // the heartbeat thread, see heartbeat.rs
+            libc::SYS_clock_nanosleep,
+            libc::SYS_sched_yield,
+            libc::SYS_getrandom,
+            libc::SYS_exit,
+            libc::SYS_exit_group,
+        ]
+    }
+
+    /// Flattens the limits into one string (i.e. for an environment variable), so that
+    /// they survive an exec(); the format is
+    /// "as=N;cpu=N;wall=N;fsize=N;nofile=N;nnp=1;syscalls=N,N"
+    pub fn to_env_value(&self) -> String {
+        let mut fields = Vec::new();
+        let numbers = [
+            ("as", self.address_space),
+            ("cpu", self.cpu_time_secs),
+            ("wall", self.wall_time_secs),
+            ("fsize", self.file_size),
+            ("nofile", self.open_files),
+        ];
+        for (key, value) in numbers {
+            if let Some(value) = value {
+                fields.push(format!("{}={}", key, value));
+            }
+        }
+        if self.no_new_privs {
+            fields.push("nnp=1".into());
+        }
+        if let Some(allowed) = &self.allowed_syscalls {
+            let allowed: Vec<String> = allowed.iter().map(|nr| nr.to_string()).collect();
+            fields.push(format!("syscalls={}", allowed.join(",")));
+        }
+        fields.join(";")
+    }
+
+    /// Inverse of `to_env_value()`; unknown or malformed fields are ignored
+    pub fn from_env_value(value: &str) -> SandboxLimits {
+        let mut limits = SandboxLimits::default();
+        for field in value.split(';') {
+            let Some((key, value)) = field.split_once('=') else {
+                continue;
+            };
+            match key {
+                "as" => limits.address_space = value.parse().ok(),
+                "cpu" => limits.cpu_time_secs = value.parse().ok(),
+                "wall" => limits.wall_time_secs = value.parse().ok(),
+                "fsize" => limits.file_size = value.parse().ok(),
+                "nofile" => limits.open_files = value.parse().ok(),
+                "nnp" => limits.no_new_privs = value == "1",
+                "syscalls" => {
+                    limits.allowed_syscalls =
+                        Some(value.split(',').filter_map(|nr| nr.parse().ok()).collect())
+                }
+                _ => {}
+            }
+        }
+        limits
+    }
+
+    /// Applies the limits to the CURRENT process; only to be called inside the forked child
+    #[cfg(target_os = "linux")]
+    pub fn apply_to_self(&self) -> std::io::Result<()> {
+        set_rlimit(libc::RLIMIT_AS, self.address_space)?;
+        set_rlimit(libc::RLIMIT_CPU, self.cpu_time_secs)?;
+        set_rlimit(libc::RLIMIT_FSIZE, self.file_size)?;
+        set_rlimit(libc::RLIMIT_NOFILE, self.open_files)?;
+        if let Some(secs) = self.wall_time_secs {
+            // SIGALRM's default action is to terminate; a C library that handles (or
+            // blocks) it gets to outlive the limit
+            unsafe { libc::alarm(secs.clamp(1, libc::c_uint::MAX as u64) as libc::c_uint) };
+        }
+        if self.no_new_privs || self.allowed_syscalls.is_some() {
+            set_no_new_privs()?;
+        }
+        if let Some(allowed) = &self.allowed_syscalls {
+            install_seccomp_allow_list(allowed)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply_to_self(&self) -> std::io::Result<()> {
+        if self.is_unrestricted() {
+            return Ok(());
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "sandbox limits are Linux only",
+        ))
+    }
+
+    /// Re-interprets a child's death in terms of the limits that were set on it, i.e. a
+    /// SIGXCPU is not just "some signal" when we were the ones to set RLIMIT_CPU.
+    /// Hitting RLIMIT_AS or RLIMIT_NOFILE does not kill anybody by itself (malloc()/open()
+    /// just fail), so RLIMIT_AS only gets the blame for an abort() if the child reported
+    /// an allocation that failed (`allocation_failed`, which takes `--features
+    /// malloc_wrap`, see alloc_accounting.rs); any other abort() (an assert(), glibc's
+    /// heap corruption check) stays a SIGABRT.  RLIMIT_NOFILE cannot be told apart and
+    /// comes back as whatever the C library did about the EMFILE.
+    pub fn explain(&self, outcome: CallOutcome, allocation_failed: bool) -> CallOutcome {
+        let signal = match outcome {
+            CallOutcome::Signaled(signal) => signal,
+            other => return other,
+        };
+        #[cfg(unix)]
+        {
+            if signal == libc::SIGXCPU && self.cpu_time_secs.is_some() {
+                return CallOutcome::LimitExceeded(ResourceLimit::CpuTime);
+            }
+            if signal == libc::SIGXFSZ && self.file_size.is_some() {
+                return CallOutcome::LimitExceeded(ResourceLimit::FileSize);
+            }
+            if signal == libc::SIGSYS && self.allowed_syscalls.is_some() {
+                return CallOutcome::LimitExceeded(ResourceLimit::Syscall);
+            }
+            if signal == libc::SIGALRM && self.wall_time_secs.is_some() {
+                return CallOutcome::LimitExceeded(ResourceLimit::WallTime);
+            }
+            if signal == libc::SIGABRT && self.address_space.is_some() && allocation_failed {
+                return CallOutcome::LimitExceeded(ResourceLimit::AddressSpace);
+            }
+        }
+        CallOutcome::Signaled(signal)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_rlimit(resource: libc::__rlimit_resource_t, limit: Option<u64>) -> std::io::Result<()> {
+    let Some(limit) = limit else {
+        return Ok(());
+    };
+    let rlim = libc::rlimit {
+        rlim_cur: limit as libc::rlim_t,
+        rlim_max: limit as libc::rlim_t,
+    };
+    if unsafe { libc::setrlimit(resource, &rlim) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_no_new_privs() -> std::io::Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// AUDIT_ARCH_* from <linux/audit.h>; the filter refuses to run syscalls of any other ABI
+// (i.e. 32-bit int 0x80 on x86_64) since the syscall numbers would not match
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const AUDIT_ARCH: u32 = 0xC000_003E;
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn install_seccomp_allow_list(allowed: &[libc::c_long]) -> std::io::Result<()> {
+    fn stmt(code: u32, k: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+    // offsets into `struct seccomp_data { int nr; __u32 arch; ... }`
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+
+    let mut filter = vec![
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, ARCH_OFFSET),
+        jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            AUDIT_ARCH,
+            1,
+            0,
+        ),
+        stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, NR_OFFSET),
+    ];
+    for nr in allowed {
+        // if equal, fall into the ALLOW below; else skip over it to the next compare
+        filter.push(jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            *nr as u32,
+            0,
+            1,
+        ));
+        filter.push(stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+    }
+    filter.push(stmt(
+        libc::BPF_RET | libc::BPF_K,
+        libc::SECCOMP_RET_KILL_PROCESS,
+    ));
+
+    let program = libc::sock_fprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_mut_ptr(),
+    };
+    let result = unsafe {
+        libc::prctl(
+            libc::PR_SET_SECCOMP,
+            libc::SECCOMP_MODE_FILTER as libc::c_ulong,
+            &program as *const libc::sock_fprog as libc::c_ulong,
+            0,
+            0,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(all(
+    target_os = "linux",
+    not(any(target_arch = "x86_64", target_arch = "aarch64"))
+))]
+fn install_seccomp_allow_list(_allowed: &[libc::c_long]) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "seccomp allow-list is only supported on Linux x86_64/aarch64",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_explain_only_blames_limits_that_were_set() {
+        let unrestricted = SandboxLimits::default();
+        assert_eq!(
+            unrestricted.explain(CallOutcome::Signaled(libc::SIGXCPU), false),
+            CallOutcome::Signaled(libc::SIGXCPU)
+        );
+
+        let limits = SandboxLimits {
+            cpu_time_secs: Some(1),
+            wall_time_secs: Some(1),
+            file_size: Some(0),
+            allowed_syscalls: Some(vec![]),
+            ..Default::default()
+        };
+        assert_eq!(
+            limits.explain(CallOutcome::Signaled(libc::SIGXCPU), false),
+            CallOutcome::LimitExceeded(ResourceLimit::CpuTime)
+        );
+        assert_eq!(
+            limits.explain(CallOutcome::Signaled(libc::SIGXFSZ), false),
+            CallOutcome::LimitExceeded(ResourceLimit::FileSize)
+        );
+        assert_eq!(
+            limits.explain(CallOutcome::Signaled(libc::SIGSYS), false),
+            CallOutcome::LimitExceeded(ResourceLimit::Syscall)
+        );
+        assert_eq!(
+            limits.explain(CallOutcome::Signaled(libc::SIGALRM), false),
+            CallOutcome::LimitExceeded(ResourceLimit::WallTime)
+        );
+        // seg-faults are still just seg-faults
+        assert_eq!(
+            limits.explain(CallOutcome::Signaled(libc::SIGSEGV), false),
+            CallOutcome::Signaled(libc::SIGSEGV)
+        );
+        assert_eq!(
+            limits.explain(CallOutcome::Exited(1), false),
+            CallOutcome::Exited(1)
+        );
+
+        // an abort() is only RLIMIT_AS's doing if an allocation is known to have failed
+        let limits = SandboxLimits {
+            address_space: Some(64 << 20),
+            ..Default::default()
+        };
+        assert_eq!(
+            limits.explain(CallOutcome::Signaled(libc::SIGABRT), false),
+            CallOutcome::Signaled(libc::SIGABRT)
+        );
+        assert_eq!(
+            limits.explain(CallOutcome::Signaled(libc::SIGABRT), true),
+            CallOutcome::LimitExceeded(ResourceLimit::AddressSpace)
+        );
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn test_syscall_outside_allow_list_kills_child() {
+        use crate::isolation::{call_isolated_with, IsolationMode};
+
+        let limits = SandboxLimits {
+            allowed_syscalls: Some(SandboxLimits::minimal_syscalls()),
+            ..Default::default()
+        };
+        let fn_getppid = || Ok(format!("{}", unsafe { libc::getppid() }));
+        assert_eq!(
+            call_isolated_with(IsolationMode::Fork, &limits, fn_getppid),
+            CallOutcome::LimitExceeded(ResourceLimit::Syscall)
+        );
+        let fn_harmless = || Ok("harmless".to_string());
+        assert_eq!(
+            call_isolated_with(IsolationMode::Fork, &limits, fn_harmless),
+            CallOutcome::Returned("harmless".into())
+        );
+    }
+}
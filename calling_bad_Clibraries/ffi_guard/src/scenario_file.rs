@@ -0,0 +1,389 @@
+// src/scenario_file.rs
+//
+// Every new way for the C library to go wrong used to mean one more `do_xxx()` in main.rs,
+// and a Rust programmer to write it.  A scenario file says the same thing in TOML, one
+// call per file, so QA can add cases without touching (or rebuilding) the Rust side:
+//
+//      # scenarios/checksum_unlucky.toml
+//      function = "mid_checksum"
+//      signature = "int(int, int)"     # see dyn_call.rs for what signatures work
+//      args = [13, 1]
+//      library = "libmid_exit.so"      # defaults to the runner's, see `run_dir()`
+//      mode = "fork"                   # the default; or "thread"
+//      timeout_secs = 5                # the default is 10 (fork only)
+//
+//      [expect]                        # at least one of these, ALL of them must hold
+//      exit_code = 13
+//      stdout = "unlucky count"        # a substring of what the call printed
+//      # returns = 11, signal = "SIGSEGV" (or 11), timeout = true
+//
+// `run_dir()` (i.e. `cargo run -- scenarios [dir]` in the demo) runs every `*.toml` in the
+// directory (in file name order) and reports which passed and which did not.  The call
+// itself goes through dyn_call.rs, i.e. the library is dlopen()ed in the worker.
+//
+// The call is handed to the worker by inheritance (same as dyn_call.rs does it), hence
+// "fork" and "thread" only.  "thread" is there for the scenarios that are expected to
+// return (it takes the runner down with it otherwise), and cannot have a timeout or look
+// at stdout (fd 1 belongs to the whole process).
+
+use std::io::{Read, Seek};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::Deserialize;
+
+use crate::dyn_call::{find_library, DynCall};
+use crate::isolation::{call_isolated_report, CallReport, IsolationMode};
+use crate::outcome::CallOutcome;
+use crate::sandbox::{ResourceLimit, SandboxLimits};
+
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScenarioMode {
+    #[default]
+    Fork,
+    Thread,
+}
+
+/// One scenario file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)] // a typo in a field name should not quietly pass
+pub struct Scenario {
+    /// defaults to the file name (without the `.toml`)
+    #[serde(default)]
+    pub name: String,
+    /// defaults to the library the runner was given; see `dyn_call::find_library()`
+    #[serde(default)]
+    pub library: String,
+    pub function: String,
+    pub signature: String,
+    /// numbers or strings, each one converted as the signature says
+    #[serde(default)]
+    pub args: Vec<toml::Value>,
+    #[serde(default)]
+    pub mode: ScenarioMode,
+    pub timeout_secs: Option<u64>,
+    pub expect: Expect,
+}
+
+/// What the scenario expects of the call; every field that is set has to hold
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Expect {
+    /// the return value, as `cargo run -- call` prints it
+    pub returns: Option<toml::Value>,
+    pub exit_code: Option<i32>,
+    /// a number, or a name ("SIGSEGV")
+    pub signal: Option<toml::Value>,
+    /// `true` if the call is expected to hang (and be killed at `timeout_secs`)
+    pub timeout: Option<bool>,
+    /// a substring of the call's stdout
+    pub stdout: Option<String>,
+}
+
+/// How a scenario went
+#[derive(Debug, Clone)]
+pub struct ScenarioResult {
+    pub file: PathBuf,
+    pub name: String,
+    /// None if the scenario did not get as far as the call (i.e. a bad file)
+    pub outcome: Option<CallOutcome>,
+    /// one entry per expectation that did not hold; empty means it passed
+    pub failures: Vec<String>,
+}
+
+impl ScenarioResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl Scenario {
+    /// `default_library` is for the scenarios that do not name a library of their own
+    pub fn load(path: &Path, default_library: Option<&str>) -> Result<Scenario, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut scenario: Scenario = toml::from_str(&text).map_err(|e| e.to_string())?;
+        if scenario.library.is_empty() {
+            scenario.library = default_library
+                .ok_or("no library, and the runner has no default either")?
+                .to_string();
+        }
+        if scenario.name.is_empty() {
+            scenario.name = path
+                .file_stem()
+                .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+        }
+        scenario.validate()?;
+        Ok(scenario)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let expect = &self.expect;
+        if expect.returns.is_none()
+            && expect.exit_code.is_none()
+            && expect.signal.is_none()
+            && expect.timeout.is_none()
+            && expect.stdout.is_none()
+        {
+            return Err("[expect] is empty, a scenario has to expect something".into());
+        }
+        if let Some(signal) = &expect.signal {
+            signal_number(signal)?;
+        }
+        if self.mode == ScenarioMode::Thread {
+            if self.timeout_secs.is_some() || expect.timeout.is_some() {
+                return Err("timeouts need mode = \"fork\"".into());
+            }
+            if expect.stdout.is_some() {
+                return Err("checking stdout needs mode = \"fork\"".into());
+            }
+        }
+        self.call().map(|_| ())
+    }
+
+    /// The call, as dyn_call.rs wants it
+    pub fn call(&self) -> Result<DynCall, String> {
+        let args: Vec<String> = self.args.iter().map(value_text).collect();
+        DynCall::parse(
+            &find_library(&self.library),
+            &self.function,
+            &self.signature,
+            &args,
+        )
+    }
+
+    /// Runs the call, returns what happened and what it printed on stdout
+    pub fn run(&self) -> Result<(CallReport, String), String> {
+        let call = self.call()?;
+        let (mode, limits) = match self.mode {
+            ScenarioMode::Fork => (
+                IsolationMode::Fork,
+                SandboxLimits {
+                    wall_time_secs: Some(self.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS)),
+                    ..Default::default()
+                },
+            ),
+            ScenarioMode::Thread => (IsolationMode::Thread, SandboxLimits::default()),
+        };
+
+        // one call at a time, the worker finds its call in NEXT_SCENARIO
+        let _serialized = lock(&CALL_LOCK);
+        // the worker's stdout goes to a file of ours; a pipe would need draining while
+        // the call is running
+        let mut stdout_file = crate::anonymous_file("scenario.out")
+            .map_err(|e| format!("no file for the worker's stdout: {}", e))?;
+
+        // while recording (or replaying), the worker's stdout is already taken care of, and
+        // comes back as `CallReport::output` (see record_replay.rs)
+        let captured = crate::record_replay::is_recording() || crate::record_replay::is_replaying();
+        *lock(&NEXT_SCENARIO) = Some(NextScenario {
+            call: call.clone(),
+            stdout_fd: (mode == IsolationMode::Fork && !captured).then(|| stdout_file.as_raw_fd()),
+        });
+        crate::metrics::name_function(run_next_scenario, &call.symbol);
+        crate::record_replay::set_arguments(call.arg_texts());
+        let report = call_isolated_report(mode, &limits, run_next_scenario);
+        *lock(&NEXT_SCENARIO) = None;
+
+        let mut stdout = String::new();
+        if captured {
+            stdout = report.output.clone().unwrap_or_default();
+        } else {
+            let _ = stdout_file.rewind();
+            let _ = stdout_file.read_to_string(&mut stdout);
+        }
+        Ok((report, stdout))
+    }
+
+    /// Whatever did not go as expected, in words
+    pub fn check(&self, outcome: &CallOutcome, stdout: &str) -> Vec<String> {
+        let expect = &self.expect;
+        let mut failures = Vec::new();
+        let mut expected = |wanted: String, holds: bool| {
+            if !holds {
+                failures.push(format!("expected {}, got {}", wanted, outcome));
+            }
+        };
+        if let Some(value) = &expect.returns {
+            let value = value_text(value);
+            let holds = *outcome == CallOutcome::Returned(value.clone());
+            expected(format!("returned: {}", value), holds);
+        }
+        if let Some(status) = expect.exit_code {
+            let holds = *outcome == CallOutcome::Exited(status);
+            expected(CallOutcome::Exited(status).to_string(), holds);
+        }
+        if let Some(Ok(signal)) = expect.signal.as_ref().map(signal_number) {
+            let holds = *outcome == CallOutcome::Signaled(signal);
+            expected(CallOutcome::Signaled(signal).to_string(), holds);
+        }
+        if let Some(timeout) = expect.timeout {
+            let timed_out = *outcome == CallOutcome::LimitExceeded(ResourceLimit::WallTime);
+            expected(
+                if timeout { "a timeout" } else { "no timeout" }.to_string(),
+                timed_out == timeout,
+            );
+        }
+        if let Some(text) = &expect.stdout {
+            if !stdout.contains(text.as_str()) {
+                failures.push(format!("expected {:?} on stdout, got {:?}", text, stdout));
+            }
+        }
+        failures
+    }
+}
+
+/// Loads and runs every `*.toml` in `dir`, in file name order; `default_library` is for
+/// the scenarios that do not name a library of their own
+pub fn run_dir(dir: &Path, default_library: Option<&str>) -> std::io::Result<Vec<ScenarioResult>> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+        .collect();
+    files.sort();
+    Ok(files
+        .into_iter()
+        .map(|file| run_file(&file, default_library))
+        .collect())
+}
+
+pub fn run_file(file: &Path, default_library: Option<&str>) -> ScenarioResult {
+    let name = file
+        .file_stem()
+        .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+    let scenario = match Scenario::load(file, default_library) {
+        Ok(scenario) => scenario,
+        Err(e) => {
+            return ScenarioResult {
+                file: file.to_path_buf(),
+                name,
+                outcome: None,
+                failures: vec![format!("bad scenario file: {}", e)],
+            }
+        }
+    };
+    let (outcome, failures) = match scenario.run() {
+        Ok((report, stdout)) => {
+            let failures = scenario.check(&report.outcome, &stdout);
+            (Some(report.outcome), failures)
+        }
+        Err(e) => (None, vec![format!("could not run: {}", e)]),
+    };
+    ScenarioResult {
+        file: file.to_path_buf(),
+        name: scenario.name,
+        outcome,
+        failures,
+    }
+}
+
+// TOML numbers and strings, as `Arg::parse()` (and the `call` subcommand's output) has them
+fn value_text(value: &toml::Value) -> String {
+    match value {
+        toml::Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn signal_number(value: &toml::Value) -> Result<i32, String> {
+    const SIGNALS: [(&str, i32); 8] = [
+        ("SIGSEGV", libc::SIGSEGV),
+        ("SIGFPE", libc::SIGFPE),
+        ("SIGBUS", libc::SIGBUS),
+        ("SIGILL", libc::SIGILL),
+        ("SIGABRT", libc::SIGABRT),
+        ("SIGKILL", libc::SIGKILL),
+        ("SIGSYS", libc::SIGSYS),
+        ("SIGXCPU", libc::SIGXCPU),
+    ];
+    match value {
+        toml::Value::Integer(number) => Ok(*number as i32),
+        toml::Value::String(name) => SIGNALS
+            .iter()
+            .find(|(signal_name, _)| signal_name == name)
+            .map(|(_, number)| *number)
+            .ok_or_else(|| format!("unknown signal {:?}", name)),
+        other => Err(format!("signal {} is neither a number nor a name", other)),
+    }
+}
+
+#[derive(Clone)]
+struct NextScenario {
+    call: DynCall,
+    /// where the worker's stdout goes; None in thread mode
+    stdout_fd: Option<libc::c_int>,
+}
+
+static CALL_LOCK: Mutex<()> = Mutex::new(());
+static NEXT_SCENARIO: Mutex<Option<NextScenario>> = Mutex::new(None);
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// runs in the worker (or, in thread mode, in a thread of the runner)
+fn run_next_scenario() -> anyhow::Result<String> {
+    let Some(next) = lock(&NEXT_SCENARIO).clone() else {
+        anyhow::bail!("scenario worker started without a scenario");
+    };
+    if let Some(fd) = next.stdout_fd {
+        if let Err(e) = crate::isolation::redirect_stdout(fd) {
+            anyhow::bail!("dup2() failed: {}", e);
+        }
+    }
+    next.call.invoke()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(all(
+        target_os = "linux",
+        target_env = "gnu",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn test_run_dir() {
+        let dir = std::env::temp_dir().join(format!("scenario_file_test.{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = [
+            (
+                "1_pow.toml",
+                "library = \"libm.so.6\"\nfunction = \"pow\"\nsignature = \"double(double, double)\"\nargs = [2, 10.0]\nmode = \"thread\"\n[expect]\nreturns = 1024\n",
+            ),
+            (
+                "2_puts_expecting_a_crash.toml",
+                "function = \"puts\"\nsignature = \"int(const char*)\"\nargs = [\"hello from C\"]\n[expect]\nstdout = \"hello from C\"\nsignal = \"SIGABRT\"\n",
+            ),
+            (
+                "3_typo.toml",
+                "function = \"mid_exit\"\nsignature = \"int(int)\"\nargs = [1]\n[expect]\nexit_cod = 1\n",
+            ),
+            ("notes.txt", "not a scenario"),
+        ];
+        for (name, text) in files {
+            std::fs::write(dir.join(name), text).unwrap();
+        }
+        let results = run_dir(&dir, Some("libc.so.6")).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(results.len(), 3);
+        assert!(results[0].passed(), "{:?}", results[0]);
+        // puts() returns, hence only the stdout half of the expectation holds
+        assert_eq!(results[1].outcome, Some(CallOutcome::Returned("13".into())));
+        assert_eq!(results[1].failures.len(), 1, "{:?}", results[1]);
+        assert!(results[1].failures[0].starts_with("expected killed by signal 6"));
+        assert_eq!(results[2].outcome, None);
+        assert!(
+            results[2].failures[0].contains("exit_cod"),
+            "{:?}",
+            results[2]
+        );
+    }
+}
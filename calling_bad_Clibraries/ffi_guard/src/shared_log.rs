@@ -0,0 +1,360 @@
+// src/shared_log.rs
+//
+// The README's nightmare: "Log files that was about to log something and locked the
+// file", and then the C library calls exit().  A lock FILE (or a lock in shared memory)
+// stays locked forever once its holder is gone.  An flock() does not: the kernel drops it
+// as soon as the last fd of its holder is closed, and dying closes every fd.  So every
+// record goes out as:
+//
+//      flock(LOCK_EX); write(whole line); flock(LOCK_UN)
+//
+// on an O_APPEND file, which the host and all of its isolated workers (forked, re-exec'ed
+// or forked off the fork server) can share:
+//
+//      1792386566.533 worker 4250 call 7: about to call mid_access_violation()
+//      1792386566.537 host 4242 call 7: ReExec call -> killed by signal 11
+//
+// - one write() per record, under the lock, hence records never interleave; a record is
+//   either all there or not at all (newlines in the message get escaped, so one record
+//   is one line)
+// - if a writer somehow died half-way through its line anyway, the next writer finds the
+//   log not ending in '\n' and terminates the fragment before appending its own record
+// - the call id is handed out by the host for each isolated call and handed down to the
+//   worker (inherited over fork(), over the environment for re-exec, over the request for
+//   the fork server); a thread keeps the id of the last call it made, so the host's own
+//   records about a call get tagged with it too
+//
+// NOTE: flock() locks belong to the open file DESCRIPTION, which fork() shares between
+// parent and child, so the two would not exclude each other; a forked child re-opens
+// the log (when it becomes a worker, else on its first record) to get a description of
+// its own.
+
+use std::cell::Cell;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const LOG_ENV: &str = "FFI_SHARED_LOG";
+const CALL_ID_ENV: &str = "FFI_WORKER_CALL_ID";
+
+pub struct SharedLog {
+    path: PathBuf,
+    // (pid that opened it, the file); see the NOTE at the top for why the pid
+    file: Mutex<Option<(u32, File)>>,
+}
+
+impl SharedLog {
+    /// Opens (creating it if need be) the log at `path`
+    pub fn open(path: &Path) -> io::Result<SharedLog> {
+        let file = open_for_append(path)?;
+        Ok(SharedLog {
+            path: path.to_path_buf(),
+            file: Mutex::new(Some((std::process::id(), file))),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends one record, tagged with who we are and the current call id
+    pub fn write_record(&self, message: impl fmt::Display) -> io::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let call = current_call().map_or("-".to_string(), |id| id.to_string());
+        let message = message
+            .to_string()
+            .replace('\r', "\\r")
+            .replace('\n', "\\n");
+        let line = format!(
+            "{}.{:03} {} {} call {}: {}\n",
+            now.as_secs(),
+            now.subsec_millis(),
+            if IS_WORKER.load(Ordering::Relaxed) {
+                "worker"
+            } else {
+                "host"
+            },
+            std::process::id(),
+            call,
+            message
+        );
+        self.append(line.as_bytes())
+    }
+
+    fn append(&self, line: &[u8]) -> io::Result<()> {
+        // the Mutex keeps OUR threads apart (they all share one description, so the flock
+        // would not), the flock keeps the processes apart
+        let mut slot = self
+            .file
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut file = own_file(&mut slot, &self.path)?;
+        let _lock = FileLock::exclusive(file)?;
+        let mut record = Vec::with_capacity(line.len() + 1);
+        if !ends_with_newline(file)? {
+            record.push(b'\n');
+        }
+        record.extend_from_slice(line);
+        file.write_all(&record)
+    }
+}
+
+// The file, opened by THIS process
+fn own_file<'a>(slot: &'a mut Option<(u32, File)>, path: &Path) -> io::Result<&'a File> {
+    let pid = std::process::id();
+    if slot.as_ref().map(|(owner, _)| *owner) != Some(pid) {
+        // we are a fork of whoever opened it
+        *slot = Some((pid, open_for_append(path)?));
+    }
+    Ok(&slot.as_ref().expect("opened above").1)
+}
+
+fn open_for_append(path: &Path) -> io::Result<File> {
+    // no symlink followed, 0600 if we create it (see journal.rs)
+    crate::journal::private_file_options()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)
+}
+
+// true for an empty log as well; lseek() + pread() only, both of which a seccomp'ed
+// worker is allowed (see `SandboxLimits::minimal_syscalls()`)
+#[cfg(unix)]
+fn ends_with_newline(mut file: &File) -> io::Result<bool> {
+    use std::io::{Seek, SeekFrom};
+    use std::os::unix::fs::FileExt;
+
+    let len = file.seek(SeekFrom::End(0))?;
+    if len == 0 {
+        return Ok(true);
+    }
+    let mut last = [0u8; 1];
+    file.read_exact_at(&mut last, len - 1)?;
+    Ok(last[0] == b'\n')
+}
+
+#[cfg(not(unix))]
+fn ends_with_newline(_file: &File) -> io::Result<bool> {
+    Ok(true)
+}
+
+// flock() for as long as it lives; the kernel releases it anyway if we die holding it
+struct FileLock<'a>(&'a File);
+
+impl<'a> FileLock<'a> {
+    #[cfg(unix)]
+    fn exclusive(file: &'a File) -> io::Result<FileLock<'a>> {
+        use std::os::unix::io::AsRawFd;
+
+        while unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+        Ok(FileLock(file))
+    }
+
+    // NOTE: LockFileEx() would be the Windows equivalent; until then, only our own
+    // threads are kept apart (by the Mutex)
+    #[cfg(not(unix))]
+    fn exclusive(file: &'a File) -> io::Result<FileLock<'a>> {
+        Ok(FileLock(file))
+    }
+}
+
+impl Drop for FileLock<'_> {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::AsRawFd;
+            unsafe { libc::flock(self.0.as_raw_fd(), libc::LOCK_UN) };
+        }
+    }
+}
+
+// ############################## the process wide log
+
+static GLOBAL: OnceLock<SharedLog> = OnceLock::new();
+
+/// Opens THE shared log used by `log()`; call this before `zygote::start_global()`, else
+/// the workers of the fork server do not get to see it
+pub fn init_global(path: &Path) -> io::Result<()> {
+    let log = SharedLog::open(path)?;
+    GLOBAL
+        .set(log)
+        .map_err(|_| io::Error::other("shared log was already opened"))
+}
+
+pub fn global() -> Option<&'static SharedLog> {
+    GLOBAL.get()
+}
+
+/// `<journal::private_dir()>/<program>.log`
+pub fn default_path() -> io::Result<PathBuf> {
+    crate::private_file_of_program("log")
+}
+
+/// Appends a record to the shared log, if there is one; never fails the caller (a log
+/// that cannot be written is reported on stderr and otherwise ignored)
+pub fn log(message: impl fmt::Display) {
+    if let Some(log) = GLOBAL.get() {
+        if let Err(e) = log.write_record(message) {
+            eprintln!("shared_log::log(): {}: {}", log.path().display(), e);
+        }
+    }
+}
+
+// ############################## call ids
+
+static NEXT_CALL_ID: AtomicU64 = AtomicU64::new(1);
+static IS_WORKER: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // 0 = no call yet
+    static CURRENT_CALL: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Hands out the id of a new isolated call, and makes it the current one of this thread
+pub fn begin_call() -> u64 {
+    let id = NEXT_CALL_ID.fetch_add(1, Ordering::Relaxed);
+    set_current_call(id);
+    id
+}
+
+/// The id of the call this thread is making (or made last), if any
+pub fn current_call() -> Option<u64> {
+    let id = CURRENT_CALL.with(Cell::get);
+    (id != 0).then_some(id)
+}
+
+pub(crate) fn set_current_call(id: u64) {
+    CURRENT_CALL.with(|current| current.set(id));
+}
+
+/// From now on, our records are tagged "worker" (see `isolation::run_as_worker()`).  A
+/// forked worker also gets its own fd of the log right away, rather than on its first
+/// record: before the sandbox can forbid open(), and before the fd snapshot of
+/// leak_check.rs (which would report it as leaked by the call).
+pub(crate) fn enter_worker() {
+    IS_WORKER.store(true, Ordering::Relaxed);
+    if let Some(log) = GLOBAL.get() {
+        let mut slot = log
+            .file
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(e) = own_file(&mut slot, &log.path) {
+            eprintln!("shared_log: could not reopen {}: {}", log.path.display(), e);
+        }
+    }
+}
+
+/// What a re-exec'ed worker needs in its environment to log like a forked one
+pub(crate) fn worker_env() -> Vec<(&'static str, String)> {
+    let mut env = vec![(CALL_ID_ENV, current_call().unwrap_or(0).to_string())];
+    if let Some(log) = GLOBAL.get() {
+        env.push((LOG_ENV, log.path().display().to_string()));
+    }
+    env
+}
+
+/// The re-exec'ed worker's side of `worker_env()`
+pub(crate) fn from_worker_env() {
+    if let Some(id) = std::env::var(CALL_ID_ENV)
+        .ok()
+        .and_then(|id| id.parse().ok())
+    {
+        set_current_call(id);
+    }
+    if let Some(path) = std::env::var_os(LOG_ENV) {
+        if let Err(e) = init_global(Path::new(&path)) {
+            eprintln!("shared_log: could not open {:?}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn test_log(name: &str) -> SharedLog {
+        let path = std::env::temp_dir().join(format!("shared_log_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        SharedLog::open(&path).unwrap()
+    }
+
+    #[test]
+    fn test_dead_holder_does_not_block_the_others() {
+        use std::os::unix::io::AsRawFd;
+
+        let log = test_log("dead_holder");
+        log.write_record("before").unwrap();
+        match unsafe { libc::fork() } {
+            0 => {
+                // takes the lock, gets half a record out, and dies holding it
+                let file = open_for_append(log.path()).unwrap();
+                unsafe {
+                    libc::flock(file.as_raw_fd(), libc::LOCK_EX);
+                    libc::write(file.as_raw_fd(), b"torn".as_ptr().cast(), 4);
+                    libc::_exit(1);
+                }
+            }
+            pid => {
+                let mut status = 0;
+                unsafe { libc::waitpid(pid, &mut status, 0) };
+            }
+        }
+        set_current_call(7);
+        log.write_record("after\nthe crash").unwrap();
+
+        let content = std::fs::read_to_string(log.path()).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 3, "{:?}", content);
+        assert!(lines[0].ends_with(" call -: before"), "{}", lines[0]);
+        assert_eq!(lines[1], "torn");
+        let tag = format!(" host {} call 7: after\\nthe crash", std::process::id());
+        assert!(lines[2].ends_with(&tag), "{}", lines[2]);
+        let _ = std::fs::remove_file(log.path());
+    }
+
+    #[test]
+    fn test_concurrent_writers_do_not_interleave() {
+        let log = test_log("concurrent");
+        let children: Vec<libc::pid_t> = (0..4)
+            .map(|_| match unsafe { libc::fork() } {
+                0 => {
+                    enter_worker();
+                    for i in 0..200 {
+                        let _ = log.write_record(format!("{}{}", "x".repeat(4000), i));
+                    }
+                    unsafe { libc::_exit(0) };
+                }
+                pid => pid,
+            })
+            .collect();
+        for i in 0..200 {
+            log.write_record(format!("{}{}", "y".repeat(4000), i))
+                .unwrap();
+        }
+        for pid in children {
+            let mut status = 0;
+            unsafe { libc::waitpid(pid, &mut status, 0) };
+        }
+
+        let content = std::fs::read_to_string(log.path()).unwrap();
+        assert_eq!(content.lines().count(), 5 * 200);
+        for line in content.lines() {
+            let (_, message) = line.split_once(": ").unwrap();
+            let body = message.trim_end_matches(|c: char| c.is_ascii_digit());
+            assert!(body == "x".repeat(4000) || body == "y".repeat(4000));
+        }
+        let _ = std::fs::remove_file(log.path());
+    }
+}
@@ -0,0 +1,395 @@
+// src/shared_mem.rs
+//
+// The report pipe is fine for "returned: 42", but the calls we really care about take an
+// image of a few megabytes and hand back another one, and pushing those through a pipe
+// (and back) costs more than the call itself.  So for buffers, the host mmap()s ONE
+// anonymous, shared mapping before the fork, the worker inherits it (same address, since
+// it is a fork of us), and both the input and the output are read/written in place:
+//
+//      page 0:             header (written by the worker once the output is complete)
+//      page 1 ..:          input, copied in by the host; read-only in the worker
+//      after the input:    output, written by the C function in the worker
+//
+// The worker is the one process we do NOT trust, and it writes into memory the host reads,
+// hence the rules:
+//  - the input pages are mprotect()ed read-only in the worker (mprotect() in the child
+//    does not change the host's view), so a library that writes to its `const` input gets
+//    a SIGSEGV rather than quietly changing the host's copy
+//  - on Linux, the worker madvise(MADV_DONTFORK)s the mapping, so whatever the library
+//    forks off does not inherit it (and cannot keep scribbling on it after the worker is
+//    reaped)
+//  - the worker commits the output LAST (length, checksum, then the state word), and the
+//    host only hands the output out if the call returned AND the commit checks out; a
+//    worker that crashed half-way leaves a half-written buffer, which nobody gets to see
+//  - a mapping is used for one call only, and is munmap()ed as soon as the call is over,
+//    whatever the outcome; if the output is handed out, it is munmap()ed when the
+//    `SharedOutput` is dropped
+//
+// The mapping is inherited, hence `IsolationMode::Fork` only: a re-exec'ed worker gets a
+// fresh address space, and the fork server was forked before the mapping existed.
+
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::isolation::{call_isolated_report, CallReport, IsolationMode};
+use crate::outcome::CallOutcome;
+use crate::sandbox::SandboxLimits;
+
+/// What runs in the worker: the input, and room for the output; returns how many bytes
+/// of the output it wrote
+pub type BufferFn = fn(&[u8], &mut [u8]) -> anyhow::Result<usize>;
+
+const MAGIC: u64 = 0x5348_4d5f_4255_4631; // "SHM_BUF1"
+const STATE_EMPTY: u64 = 0;
+const STATE_COMMITTED: u64 = 1;
+
+// lives at the start of the mapping; the host writes everything but `output_len`,
+// `checksum` and `state`, which are the worker's
+#[repr(C)]
+struct Header {
+    magic: u64,
+    input_len: u64,
+    output_capacity: u64,
+    output_len: u64,
+    checksum: u64,
+    state: AtomicU64,
+}
+
+// so that a test (or an operator) can tell whether every mapping got released
+static LIVE_MAPPINGS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of shared mappings currently mapped in this process
+pub fn live_mappings() -> usize {
+    LIVE_MAPPINGS.load(Ordering::SeqCst)
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+fn round_up(len: usize, page: usize) -> usize {
+    len.div_ceil(page) * page
+}
+
+/// The input and output buffers of ONE call, in an anonymous shared mapping
+pub struct SharedBuffers {
+    base: *mut u8,
+    mapped_len: usize,
+    input_len: usize,
+    output_offset: usize,
+    output_capacity: usize,
+}
+
+// the raw pointer is ours alone (until the fork, after which only the worker writes)
+unsafe impl Send for SharedBuffers {}
+
+impl SharedBuffers {
+    /// Maps room for `input_len` bytes of input and `output_capacity` bytes of output;
+    /// the input is zeroed, fill it in via `input_mut()`
+    pub fn new(input_len: usize, output_capacity: usize) -> std::io::Result<SharedBuffers> {
+        let page = page_size();
+        let output_offset = page + round_up(input_len, page);
+        let mapped_len = output_offset + round_up(output_capacity, page).max(page);
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mapped_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        LIVE_MAPPINGS.fetch_add(1, Ordering::SeqCst);
+        let buffers = SharedBuffers {
+            base: base as *mut u8,
+            mapped_len,
+            input_len,
+            output_offset,
+            output_capacity,
+        };
+        let header = Header {
+            magic: MAGIC,
+            input_len: input_len as u64,
+            output_capacity: output_capacity as u64,
+            output_len: 0,
+            checksum: 0,
+            state: AtomicU64::new(STATE_EMPTY),
+        };
+        unsafe { std::ptr::write(buffers.header_ptr(), header) };
+        Ok(buffers)
+    }
+
+    /// Same as `new()`, with a copy of `input` already in place
+    pub fn with_input(input: &[u8], output_capacity: usize) -> std::io::Result<SharedBuffers> {
+        let mut buffers = SharedBuffers::new(input.len(), output_capacity)?;
+        buffers.input_mut().copy_from_slice(input);
+        Ok(buffers)
+    }
+
+    /// The input, for the host to fill in before the call (no extra copy of the image)
+    pub fn input_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.input_ptr(), self.input_len) }
+    }
+
+    pub fn input_len(&self) -> usize {
+        self.input_len
+    }
+
+    pub fn output_capacity(&self) -> usize {
+        self.output_capacity
+    }
+
+    /// Calls `function` in a forked worker (under `limits`) with these buffers.  The
+    /// buffers are consumed either way: they come back as `SharedCall::output` only if
+    /// the call returned and its output checks out, else they are unmapped right here
+    pub fn call(self, name: &str, limits: &SandboxLimits, function: BufferFn) -> SharedCall {
+        // one call at a time, the worker finds its buffers in NEXT_CALL (same as the
+        // fuzzer's input, see fuzz.rs)
+        let _serialized = lock(&CALL_LOCK);
+        *lock(&NEXT_CALL) = Some(NextCall {
+            function,
+            base: self.base as usize,
+            input_len: self.input_len,
+            output_offset: self.output_offset,
+            output_capacity: self.output_capacity,
+            mapped_len: self.mapped_len,
+        });
+        crate::metrics::name_function(run_next_call, name);
+        let mut report = call_isolated_report(IsolationMode::Fork, limits, run_next_call);
+        *lock(&NEXT_CALL) = None;
+
+        // the worker is reaped by now, nobody else writes to the mapping any more
+        let output = match &report.outcome {
+            CallOutcome::Returned(_) => match self.committed_output_len() {
+                Ok(len) => Some(SharedOutput { buffers: self, len }),
+                Err(e) => {
+                    report.outcome = CallOutcome::Failed(format!("shared memory: {}", e));
+                    None
+                }
+            },
+            _ => None,
+        };
+        SharedCall { report, output }
+    }
+
+    /// Checks what the worker left in the header, returns the length of the output
+    fn committed_output_len(&self) -> Result<usize, String> {
+        let header = unsafe { &*self.header_ptr() };
+        if header.state.load(Ordering::Acquire) != STATE_COMMITTED {
+            return Err("the worker returned without committing its output".into());
+        }
+        let (magic, input_len, output_capacity, output_len, checksum) = unsafe {
+            (
+                std::ptr::read_volatile(&header.magic),
+                std::ptr::read_volatile(&header.input_len),
+                std::ptr::read_volatile(&header.output_capacity),
+                std::ptr::read_volatile(&header.output_len),
+                std::ptr::read_volatile(&header.checksum),
+            )
+        };
+        if magic != MAGIC
+            || input_len != self.input_len as u64
+            || output_capacity != self.output_capacity as u64
+        {
+            return Err("the header got overwritten".into());
+        }
+        if output_len > output_capacity {
+            return Err(format!(
+                "output length {} is over the capacity of {}",
+                output_len, output_capacity
+            ));
+        }
+        let output_len = output_len as usize;
+        if checksum != fnv1a(&self.output()[..output_len]) {
+            return Err("the output changed after it was committed".into());
+        }
+        Ok(output_len)
+    }
+
+    fn header_ptr(&self) -> *mut Header {
+        self.base as *mut Header
+    }
+
+    fn input_ptr(&self) -> *mut u8 {
+        unsafe { self.base.add(page_size()) }
+    }
+
+    fn output(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(self.base.add(self.output_offset), self.output_capacity)
+        }
+    }
+}
+
+impl Drop for SharedBuffers {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base as *mut libc::c_void, self.mapped_len) };
+        LIVE_MAPPINGS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// What `SharedBuffers::call()` came back with
+pub struct SharedCall {
+    pub report: CallReport,
+    /// the worker's output, if it returned and its output checks out
+    pub output: Option<SharedOutput>,
+}
+
+/// The output of a call, still in the shared mapping (no copy); unmapped when dropped
+pub struct SharedOutput {
+    buffers: SharedBuffers,
+    len: usize,
+}
+
+impl Deref for SharedOutput {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffers.output()[..self.len]
+    }
+}
+
+// FNV-1a, 64 bit; not a security measure, just enough to notice that something (another
+// thread of the library, say) kept writing after the worker committed its output
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// where the mapping is, for the worker; plain numbers, since the worker sees the mapping
+// at the same address
+#[derive(Clone, Copy)]
+struct NextCall {
+    function: BufferFn,
+    base: usize,
+    input_len: usize,
+    output_offset: usize,
+    output_capacity: usize,
+    mapped_len: usize,
+}
+
+static CALL_LOCK: Mutex<()> = Mutex::new(());
+static NEXT_CALL: Mutex<Option<NextCall>> = Mutex::new(None);
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// runs in the worker
+fn run_next_call() -> anyhow::Result<String> {
+    let Some(call) = *lock(&NEXT_CALL) else {
+        anyhow::bail!("shared memory worker started without buffers");
+    };
+    let base = call.base as *mut u8;
+    let page = page_size();
+    unsafe {
+        #[cfg(target_os = "linux")]
+        if libc::madvise(
+            base as *mut libc::c_void,
+            call.mapped_len,
+            libc::MADV_DONTFORK,
+        ) != 0
+        {
+            anyhow::bail!("madvise() failed: {}", std::io::Error::last_os_error());
+        }
+        if libc::mprotect(
+            base.add(page) as *mut libc::c_void,
+            call.output_offset - page,
+            libc::PROT_READ,
+        ) != 0
+        {
+            anyhow::bail!("mprotect() failed: {}", std::io::Error::last_os_error());
+        }
+    }
+    let input = unsafe { std::slice::from_raw_parts(base.add(page), call.input_len) };
+    let output = unsafe {
+        std::slice::from_raw_parts_mut(base.add(call.output_offset), call.output_capacity)
+    };
+    let output_len = (call.function)(input, output)?;
+    if output_len > call.output_capacity {
+        anyhow::bail!(
+            "wrote {} bytes into a {} byte buffer",
+            output_len,
+            call.output_capacity
+        );
+    }
+    let header = unsafe { &mut *(base as *mut Header) };
+    header.output_len = output_len as u64;
+    header.checksum = fnv1a(&output[..output_len]);
+    header.state.store(STATE_COMMITTED, Ordering::Release);
+    Ok(format!("{} bytes", output_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invert(input: &[u8], output: &mut [u8]) -> anyhow::Result<usize> {
+        for (out, pixel) in output.iter_mut().zip(input) {
+            *out = 255 - pixel;
+        }
+        Ok(input.len())
+    }
+
+    fn crash_half_way(input: &[u8], output: &mut [u8]) -> anyhow::Result<usize> {
+        output[..input.len() / 2].fill(0xee);
+        unsafe { std::ptr::write_volatile(std::ptr::null_mut::<u32>(), 42) };
+        Ok(input.len())
+    }
+
+    fn write_to_input(input: &[u8], _output: &mut [u8]) -> anyhow::Result<usize> {
+        // what a C library does with its `const unsigned char *`
+        unsafe { std::ptr::write_volatile(input.as_ptr() as *mut u8, 0) };
+        Ok(0)
+    }
+
+    fn overflow(_input: &[u8], output: &mut [u8]) -> anyhow::Result<usize> {
+        Ok(output.len() + 1)
+    }
+
+    #[test]
+    fn test_buffers_on_every_outcome() {
+        let limits = SandboxLimits::default();
+        let image: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let call = |function: BufferFn| {
+            let buffers = SharedBuffers::with_input(&image, image.len()).unwrap();
+            buffers.call("test", &limits, function)
+        };
+
+        let returned = call(invert);
+        assert_eq!(
+            returned.report.outcome,
+            CallOutcome::Returned(format!("{} bytes", image.len()))
+        );
+        let output = returned.output.expect("output of a call that returned");
+        assert!(output
+            .iter()
+            .zip(&image)
+            .all(|(out, pixel)| *out == 255 - pixel));
+        assert_eq!(live_mappings(), 1);
+        drop(output);
+        assert_eq!(live_mappings(), 0);
+
+        let crashed = call(crash_half_way);
+        assert_eq!(crashed.report.outcome, CallOutcome::Signaled(libc::SIGSEGV));
+        assert!(crashed.output.is_none());
+        let scribbled = call(write_to_input);
+        assert_eq!(
+            scribbled.report.outcome,
+            CallOutcome::Signaled(libc::SIGSEGV)
+        );
+        assert!(scribbled.output.is_none());
+        let overflowed = call(overflow);
+        assert!(matches!(overflowed.report.outcome, CallOutcome::Failed(_)));
+        assert!(overflowed.output.is_none());
+        assert_eq!(live_mappings(), 0);
+    }
+}
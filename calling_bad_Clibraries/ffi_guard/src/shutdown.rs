@@ -0,0 +1,338 @@
+// src/shutdown.rs
+//
+// Isolation turns the library's `exit(n)` into `CallOutcome::Exited(n)`, and the host
+// lives on.  But sometimes the library really does mean "shut down" (i.e. it found its
+// license expired, or its data files corrupt), and the host should go down too.  It
+// should just go down ORDERLY rather than the way `exit()` would have taken it:
+//
+//  1. stop accepting work: from now on, `call()` refuses with `CallOutcome::ShuttingDown`
+//  2. drain: wait (up to `drain_timeout`) for the calls that are still in flight
+//  3. run the registered cleanups, last registered first (like atexit())
+//  4. exit with the library's status, mapped through `StatusMap`
+//
+// The mapping is there because what the library passes to exit() is not necessarily a
+// status we want to hand to our supervisor: `mid_exit(-666)` comes back as 102 (the
+// kernel only keeps the low 8 bits), which means nothing to anybody.
+
+use std::fmt;
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::isolation::{call_isolated_with, panic_message, IsolationMode};
+use crate::outcome::CallOutcome;
+use crate::sandbox::SandboxLimits;
+
+/// Library exit status -> host exit status
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatusMap {
+    /// (library status, host status); library statuses are kept as the kernel reports
+    /// them, i.e. truncated to 8 bits (-666 -> 102)
+    pub exact: Vec<(i32, i32)>,
+    /// for any other library status; `None` = pass it through as is
+    pub otherwise: Option<i32>,
+}
+
+impl StatusMap {
+    pub fn map(&self, library_status: i32) -> i32 {
+        let library_status = library_status & 0xff;
+        self.exact
+            .iter()
+            .find(|(from, _)| *from == library_status)
+            .map(|(_, to)| *to)
+            .or(self.otherwise)
+            .unwrap_or(library_status)
+    }
+
+    /// From "FROM=TO,...,*=TO", i.e. "-666=75,0=0,*=1"; FROM is what the library passes
+    /// to exit() (or what comes back of it, 102 for -666, same thing)
+    pub fn parse(spec: &str) -> Result<StatusMap, String> {
+        let mut map = StatusMap::default();
+        for rule in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+        {
+            let (from, to) = rule
+                .split_once('=')
+                .ok_or_else(|| format!("{:?}: expected FROM=TO", rule))?;
+            let to: i32 = to
+                .trim()
+                .parse()
+                .map_err(|e| format!("{:?}: bad status: {}", rule, e))?;
+            match from.trim() {
+                "*" => map.otherwise = Some(to),
+                from => {
+                    let from: i32 = from
+                        .parse()
+                        .map_err(|e| format!("{:?}: bad status: {}", rule, e))?;
+                    map.exact.push((from & 0xff, to));
+                }
+            }
+        }
+        Ok(map)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ShutdownPolicy {
+    pub status_map: StatusMap,
+    /// how long to wait for the calls still in flight; whatever is not done by then is
+    /// abandoned (its worker process, if any, is left to finish on its own)
+    pub drain_timeout: Duration,
+    /// `call()` runs the shutdown and exits the process itself; if false, it returns the
+    /// `Exited` outcome and it is up to the caller to `shutdown()` and exit
+    pub exit_when_done: bool,
+}
+
+impl Default for ShutdownPolicy {
+    fn default() -> Self {
+        ShutdownPolicy {
+            status_map: StatusMap::default(),
+            drain_timeout: Duration::from_secs(10),
+            exit_when_done: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// what the library exit()'ed with
+    pub library_status: i32,
+    /// what we exit with (`StatusMap::map()` of the above)
+    pub exit_status: i32,
+    /// calls still in flight when the drain timed out
+    pub abandoned: usize,
+    /// (name, Err(panic message) if it panicked), in the order they ran
+    pub cleanups: Vec<(String, Result<(), String>)>,
+}
+
+type Cleanup = (String, Box<dyn FnOnce() + Send>);
+
+#[derive(Default)]
+struct State {
+    in_flight: usize,
+    // the library status that started the shutdown; no more calls once set
+    requested: Option<i32>,
+    cleanups: Vec<Cleanup>,
+}
+
+pub struct ShutdownCoordinator {
+    policy: ShutdownPolicy,
+    state: Mutex<State>,
+    call_done: Condvar,
+}
+
+impl ShutdownCoordinator {
+    pub fn new(policy: ShutdownPolicy) -> Self {
+        ShutdownCoordinator {
+            policy,
+            state: Mutex::new(State::default()),
+            call_done: Condvar::new(),
+        }
+    }
+
+    /// `cleanup` runs during the shutdown, after the drain
+    pub fn register_cleanup(&self, name: &str, cleanup: impl FnOnce() + Send + 'static) {
+        self.lock()
+            .cleanups
+            .push((name.to_string(), Box::new(cleanup)));
+    }
+
+    /// The library status the shutdown was requested with, if it was
+    pub fn shutdown_requested(&self) -> Option<i32> {
+        self.lock().requested
+    }
+
+    /// Stops accepting calls; true if this was the first request (later ones are ignored,
+    /// the first status wins)
+    pub fn request_shutdown(&self, library_status: i32) -> bool {
+        let mut state = self.lock();
+        if state.requested.is_some() {
+            return false;
+        }
+        state.requested = Some(library_status);
+        true
+    }
+
+    /// Runs `my_function` isolated unless we are shutting down; if it comes back as
+    /// `Exited(n)` and `exit_when_done` is set, this does NOT return: it shuts down and
+    /// exits the process
+    pub fn call(
+        &self,
+        mode: IsolationMode,
+        my_function: fn() -> anyhow::Result<String>,
+    ) -> CallOutcome {
+        self.call_with(mode, &SandboxLimits::default(), my_function)
+    }
+
+    pub fn call_with(
+        &self,
+        mode: IsolationMode,
+        limits: &SandboxLimits,
+        my_function: fn() -> anyhow::Result<String>,
+    ) -> CallOutcome {
+        {
+            let mut state = self.lock();
+            if state.requested.is_some() {
+                let metrics = crate::metrics::global();
+                let function = metrics.function_name(my_function);
+                metrics.record(&function, &CallOutcome::ShuttingDown, None);
+                return CallOutcome::ShuttingDown;
+            }
+            state.in_flight += 1;
+        }
+        let outcome = call_isolated_with(mode, limits, my_function);
+        self.lock().in_flight -= 1;
+        self.call_done.notify_all();
+
+        if let CallOutcome::Exited(status) = outcome {
+            if self.request_shutdown(status) && self.policy.exit_when_done {
+                self.shutdown_and_exit();
+            }
+        }
+        outcome
+    }
+
+    /// Steps 2 and 3 (see the top of this file); the shutdown must have been requested
+    /// already (else the library status is taken to be 0)
+    pub fn shutdown(&self) -> ShutdownReport {
+        let deadline = Instant::now() + self.policy.drain_timeout;
+        let mut state = self.lock();
+        state.requested.get_or_insert(0);
+        while state.in_flight > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = self
+                .call_done
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+        let abandoned = state.in_flight;
+        let library_status = state.requested.unwrap_or(0);
+        let cleanups = std::mem::take(&mut state.cleanups);
+        drop(state); // the cleanups may well want to look at us
+
+        let cleanups = cleanups
+            .into_iter()
+            .rev()
+            .map(|(name, cleanup)| {
+                let result = panic::catch_unwind(AssertUnwindSafe(cleanup))
+                    .map_err(|panic_value| panic_message(&panic_value));
+                (name, result)
+            })
+            .collect();
+        ShutdownReport {
+            library_status,
+            exit_status: self.policy.status_map.map(library_status),
+            abandoned,
+            cleanups,
+        }
+    }
+
+    /// `shutdown()`, then exit with the mapped status
+    pub fn shutdown_and_exit(&self) -> ! {
+        let report = self.shutdown();
+        eprintln!("{}", report);
+        crate::shared_log::log(format!("shutdown: {}", report));
+        let _ = std::io::stdout().flush();
+        std::process::exit(report.exit_status)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        // a panicking cleanup never runs under the lock, but a panicking caller might
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl fmt::Display for ShutdownReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "library exit({}) -> shutting down with status {}, {} calls abandoned, {} cleanups",
+            self.library_status,
+            self.exit_status,
+            self.abandoned,
+            self.cleanups.len()
+        )?;
+        for (name, result) in &self.cleanups {
+            match result {
+                Ok(()) => write!(f, "\n    {}: done", name)?,
+                Err(msg) => write!(f, "\n    {}: panicked: {}", name, msg)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_status_map() {
+        let map = StatusMap::parse("-666=75, 0=0, *=1").unwrap();
+        assert_eq!(map.map(102), 75);
+        assert_eq!(map.map(-666), 75);
+        assert_eq!(map.map(0), 0);
+        assert_eq!(map.map(3), 1);
+        assert_eq!(StatusMap::default().map(102), 102);
+        assert!(StatusMap::parse("102").is_err());
+        assert!(StatusMap::parse("x=1").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exit_drains_then_cleans_up() {
+        let coordinator = Arc::new(ShutdownCoordinator::new(ShutdownPolicy {
+            status_map: StatusMap::parse("3=75").unwrap(),
+            exit_when_done: false,
+            ..Default::default()
+        }));
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        for name in ["first", "second"] {
+            let ran = ran.clone();
+            coordinator.register_cleanup(name, move || ran.lock().unwrap().push(name));
+        }
+        coordinator.register_cleanup("broken", || panic!("oops"));
+
+        let slow = {
+            let coordinator = coordinator.clone();
+            std::thread::spawn(move || {
+                coordinator.call(IsolationMode::Thread, || {
+                    std::thread::sleep(Duration::from_millis(300));
+                    Ok("slow".into())
+                })
+            })
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        let fn_exit = || -> anyhow::Result<String> { unsafe { libc::_exit(3) } };
+        assert_eq!(
+            coordinator.call(IsolationMode::Fork, fn_exit),
+            CallOutcome::Exited(3)
+        );
+        assert_eq!(coordinator.shutdown_requested(), Some(3));
+        assert_eq!(
+            coordinator.call(IsolationMode::Thread, || Ok("late".into())),
+            CallOutcome::ShuttingDown
+        );
+
+        let report = coordinator.shutdown();
+        // the slow call was in flight, and got to finish
+        assert_eq!(slow.join().unwrap(), CallOutcome::Returned("slow".into()));
+        assert_eq!(report.abandoned, 0);
+        assert_eq!(report.exit_status, 75);
+        assert_eq!(*ran.lock().unwrap(), vec!["second", "first"]);
+        assert_eq!(
+            report.cleanups[0],
+            ("broken".to_string(), Err("oops".to_string()))
+        );
+    }
+}
@@ -0,0 +1,479 @@
+// src/thread_guard.rs
+//
+// A spawned thread cannot survive `exit()` (there is nothing to be done about that short of
+// a process), but a SIGSEGV or SIGFPE is raised on the very thread that faulted.  Here the
+// guarded thread gets an alternate signal stack of its own (in case the stack is what got
+// smashed), and a process-wide handler for SIGSEGV/SIGFPE that, when the fault is one of
+// a guarded thread:
+//
+//      1. writes the same crash record as a forked worker would (see crash_report.rs)
+//      2. ends ONLY the faulting thread, with the raw exit syscall (the one pthread_exit()
+//         ends with, minus the unwinding, which is not safe from a signal handler)
+//
+// The kernel then wakes up whoever is joining the thread, as if it had returned, and the
+// host gets a `ThreadEnd::Faulted` with the symbolized crash rather than going down.
+//
+// This is NOT a process.  What the faulting thread was in the middle of stays that way:
+// locks it held stay locked, memory it allocated is leaked, its destructors never run.
+// Hence it is only worth it for a C library that faults on bad input, but keeps its hands
+// off of shared state when it does.  The faults it cannot contain are reported on stderr
+// (along with why; see `Uncontained`) and then go on to kill the process as usual:
+//  - faults of any thread that is not a guarded one (the host's own, or one the C library
+//    spawned itself)
+//  - SIGSEGV/SIGFPE sent with kill()/raise(), which are not faults of the thread at all
+//  - faults inside libc or the dynamic loader, which may be holding a lock of their own
+//    (i.e. malloc()'s, or the dlopen() lock) that the rest of the host will need
+//  - anything other than SIGSEGV/SIGFPE: `exit()`, `abort()`, SIGBUS, SIGILL, ...
+//
+// NOTE: the handler is Linux/glibc only, same as the crash reporter; elsewhere a guarded
+// thread is just a thread.
+
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+
+use crate::crash_report::CrashReport;
+
+/// How a guarded thread ended
+#[derive(Debug)]
+pub enum ThreadEnd<T> {
+    Returned(T),
+    Panicked(Box<dyn Any + Send>),
+    /// SIGSEGV/SIGFPE that ended the thread (but nothing else); `crash` is None only if
+    /// the crash record could not be parsed
+    Faulted {
+        signal: i32,
+        crash: Option<CrashReport>,
+    },
+}
+
+/// Why a SIGSEGV/SIGFPE could not be contained to the thread that got it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Uncontained {
+    /// not a guarded thread, i.e. the host's own thread or one the C library spawned
+    NotGuarded,
+    /// sent by kill()/raise()/sigqueue(), not a fault of the thread itself
+    NotAFault,
+    /// the fault is inside libc or the dynamic loader, which may be holding locks
+    InSystemLibrary,
+}
+
+impl Uncontained {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Uncontained::NotGuarded => "on a thread that is not a guarded one",
+            Uncontained::NotAFault => "sent by kill()/raise(), not a fault of the thread",
+            Uncontained::InSystemLibrary => "inside libc/ld.so, which may be holding locks",
+        }
+    }
+}
+
+struct Slot<T> {
+    fault: Fault,
+    result: Mutex<Option<std::thread::Result<T>>>,
+}
+
+/// A thread started by `spawn_guarded()`; `join()` it to find out how it ended
+pub struct GuardedThread<T> {
+    handle: Option<std::thread::JoinHandle<()>>,
+    slot: Arc<Slot<T>>,
+    // the alternate signal stack of the thread; it is ours, not the thread's, as a thread
+    // that faulted never gets to free it
+    alt_stack: Option<Box<[u8]>>,
+}
+
+/// `std::thread::spawn()` + `catch_unwind()`, plus SIGSEGV/SIGFPE of the thread ending
+/// only the thread (see the top of this file for when that is not the case)
+pub fn spawn_guarded<F, T>(f: F) -> GuardedThread<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    install();
+    let slot = Arc::new(Slot {
+        fault: Fault::default(),
+        result: Mutex::new(None),
+    });
+    let mut alt_stack = vec![0u8; ALT_STACK_SIZE].into_boxed_slice();
+    let alt_stack_ptr = alt_stack.as_mut_ptr() as usize;
+    let thread_slot = slot.clone();
+    let handle = std::thread::spawn(move || {
+        let guard = Guard::enter(&thread_slot.fault, alt_stack_ptr);
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        drop(guard);
+        *thread_slot.result.lock().unwrap() = Some(result);
+    });
+    GuardedThread {
+        handle: Some(handle),
+        slot,
+        alt_stack: Some(alt_stack),
+    }
+}
+
+impl<T> GuardedThread<T> {
+    /// Blocks until the thread has ended, one way or another
+    pub fn join(mut self) -> ThreadEnd<T> {
+        if let Some(handle) = self.handle.take() {
+            join_native(handle);
+        }
+        if let Some(signal) = self.slot.fault.signal() {
+            return ThreadEnd::Faulted {
+                signal,
+                crash: self.slot.fault.crash_report(),
+            };
+        }
+        match self.slot.result.lock().unwrap().take() {
+            Some(Ok(value)) => ThreadEnd::Returned(value),
+            Some(Err(panic_value)) => ThreadEnd::Panicked(panic_value),
+            // the thread ended without a word; should not happen
+            None => ThreadEnd::Faulted {
+                signal: 0,
+                crash: None,
+            },
+        }
+    }
+}
+
+impl<T> Drop for GuardedThread<T> {
+    fn drop(&mut self) {
+        if self.handle.is_some() {
+            // never joined, hence (for all we know) still running on its alternate stack
+            if let Some(alt_stack) = self.alt_stack.take() {
+                Box::leak(alt_stack);
+            }
+        }
+    }
+}
+
+const ALT_STACK_SIZE: usize = 64 * 1024;
+
+// ############################## thread side (signal handler)
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+use handler::{install, join_native, Fault, Guard};
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+mod handler {
+    use std::cell::{Cell, UnsafeCell};
+    use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+    use std::sync::OnceLock;
+
+    use super::{Uncontained, ALT_STACK_SIZE};
+    use crate::crash_report::handler::{crash_record, faulting_pc_and_access, prepare, Record};
+    use crate::crash_report::CrashReport;
+
+    const GUARDED_SIGNALS: [libc::c_int; 2] = [libc::SIGSEGV, libc::SIGFPE];
+
+    // whatever handled SIGSEGV/SIGFPE before us (Rust's own stack overflow handler, as a
+    // rule); faults we cannot contain go on to it
+    static PREVIOUS: OnceLock<[libc::sigaction; 2]> = OnceLock::new();
+    // [start, end) of libc and of ld.so, see `Uncontained::InSystemLibrary`
+    static SYSTEM_LIBRARIES: [AtomicU64; 4] = [
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+    ];
+
+    thread_local! {
+        // const and without a destructor, hence safe to look at from a signal handler
+        static GUARDED: Cell<*const Fault> = const { Cell::new(std::ptr::null()) };
+    }
+
+    /// The fault of one guarded thread, written by the signal handler (of that thread)
+    /// and read by whoever joined it
+    pub(super) struct Fault {
+        signal: AtomicI32,
+        record: UnsafeCell<Record>,
+    }
+
+    // the record is written by the faulting thread only, and read only once it is dead
+    unsafe impl Sync for Fault {}
+
+    impl Default for Fault {
+        fn default() -> Self {
+            Fault {
+                signal: AtomicI32::new(0),
+                // allocated up front: no malloc() in the signal handler
+                record: UnsafeCell::new(Record::new()),
+            }
+        }
+    }
+
+    impl Fault {
+        pub(super) fn signal(&self) -> Option<i32> {
+            match self.signal.load(Ordering::SeqCst) {
+                0 => None,
+                signal => Some(signal),
+            }
+        }
+
+        pub(super) fn crash_report(&self) -> Option<CrashReport> {
+            let record = unsafe { &*self.record.get() };
+            CrashReport::parse(&String::from_utf8_lossy(record.as_bytes()))
+        }
+    }
+
+    /// The guarded thread's alternate signal stack and registration, for as long as the
+    /// call lasts; dropped only if the thread did NOT fault
+    pub(super) struct Guard {
+        previous_stack: libc::stack_t,
+    }
+
+    impl Guard {
+        pub(super) fn enter(fault: &Fault, alt_stack: usize) -> Guard {
+            let mut previous_stack: libc::stack_t = unsafe { std::mem::zeroed() };
+            let alt_stack = libc::stack_t {
+                ss_sp: alt_stack as *mut libc::c_void,
+                ss_flags: 0,
+                ss_size: ALT_STACK_SIZE,
+            };
+            unsafe { libc::sigaltstack(&alt_stack, &mut previous_stack) };
+            GUARDED.with(|guarded| guarded.set(fault));
+            Guard { previous_stack }
+        }
+    }
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            GUARDED.with(|guarded| guarded.set(std::ptr::null()));
+            // std's own alternate stack, which std frees (and disables) on the way out
+            unsafe { libc::sigaltstack(&self.previous_stack, std::ptr::null_mut()) };
+        }
+    }
+
+    /// Installs the (process-wide) handler, once
+    pub(super) fn install() {
+        PREVIOUS.get_or_init(|| {
+            prepare();
+            find_system_libraries();
+            let mut previous: [libc::sigaction; 2] = unsafe { std::mem::zeroed() };
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                    on_fault;
+                action.sa_sigaction = handler as usize;
+                action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+                libc::sigemptyset(&mut action.sa_mask);
+                for (signal, previous) in GUARDED_SIGNALS.iter().zip(previous.iter_mut()) {
+                    libc::sigaction(*signal, &action, previous);
+                }
+            }
+            previous
+        });
+    }
+
+    /// The native join; `JoinHandle::join()` would want the result the thread never got
+    /// to hand over if it faulted
+    pub(super) fn join_native(handle: std::thread::JoinHandle<()>) {
+        use std::os::unix::thread::JoinHandleExt;
+
+        let thread = handle.into_pthread_t();
+        unsafe { libc::pthread_join(thread, std::ptr::null_mut()) };
+    }
+
+    #[allow(clippy::unnecessary_cast)] // Elf32 on 32-bit targets
+    fn find_system_libraries() {
+        unsafe extern "C" fn each_object(
+            info: *mut libc::dl_phdr_info,
+            _size: libc::size_t,
+            _data: *mut libc::c_void,
+        ) -> libc::c_int {
+            if (*info).dlpi_name.is_null() {
+                return 0;
+            }
+            let name = std::ffi::CStr::from_ptr((*info).dlpi_name).to_string_lossy();
+            let index = if name.contains("/libc.so") {
+                0
+            } else if name.contains("/ld-linux") {
+                2
+            } else {
+                return 0;
+            };
+            let (mut start, mut end) = (u64::MAX, 0);
+            for i in 0..(*info).dlpi_phnum as usize {
+                let header = &*(*info).dlpi_phdr.add(i);
+                if header.p_type == libc::PT_LOAD {
+                    let segment = (*info).dlpi_addr as u64 + header.p_vaddr as u64;
+                    start = start.min(segment);
+                    end = end.max(segment + header.p_memsz as u64);
+                }
+            }
+            SYSTEM_LIBRARIES[index].store(start, Ordering::SeqCst);
+            SYSTEM_LIBRARIES[index + 1].store(end, Ordering::SeqCst);
+            0
+        }
+        unsafe { libc::dl_iterate_phdr(Some(each_object), std::ptr::null_mut()) };
+    }
+
+    unsafe fn uncontained(
+        fault: *const Fault,
+        info: *mut libc::siginfo_t,
+        context: *mut libc::c_void,
+    ) -> Option<Uncontained> {
+        if fault.is_null() {
+            return Some(Uncontained::NotGuarded);
+        }
+        // si_code > 0 is the kernel's doing (a fault); <= 0 came from user space
+        if (*info).si_code <= 0 {
+            return Some(Uncontained::NotAFault);
+        }
+        let (pc, _) = faulting_pc_and_access(context);
+        let in_system_library = SYSTEM_LIBRARIES.chunks(2).any(|range| {
+            (range[0].load(Ordering::SeqCst)..range[1].load(Ordering::SeqCst)).contains(&pc)
+        });
+        in_system_library.then_some(Uncontained::InSystemLibrary)
+    }
+
+    extern "C" fn on_fault(
+        signal: libc::c_int,
+        info: *mut libc::siginfo_t,
+        context: *mut libc::c_void,
+    ) {
+        let fault = GUARDED.with(|guarded| guarded.get());
+        unsafe {
+            match uncontained(fault, info, context) {
+                None => {
+                    crash_record(signal, info, context, &mut *(*fault).record.get());
+                    (*fault).signal.store(signal, Ordering::SeqCst);
+                    // this thread only (exit_group() is the one that ends the process)
+                    libc::syscall(libc::SYS_exit, 0);
+                }
+                Some(reason) => {
+                    report_uncontained(signal, reason);
+                    pass_on(signal, info, context);
+                }
+            }
+        }
+    }
+
+    // "thread_guard: SIGSEGV on a thread that is not a guarded one; cannot contain it"
+    unsafe fn report_uncontained(signal: libc::c_int, reason: Uncontained) {
+        let name: &[u8] = if signal == libc::SIGSEGV {
+            b"SIGSEGV "
+        } else {
+            b"SIGFPE "
+        };
+        for part in [
+            b"thread_guard: ".as_slice(),
+            name,
+            reason.message().as_bytes(),
+            b"; cannot contain it\n".as_slice(),
+        ] {
+            libc::write(2, part.as_ptr() as *const libc::c_void, part.len());
+        }
+    }
+
+    // Hands the signal over to whoever had it before us, or to the default action
+    unsafe fn pass_on(signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+        let index = GUARDED_SIGNALS
+            .iter()
+            .position(|s| *s == signal)
+            .unwrap_or(0);
+        let previous = PREVIOUS.get().map(|previous| previous[index]);
+        match previous {
+            Some(previous)
+                if previous.sa_sigaction != libc::SIG_DFL
+                    && previous.sa_sigaction != libc::SIG_IGN =>
+            {
+                if previous.sa_flags & libc::SA_SIGINFO != 0 {
+                    let handler: extern "C" fn(
+                        libc::c_int,
+                        *mut libc::siginfo_t,
+                        *mut libc::c_void,
+                    ) = std::mem::transmute(previous.sa_sigaction);
+                    handler(signal, info, context);
+                } else {
+                    let handler: extern "C" fn(libc::c_int) =
+                        std::mem::transmute(previous.sa_sigaction);
+                    handler(signal);
+                }
+            }
+            _ => {
+                let mut default: libc::sigaction = std::mem::zeroed();
+                default.sa_sigaction = libc::SIG_DFL;
+                libc::sigaction(signal, &default, std::ptr::null_mut());
+                // a fault re-runs the faulting instruction when we return; kill() does not
+                if (*info).si_code <= 0 {
+                    libc::raise(signal);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+use fallback::{install, join_native, Fault, Guard};
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+mod fallback {
+    use crate::crash_report::CrashReport;
+
+    #[derive(Default)]
+    pub(super) struct Fault;
+
+    impl Fault {
+        pub(super) fn signal(&self) -> Option<i32> {
+            None
+        }
+
+        pub(super) fn crash_report(&self) -> Option<CrashReport> {
+            None
+        }
+    }
+
+    pub(super) struct Guard;
+
+    impl Guard {
+        pub(super) fn enter(_fault: &Fault, _alt_stack: usize) -> Guard {
+            Guard
+        }
+    }
+
+    pub(super) fn install() {}
+
+    pub(super) fn join_native(handle: std::thread::JoinHandle<()>) {
+        let _ = handle.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_returns_and_panics() {
+        match spawn_guarded(|| 6 * 7).join() {
+            ThreadEnd::Returned(value) => assert_eq!(value, 42),
+            other => panic!("unexpected {:?}", other),
+        }
+        match spawn_guarded(|| -> i32 { panic!("oops") }).join() {
+            ThreadEnd::Panicked(panic_value) => {
+                assert_eq!(crate::isolation::panic_message(&panic_value), "oops")
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    #[test]
+    fn test_fault_ends_only_the_thread() {
+        #[inline(never)]
+        fn write_to_null() -> i32 {
+            unsafe { std::ptr::write_volatile(std::ptr::null_mut::<u32>(), 42) };
+            42
+        }
+
+        for _ in 0..3 {
+            match spawn_guarded(write_to_null).join() {
+                ThreadEnd::Faulted { signal, crash } => {
+                    assert_eq!(signal, libc::SIGSEGV);
+                    assert_eq!(crash.unwrap().description(), "null write");
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        // and we are still here, with threads that work as they should
+        assert!(matches!(spawn_guarded(|| 1).join(), ThreadEnd::Returned(1)));
+    }
+}
@@ -0,0 +1,263 @@
+// src/zygote.rs
+//
+// Fork-server (a.k.a. "zygote", as Android and Chrome call it) for process isolation.
+// Forking the host for every C call means copying the page tables of a large process,
+// and forking a multithreaded process is asking for trouble (the child only gets the
+// forking thread, and any lock that was held by the other threads stays locked forever).
+// Instead, at startup, while the host is still small and single-threaded, we fork ONE
+// helper process which has the C library already loaded (it is statically linked, so
+// it is loaded as soon as we are; for a `.so`, this is where dlopen() would go).  From
+// then on, the host sends each call to the helper, which forks the worker off ITSELF.
+//
+// Since the helper is a fork of the host, it shares the same address space layout, hence
+// the host can just send the address of the `fn()` it wants called.
+//
+// Protocol (host <-> helper, over two pipes):
+//      request:  [usize: address of fn() -> anyhow::Result<String>][u64: call id]
+//      response: [i32: worker pid, 0 if the fork failed]
+//                ... [u8: worker is dead, but not reaped yet]
+//      request:  [u8: go ahead and reap it]
+//      response: [i32: waitpid() status][u32: report length][report bytes]
+// The pid (and the handshake before reaping it) is there for async_isolation.rs, which
+// SIGKILLs the worker if the future gets dropped: until the host has said so, the worker
+// stays a zombie, hence its pid cannot have been recycled by the time the host kills it.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::{Mutex, OnceLock};
+
+use crate::isolation::{
+    cloexec_pipe, conclude, encode_report, start_worker, CallReport, IsolationMode,
+};
+use crate::outcome::CallOutcome;
+use crate::sandbox::SandboxLimits;
+
+struct Channel {
+    requests: File,
+    responses: File,
+}
+
+pub struct ForkServer {
+    pid: libc::pid_t,
+    limits: SandboxLimits,
+    // one call at a time; the helper serves requests in order
+    channel: Mutex<Option<Channel>>,
+}
+
+static FORK_SERVER: OnceLock<ForkServer> = OnceLock::new();
+
+/// Starts THE fork server used by `IsolationMode::ForkServer`; call this at the very top
+/// of main(), before any threads get spawned (and before anything big gets allocated)
+pub fn start_global(limits: SandboxLimits) -> std::io::Result<()> {
+    let server = ForkServer::start(limits)?;
+    FORK_SERVER
+        .set(server)
+        .map_err(|_| std::io::Error::other("fork server was already started"))
+}
+
+pub fn global() -> Option<&'static ForkServer> {
+    FORK_SERVER.get()
+}
+
+impl ForkServer {
+    /// Forks the helper; every worker forked off it will get `limits` applied
+    pub fn start(limits: SandboxLimits) -> std::io::Result<ForkServer> {
+        use std::os::unix::io::FromRawFd;
+
+        // close-on-exec: the helper never exec()s, and a re-exec'ed worker must not keep
+        // the helper's request pipe open
+        let (request_read, request_write) = cloexec_pipe()?;
+        let (response_read, response_write) = cloexec_pipe()?;
+        let _ = std::io::stdout().flush();
+        let _ = std::io::stderr().flush();
+
+        let host = unsafe { libc::getpid() };
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if pid == 0 {
+            // while busy with a worker, we would not notice the request pipe closing; and
+            // our workers, in turn, die along with us (see heartbeat.rs)
+            crate::heartbeat::die_with_parent(host);
+            unsafe {
+                libc::close(request_write);
+                libc::close(response_read);
+                let requests = File::from_raw_fd(request_read);
+                let responses = File::from_raw_fd(response_write);
+                serve(requests, responses, &limits);
+            }
+        }
+
+        unsafe {
+            libc::close(request_read);
+            libc::close(response_write);
+        }
+        let channel = unsafe {
+            Channel {
+                requests: File::from_raw_fd(request_write),
+                responses: File::from_raw_fd(response_read),
+            }
+        };
+        Ok(ForkServer {
+            pid,
+            limits,
+            channel: Mutex::new(Some(channel)),
+        })
+    }
+
+    pub fn pid(&self) -> libc::pid_t {
+        self.pid
+    }
+
+    /// Has the helper fork a worker that calls `my_function`, and reports what happened
+    pub fn call(&self, my_function: fn() -> anyhow::Result<String>) -> CallOutcome {
+        self.call_report(my_function).outcome
+    }
+
+    /// Same as `call()`, plus whatever else the worker reported (i.e. its crash report;
+    /// the worker is a fork of a fork of the host, so the addresses in it are ours too)
+    pub fn call_report(&self, my_function: fn() -> anyhow::Result<String>) -> CallReport {
+        self.call_report_with(my_function, |_| (), || ())
+    }
+
+    /// Same as `call_report()`, but `started` gets the worker's pid as soon as it is
+    /// forked, and `before_reap` gets called once it is dead and before it gets reaped
+    /// (see `Worker::wait_then_reap()`)
+    pub(crate) fn call_report_with(
+        &self,
+        my_function: fn() -> anyhow::Result<String>,
+        started: impl FnOnce(libc::pid_t),
+        before_reap: impl FnOnce(),
+    ) -> CallReport {
+        let mut channel = self.channel.lock().unwrap();
+        let Some(channel) = channel.as_mut() else {
+            return CallOutcome::Failed("fork server has been shut down".into()).into();
+        };
+        match round_trip(channel, my_function, started, before_reap) {
+            Ok((status, report)) => conclude(&self.limits, status, &report),
+            Err(e) => CallOutcome::Failed(format!("fork server is gone: {}", e)).into(),
+        }
+    }
+}
+
+impl Drop for ForkServer {
+    fn drop(&mut self) {
+        // closing the request pipe is the helper's cue to leave
+        self.channel.get_mut().unwrap().take();
+        let mut status: libc::c_int = 0;
+        unsafe { libc::waitpid(self.pid, &mut status, 0) };
+    }
+}
+
+fn round_trip(
+    channel: &mut Channel,
+    my_function: fn() -> anyhow::Result<String>,
+    started: impl FnOnce(libc::pid_t),
+    before_reap: impl FnOnce(),
+) -> std::io::Result<(libc::c_int, String)> {
+    let address = my_function as usize;
+    let call_id = crate::shared_log::current_call().unwrap_or(0);
+    let mut request = Vec::with_capacity(16);
+    request.extend_from_slice(&address.to_ne_bytes());
+    request.extend_from_slice(&call_id.to_ne_bytes());
+    channel.requests.write_all(&request)?;
+
+    let mut pid = [0u8; 4];
+    channel.responses.read_exact(&mut pid)?;
+    let pid = libc::pid_t::from_ne_bytes(pid);
+    if pid > 0 {
+        started(pid);
+    }
+    let mut dead = [0u8; 1];
+    channel.responses.read_exact(&mut dead)?;
+    before_reap();
+    channel.requests.write_all(&[1])?;
+
+    let mut status = [0u8; 4];
+    channel.responses.read_exact(&mut status)?;
+    let mut len = [0u8; 4];
+    channel.responses.read_exact(&mut len)?;
+    let mut report = vec![0u8; u32::from_ne_bytes(len) as usize];
+    channel.responses.read_exact(&mut report)?;
+    Ok((
+        libc::c_int::from_ne_bytes(status),
+        String::from_utf8_lossy(&report).into_owned(),
+    ))
+}
+
+// The helper's main loop; never returns
+fn serve(mut requests: File, mut responses: File, limits: &SandboxLimits) -> ! {
+    loop {
+        let mut address = [0u8; std::mem::size_of::<usize>()];
+        let mut call_id = [0u8; 8];
+        if requests.read_exact(&mut address).is_err() || requests.read_exact(&mut call_id).is_err()
+        {
+            // host closed the pipe (or is gone), so are we
+            unsafe { libc::_exit(0) };
+        }
+        // the worker forked off us inherits it
+        crate::shared_log::set_current_call(u64::from_ne_bytes(call_id));
+        // SAFETY: we are a fork of the host, so the host's fn pointers are our fn pointers
+        let my_function: fn() -> anyhow::Result<String> =
+            unsafe { std::mem::transmute(usize::from_ne_bytes(address)) };
+
+        let worker = start_worker(IsolationMode::Fork, limits, my_function);
+        let pid = worker.as_ref().map_or(0, |worker| worker.pid());
+        if responses.write_all(&pid.to_ne_bytes()).is_err() {
+            unsafe { libc::_exit(0) };
+        }
+        // the host may kill() the worker until it says it is done with the pid
+        let mut before_reap = || {
+            let mut go_ahead = [0u8; 1];
+            if responses.write_all(&[1]).is_err() || requests.read_exact(&mut go_ahead).is_err() {
+                unsafe { libc::_exit(0) };
+            }
+        };
+        let (status, report) = match worker {
+            Ok(worker) => match worker.wait_then_reap(before_reap) {
+                Ok(result) => result,
+                Err(e) => (
+                    0,
+                    encode_report(&CallOutcome::Failed(format!("waitpid() failed: {}", e))),
+                ),
+            },
+            // pretend the worker reported the failure itself, so the host sees `Failed`
+            Err(outcome) => {
+                before_reap();
+                (0, encode_report(&outcome))
+            }
+        };
+        let mut response = Vec::with_capacity(8 + report.len());
+        response.extend_from_slice(&status.to_ne_bytes());
+        response.extend_from_slice(&(report.len() as u32).to_ne_bytes());
+        response.extend_from_slice(report.as_bytes());
+        if responses.write_all(&response).is_err() {
+            unsafe { libc::_exit(0) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fork_server_outcomes() {
+        let server = ForkServer::start(SandboxLimits::default()).unwrap();
+        let fn_ok = || Ok("from the zygote".to_string());
+        assert_eq!(
+            server.call(fn_ok),
+            CallOutcome::Returned("from the zygote".into())
+        );
+        let fn_exit = || -> anyhow::Result<String> { unsafe { libc::_exit(3) } };
+        assert_eq!(server.call(fn_exit), CallOutcome::Exited(3));
+        let fn_abort = || -> anyhow::Result<String> { std::process::abort() };
+        assert_eq!(server.call(fn_abort), CallOutcome::Signaled(libc::SIGABRT));
+        // the helper itself survives all of the above
+        assert_eq!(
+            server.call(fn_ok),
+            CallOutcome::Returned("from the zygote".into())
+        );
+    }
+}
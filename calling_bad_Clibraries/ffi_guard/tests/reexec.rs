@@ -0,0 +1,45 @@
+// tests/reexec.rs
+//
+// IsolationMode::ReExec, end to end: every call here spawns a fresh copy of THIS test
+// executable, which gets as far as worker_entry() at the top of main() and makes the call
+// there.  No libtest (harness = false, see Cargo.toml), since its main() would never call
+// worker_entry(); a failed assert_eq!() is a failed test all the same.
+
+use ffi_guard::isolation::{call_isolated, IsolationMode};
+use ffi_guard::outcome::CallOutcome;
+
+fn fn_returns() -> anyhow::Result<String> {
+    Ok(format!("hello from {}", std::process::id()))
+}
+
+fn fn_exits() -> anyhow::Result<String> {
+    std::process::exit(3)
+}
+
+fn fn_aborts() -> anyhow::Result<String> {
+    std::process::abort()
+}
+
+fn main() {
+    ffi_guard::reexec::worker_entry();
+
+    // a fresh process, not us (and not a fork of us either)
+    let outcome = call_isolated(IsolationMode::ReExec, fn_returns);
+    let CallOutcome::Returned(greeting) = &outcome else {
+        panic!("fn_returns(): {:?}", outcome);
+    };
+    assert_ne!(*greeting, format!("hello from {}", std::process::id()));
+    println!("test reexec_returned ... ok");
+
+    assert_eq!(
+        call_isolated(IsolationMode::ReExec, fn_exits),
+        CallOutcome::Exited(3)
+    );
+    println!("test reexec_exited ... ok");
+
+    assert_eq!(
+        call_isolated(IsolationMode::ReExec, fn_aborts),
+        CallOutcome::Signaled(libc::SIGABRT)
+    );
+    println!("test reexec_signaled ... ok");
+}
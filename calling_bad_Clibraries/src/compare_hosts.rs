@@ -0,0 +1,178 @@
+// src/compare_hosts.rs
+//
+// The README claims that a Rust host calling the C library fares no better than a C++
+// host does: `exit()` in the library ends either one, and so does a seg-fault.  Rather
+// than taking the README's word for it, `cargo run -- compare` runs the same scenarios
+// under both hosts, each in a process of its own, and puts the outputs and the exit
+// statuses side by side:
+//
+//  - the C++ host is bad_c_libs/test1.cpp, which build.rs compiles and links against its
+//    own copy of the C library (CPP_HOST_EXE, see `build_cpp_host()` in build.rs)
+//  - the Rust host is us, `<exe> scenario <name>`, which calls the C library in-place
+//    just like test1.cpp does (no isolation, that would be cheating)
+//
+// NOTE: the children write to a pipe, hence the C library's printf() is fully buffered;
+// whatever was still in that buffer when the process got killed by a signal is gone (in
+// both hosts), which is one more of the README's points.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+
+/// Same names as the `argv[1]` of test1.cpp
+pub const SCENARIOS: [&str; 3] = ["divide_by_zero", "access_violation", "exit"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+    Exited(i32),
+    Signaled(i32),
+}
+
+impl From<ExitStatus> for Termination {
+    fn from(status: ExitStatus) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(signal) = status.signal() {
+                return Termination::Signaled(signal);
+            }
+        }
+        // NOTE: Windows reports an access violation as exit code 0xc0000005
+        Termination::Exited(status.code().unwrap_or(-1))
+    }
+}
+
+impl fmt::Display for Termination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Termination::Exited(code) => write!(f, "exited with status {}", code),
+            Termination::Signaled(signal) => write!(f, "killed by signal {}", signal),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostRun {
+    pub termination: Termination,
+    /// stdout, then stderr, one entry per line
+    pub output: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comparison {
+    pub scenario: &'static str,
+    pub cpp: HostRun,
+    pub rust: HostRun,
+}
+
+impl Comparison {
+    /// Both hosts went down (or not) the same way
+    pub fn same_termination(&self) -> bool {
+        self.cpp.termination == self.rust.termination
+    }
+}
+
+/// The C++ host that build.rs built, if it could
+pub fn cpp_host() -> Option<PathBuf> {
+    option_env!("CPP_HOST_EXE").map(PathBuf::from)
+}
+
+/// Runs `<host> [args...] <scenario>` to completion and collects what it left behind
+pub fn run_host(host: &Path, args: &[&str], scenario: &str) -> io::Result<HostRun> {
+    let output = Command::new(host).args(args).arg(scenario).output()?;
+    let text = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(HostRun {
+        termination: output.status.into(),
+        output: text.lines().map(str::to_string).collect(),
+    })
+}
+
+/// Every scenario under both hosts; `rust_host` is normally `std::env::current_exe()`
+pub fn compare(cpp_host: &Path, rust_host: &Path) -> io::Result<Vec<Comparison>> {
+    SCENARIOS
+        .iter()
+        .map(|&scenario| {
+            Ok(Comparison {
+                scenario,
+                cpp: run_host(cpp_host, &[], scenario)?,
+                rust: run_host(rust_host, &["scenario"], scenario)?,
+            })
+        })
+        .collect()
+}
+
+/// The side-by-side table; one row per scenario, followed by the output lines of both
+pub fn render_table(comparisons: &[Comparison]) -> String {
+    const WIDTH: usize = 48;
+    let cell = |text: &str| -> String {
+        if text.chars().count() > WIDTH {
+            let cut: String = text.chars().take(WIDTH - 3).collect();
+            format!("{}...", cut)
+        } else {
+            format!("{:<width$}", text, width = WIDTH)
+        }
+    };
+
+    let mut table = format!(
+        "{:<18} {} {} {}\n",
+        "scenario",
+        cell("C++ host (test1.exe)"),
+        cell("Rust host (in-place)"),
+        "same?"
+    );
+    for comparison in comparisons {
+        table.push_str(&format!(
+            "{:<18} {} {} {}\n",
+            comparison.scenario,
+            cell(&comparison.cpp.termination.to_string()),
+            cell(&comparison.rust.termination.to_string()),
+            if comparison.same_termination() {
+                "yes"
+            } else {
+                "NO"
+            }
+        ));
+        let lines = comparison
+            .cpp
+            .output
+            .len()
+            .max(comparison.rust.output.len());
+        for i in 0..lines {
+            let line = |run: &HostRun| run.output.get(i).map_or(String::new(), |l| l.clone());
+            let row = format!(
+                "{:<18} {} {}",
+                "",
+                cell(&format!("| {}", line(&comparison.cpp))),
+                cell(&format!("| {}", line(&comparison.rust)))
+            );
+            table.push_str(row.trim_end());
+            table.push('\n');
+        }
+    }
+    table
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpp_host_exits_like_the_readme_says() {
+        // no C++ compiler on this box, nothing to check
+        let Some(cpp_host) = cpp_host() else {
+            return;
+        };
+        let run = run_host(&cpp_host, &[], "exit").unwrap();
+        // exit(-666) -> -666 & 0xff
+        assert_eq!(run.termination, Termination::Exited(102));
+        assert_eq!(run.output[0], "main(0): About to call 'mid_exit(-666)'");
+
+        let run = run_host(&cpp_host, &[], "access_violation").unwrap();
+        assert_eq!(run.termination, Termination::Signaled(libc::SIGSEGV));
+    }
+}
@@ -1,8 +1,3 @@
-use std::{
-    any::{self, Any},
-    panic,
-};
-
 use anyhow::anyhow; // this has become one of my favorite error handling libraries
                     //use anyhow::Result;
 
@@ -19,37 +14,189 @@ mod ffi {
     //      extern "C" {
     //          pub fn mid_divide_by_zero() -> ::std::os::raw::c_int;
     //      }
+    //      extern "C" {
+    //          pub fn mid_leak() -> ::std::os::raw::c_int;
+    //      }
+    //      extern "C" {
+    //          pub fn mid_open_temp() -> ::std::os::raw::c_int;
+    //      }
+    //      extern "C" {
+    //          pub fn mid_checksum(
+    //              count: ::std::os::raw::c_int,
+    //              stride: ::std::os::raw::c_int,
+    //          ) -> ::std::os::raw::c_int;
+    //      }
+    //      extern "C" {
+    //          pub fn mid_invert(
+    //              pixels: *const ::std::os::raw::c_uchar,
+    //              out: *mut ::std::os::raw::c_uchar,
+    //              len: ::std::os::raw::c_int,
+    //          ) -> ::std::os::raw::c_int;
+    //      }
+    //      extern "C" {
+    //          pub fn mid_flaky(work: ::std::os::raw::c_int) -> ::std::os::raw::c_int;
+    //      }
     include!(concat!(env!("OUT_DIR"), "/bindings.rs")); // see build.rs `EXTERN_LIB_FILENAME`
 }
 
+mod compare_hosts;
+
+// everything but the C library itself (and the C++ host of `compare`) lives in ffi_guard,
+// for other hosts of other bad C libraries to use
+use ffi_guard::{
+    alloc_accounting, async_isolation, call_trace, dyn_call, fuzz, isolation, journal, leak_check,
+    metrics, outcome, quarantine, record_replay, retry, sandbox, shared_log, shutdown,
+    thread_guard,
+};
+#[cfg(unix)]
+use ffi_guard::{reexec, scenario_file, shared_mem, zygote};
+
+use isolation::IsolationMode;
+use quarantine::{CircuitBreaker, QuarantinePolicy};
+use sandbox::SandboxLimits;
+use thread_guard::ThreadEnd;
+
 fn main() {
-    //single_thread_apartment();
-    multiple_threads_apartment();
+    // hidden entry point for IsolationMode::ReExec workers (they are copies of us), so this
+    // HAS to come first; it does not return if we are a worker
+    #[cfg(unix)]
+    reexec::worker_entry();
+
+    // the Rust side of `compare`: one scenario, in-place, like test1.cpp does it; so none
+    // of the setup below (no shared log, trace, fork server, and above all no recovery of
+    // the journal that the `compare` process itself has open)
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("scenario") {
+        run_scenario(args.get(2).map_or("", String::as_str));
+        return;
+    }
+
+    // before the fork server, so that its workers get to log too
+    if let Err(e) = shared_log::default_path().and_then(|path| shared_log::init_global(&path)) {
+        eprintln!("main(): could not open the shared log: {}", e);
+    }
+
+    // same here, the fork server's workers inherit the subscriber
+    if let Err(e) =
+        call_trace::configured_path().and_then(|path| call_trace::init_global(path.as_deref()))
+    {
+        eprintln!("main(): could not set up the trace: {}", e);
+    }
+
+    // FFI_RECORD=<file> / FFI_REPLAY=<file>; also before the fork server, whose workers'
+    // output only gets captured if it was recording when it got started
+    if let Err(e) = record_replay::init_from_env() {
+        eprintln!("main(): could not record/replay: {}", e);
+    }
+
+    // `call libmid_exit.so ...` (and the scenario files) mean OUR copy of it, which build.rs
+    // leaves in OUT_DIR, where dlopen() would not look for it
+    if let Some(mid_exit_so) = option_env!("MID_EXIT_SO") {
+        if let Some(name) = std::path::Path::new(mid_exit_so).file_name() {
+            dyn_call::register_library(&name.to_string_lossy(), mid_exit_so);
+        }
+    }
+
+    // the fork server has to be forked off while we are still small and single-threaded
+    #[cfg(unix)]
+    if let Err(e) = zygote::start_global(SandboxLimits::default()) {
+        eprintln!("main(): could not start the fork server: {}", e);
+    }
+
+    // clean up after the previous run, in case the C library took it down mid-way
+    let journal = match journal::Journal::default_path()
+        .and_then(|path| journal::Journal::open_and_recover(&path))
+    {
+        Ok((journal, report)) => {
+            if !report.is_empty() {
+                println!("main(): recovery: {}", report);
+            }
+            Some(journal)
+        }
+        Err(e) => {
+            eprintln!("main(): could not open the journal: {}", e);
+            None
+        }
+    };
+
+    match args.get(1).map(String::as_str) {
+        // $ cargo run -- latency [calls] [ballast_mb]
+        Some("latency") => {
+            let calls = args.get(2).and_then(|n| n.parse().ok()).unwrap_or(200);
+            let ballast_mb = args.get(3).and_then(|n| n.parse().ok()).unwrap_or(0);
+            isolation_latency(calls, ballast_mb);
+        }
+        // $ cargo run -- async
+        Some("async") => async_apartment(),
+        // $ cargo run -- journal; cargo run -- journal
+        Some("journal") => match &journal {
+            Some(journal) => journaled_apartment(journal),
+            None => eprintln!("main(): no journal, no journaled_apartment()"),
+        },
+        // $ cargo run -- compare
+        Some("compare") => compare_apartment(),
+        // $ cargo run -- fuzz [target] [iterations] [seed]; cargo run -- fuzz replay
+        Some("fuzz") => fuzz_apartment(&args[2..]),
+        // $ cargo run -- scenarios [dir]; echo $?
+        #[cfg(unix)]
+        Some("scenarios") => {
+            scenario_files_apartment(args.get(2).map_or("scenarios", String::as_str))
+        }
+        // $ cargo run -- call libmid_exit.so mid_exit "int(int)" 5
+        Some("call") => dyn_call_apartment(&args[2..]),
+        // $ cargo run -- record [file]
+        Some("record") => record_replay_apartment(args.get(2).map_or("", String::as_str)),
+        // $ cargo run -- metrics [<file>.prom | serve [127.0.0.1:9464]]
+        Some("metrics") => metrics_apartment(&args[2..]),
+        // $ cargo run -- shutdown [status map]; echo $?
+        Some("shutdown") => shutdown_apartment(args.get(2).map_or("", String::as_str)),
+        // $ cargo run -- retry [calls]
+        Some("retry") => {
+            let calls = args.get(2).and_then(|n| n.parse().ok()).unwrap_or(5);
+            retry_apartment(calls);
+        }
+        // $ cargo run -- log
+        Some("log") => shared_log_apartment(),
+        // $ cargo run -- shm [megabytes]
+        #[cfg(unix)]
+        Some("shm") => {
+            let megabytes = args.get(2).and_then(|n| n.parse().ok()).unwrap_or(8);
+            shared_memory_apartment(megabytes);
+        }
+        // $ cargo run --features malloc_wrap -- soak [calls]
+        Some("soak") => {
+            let calls = args.get(2).and_then(|n| n.parse().ok()).unwrap_or(1000);
+            soak_apartment(calls);
+        }
+        _ => {
+            //single_thread_apartment();
+            isolated_process_apartment();
+            sandboxed_process_apartment();
+            leak_checked_apartment();
+            multiple_threads_apartment();
+        }
+    }
 }
 
 fn single_thread_apartment() {
+    // NOTE: the "calling mid_xxx()" markers of do_xxx() (and fork_and_join_0arg()) are
+    // tracing events now; they go to the trace (see call_trace.rs), not to stdout
     // Output (same on both Linux and Windows):
-    //      do_proc_exit(0): About to call mid_exit() from C library
     //      mid_exit(): Calling exit() now...
     do_proc_exit("single_thread_apartment");
 
     // Output (Linux):
-    //      do_div_by_zero(0): About to call mid_divide_by_zero() from C library
     //      mid_divide_by_zero(): about to divide by zero...
     //      mid_divide_by_zero(): This WILL print - In C/C++ NaN and INF are valid answer...
-    //      do_div_by_zero(1): mid_divide_by_zero() from C library called successfully
     // Output (Windows):
-    //      do_div_by_zero(0): About to call mid_divide_by_zero() from C library
     //      mid_divide_by_zero(): about to divide by zero...
     //      error: process didn't exit successfully: `rust-demo\target\debug\calling_bad_Clibraries.exe` (exit code: 0xc0000094, STATUS_INTEGER_DIVIDE_BY_ZERO)
     do_div_by_zero("single_thread_apartment");
 
     // Output (Linux):
-    //      do_seg_fault(0): About to call mid_access_violation() from C library
     //      mid_access_violation(): about to write some value to NULL pointer; seg-fault here we come...
     //      Segmentation fault (core dumped)
     // Output (Windows):
-    //      do_seg_fault(0): About to call mid_access_violation() from C library
     //      mid_access_violation(): about to write some value to NULL pointer; seg-fault here we come...
     //      error: process didn't exit successfully: `rust-demo\target\debug\calling_bad_Clibraries.exe` (exit code: 0xc0000005, STATUS_ACCESS_VIOLATION)
     //      Segmentation fault
@@ -57,88 +204,1017 @@ fn single_thread_apartment() {
 }
 
 fn multiple_threads_apartment() {
+    // NOTE: the threads of fork_and_join_0arg() are guarded ones now (see thread_guard.rs);
+    // a seg-fault (or SIGFPE) only takes down the thread, but exit() still takes down all
+    // of us, hence it stays commented out
     // Output (Linux):
     //
     // Output (Windows):
-    //      fork_and_join_0arg(0): About to fork a thread...
-    //          >>>>>>> Calling function at address '0x7ff7b0c359a0'
-    //      multiple_threads_apartment - do_proc_exit(0): About to call mid_exit() from C library
     //      mid_exit(): Calling exit() now...
     //      error: process didn't exit successfully: `target\debug\calling_bad_Clibraries.exe` (exit code: 0xfffffd66)
     let fn_proc_exit = || do_proc_exit("multiple_threads_apartment");
     //fork_and_join_0arg(fn_proc_exit);
 
     // Output (Linux):
-    //
+    //      mid_divide_by_zero(): about to divide by zero...
+    //      multiple_threads_apartment: mid_divide_by_zero() -> thread killed by signal 8: divide by zero in mid_divide_by_zero at mid_exit.c:21
     // Output (Windows):
-    //      fork_and_join_0arg(0): About to fork a thread...
-    //          >>>>>>> Calling function at address '0x7ff75d2d5930'
-    //      multiple_threads_apartment - do_div_by_zero(0): About to call mid_divide_by_zero() from C library
     //      mid_divide_by_zero(): about to divide by zero...
     //      error: process didn't exit successfully: `C:\Users\HidekiAI\projects\rust-demo\target\debug\calling_bad_Clibraries.exe` (exit code: 0xc0000094, STATUS_INTEGER_DIVIDE_BY_ZERO)
     let fn_div_by_zero = || do_div_by_zero("multiple_threads_apartment");
-    //fork_and_join_0arg(fn_div_by_zero);
+    match fork_and_join_0arg(fn_div_by_zero) {
+        Ok(value) => println!(
+            "multiple_threads_apartment: mid_divide_by_zero() -> {}",
+            value
+        ),
+        Err(e) => println!("multiple_threads_apartment: mid_divide_by_zero() -> {}", e),
+    }
 
     // Output (Linux):
-    //
+    //      mid_access_violation(): about to write some value to NULL pointer; seg-fault here we come...
+    //      multiple_threads_apartment: mid_access_violation() -> thread killed by signal 11: null write in mid_access_violation at mid_exit.c:44
     // Output (Windows):
-    //      fork_and_join_0arg(0): About to fork a thread...
-    //          >>>>>>> Calling function at address '0x7ff6e1415850'
-    //      multiple_threads_apartment - do_seg_fault(0): About to call mid_access_violation() from C library
     //      mid_access_violation(): about to write some value to NULL pointer; seg-fault here we come...
     //      error: process didn't exit successfully: `C:\Users\HidekiAI\projects\rust-demo\target\debug\calling_bad_Clibraries.exe` (exit code: 0xc0000005, STATUS_ACCESS_VIOLATION)
     //      Segmentation fault
     let fn_seg_fault = || do_seg_fault("multiple_threads_apartment");
-    fork_and_join_0arg(fn_seg_fault);
+    match fork_and_join_0arg(fn_seg_fault) {
+        Ok(value) => println!(
+            "multiple_threads_apartment: mid_access_violation() -> {}",
+            value
+        ),
+        Err(e) => println!(
+            "multiple_threads_apartment: mid_access_violation() -> {}",
+            e
+        ),
+    }
 }
 
-fn fork_and_join_0arg(
-    my_function: fn() -> anyhow::Result<String>, // no need for Sync since we are not sharing data between threads
-) -> anyhow::Result<String> {
+fn isolated_process_apartment() {
+    // Output (Linux):
+    //      isolated_process_apartment(0): mid_access_violation() -> killed by signal 11
+    //      isolated_process_apartment(1): mid_access_violation() -> killed by signal 11
+    //      isolated_process_apartment(2): mid_access_violation() -> killed by signal 11
+    //      isolated_process_apartment(3): mid_access_violation() -> quarantined
+    //      isolated_process_apartment(4): mid_access_violation() -> quarantined
+    //      isolated_process_apartment(5): mid_exit() -> exited with status 102
+    //      isolated_process_apartment(6): mid_exit() -> exited with status 102
+    // Unlike the thread apartment, each call gets its own forked process, hence the seg-fault
+    // and exit() only takes down the child; and once the child has crashed 3 times, the
+    // circuit breaker stops forking new ones for a while
+    let breaker = CircuitBreaker::new(QuarantinePolicy::default());
+    let fn_seg_fault = || do_seg_fault("isolated_process_apartment");
+    for attempt in 0..5 {
+        let outcome = breaker.call("mid_access_violation", IsolationMode::Fork, fn_seg_fault);
+        println!(
+            "isolated_process_apartment({}): mid_access_violation() -> {}",
+            attempt, outcome
+        );
+    }
+
+    let fn_proc_exit = || do_proc_exit("isolated_process_apartment");
+    let outcome = breaker.call("mid_exit", IsolationMode::Fork, fn_proc_exit);
+    println!("isolated_process_apartment(5): mid_exit() -> {}", outcome);
+
+    // same again, but from a fresh copy of this executable rather than a fork of it; this
+    // is the mode to use once the host has threads of its own (see reexec.rs)
+    let outcome = breaker.call("mid_exit", IsolationMode::ReExec, fn_proc_exit);
+    println!("isolated_process_apartment(6): mid_exit() -> {}", outcome);
+
+    crashed_process_postmortem();
+}
+
+fn crashed_process_postmortem() {
+    // Output (Linux, debug build):
+    //      crashed_process_postmortem(0): mid_access_violation() -> killed by signal 11
+    //      crashed_process_postmortem(0): null write in mid_access_violation at mid_exit.c:44
+    //          #0 0x0000563d76160d7c mid_access_violation at ./bad_c_libs/mid_exit.c:44
+    //          #1 0x0000563d761478eb calling_bad_Clibraries::do_seg_fault at src/main.rs:425
+    //          ...
+    // "killed by signal 11" is all waitpid() can tell us; the crash reporter installed in
+    // the worker also tells us WHERE (see crash_report.rs).  Release builds have no line
+    // info for the C library (cc only passes -g in debug), only the function names.
+    let fn_seg_fault = || do_seg_fault("crashed_process_postmortem");
+    metrics::name_function(fn_seg_fault, "mid_access_violation"); // for the metrics and the trace
+    let call = isolation::call_isolated_report(
+        IsolationMode::Fork,
+        &SandboxLimits::default(),
+        fn_seg_fault,
+    );
     println!(
-        "fork_and_join_0arg(0): About to fork a thread...\n\t>>>>>>> Calling function at address '{:?}'",
-        my_function
+        "crashed_process_postmortem(0): mid_access_violation() -> {}",
+        call.outcome
     );
+    if let (Some(crash), Ok(exe)) = (call.crash, std::env::current_exe()) {
+        let frames = crash.symbolize(&exe);
+        println!("crashed_process_postmortem(0): {}", crash.render(&frames));
+    }
+}
 
-    // first, fork off a new thread
-    let handle = std::thread::spawn(move || {
-        let duration_msec = 1000;
-        println!(">>> fork_and_join_0arg(1): Inside the NEW thread...  Will yield for {} mSec to let the main thread print first...", duration_msec);
-        std::thread::sleep(std::time::Duration::from_millis(duration_msec)); // yield for few mSec to let the main thread print first
+fn sandboxed_process_apartment() {
+    // Output (Linux):
+    //      sandboxed_process_apartment(0): mid_divide_by_zero() -> killed by signal 8
+    //      sandboxed_process_apartment(1): mid_exit() -> exited with status 102
+    // (signal 8 is SIGFPE; with gcc -O0, the `1 / 0` really does get executed on x86_64)
+    // Same as isolated_process_apartment(), but the child is not allowed to do much other
+    // than printf() and exit(); i.e. if mid_exit() were to try to fork() or open() a file
+    // on its way out, the child would be killed with SIGSYS, which comes back as
+    // "exceeded sandbox limit Syscall"
+    let limits = SandboxLimits {
+        address_space: Some(1024 * 1024 * 1024),
+        cpu_time_secs: Some(5),
+        wall_time_secs: Some(10),
+        file_size: Some(1024 * 1024), // NOTE: stdout redirected to a file counts too
+        open_files: Some(16),
+        no_new_privs: true,
+        allowed_syscalls: Some(SandboxLimits::minimal_syscalls()),
+    };
+    let fn_div_by_zero = || do_div_by_zero("sandboxed_process_apartment");
+    metrics::name_function(fn_div_by_zero, "mid_divide_by_zero"); // for the metrics and the trace
+    let outcome = isolation::call_isolated_with(IsolationMode::Fork, &limits, fn_div_by_zero);
+    println!(
+        "sandboxed_process_apartment(0): mid_divide_by_zero() -> {}",
+        outcome
+    );
+
+    let fn_proc_exit = || do_proc_exit("sandboxed_process_apartment");
+    metrics::name_function(fn_proc_exit, "mid_exit"); // for the metrics and the trace
+    let outcome = isolation::call_isolated_with(IsolationMode::Fork, &limits, fn_proc_exit);
+    println!("sandboxed_process_apartment(1): mid_exit() -> {}", outcome);
+}
+
+fn leak_checked_apartment() {
+    // Output (Linux):
+    //      leak_checked_apartment(0): mid_open_temp() -> leaked 1 fds and 1 temp files
+    //          fd 3 (r+) /tmp/mid_exit_8bV2xQ
+    //          temp file /tmp/mid_exit_8bV2xQ (0600)
+    //      leak_checked_apartment(1): mid_open_temp() -> returned: fd 3
+    //      leak_checked_apartment(1): leaked 1 fds and 1 temp files
+    //          fd 3 (r+) /tmp/mid_exit_Zr41Lk
+    //          temp file /tmp/mid_exit_Zr41Lk (0600)
+    // In-place, the leaked fd is OURS (and stays open, for all we know it is another
+    // thread's); in the worker, it is the worker's (reported by the worker, and closed by
+    // the kernel when it exits), but the temp file is left behind either way.  Had
+    // mid_open_temp() called exit() before returning, the in-place version would not have
+    // had the chance to report anything at all.  The report is all we get here: /tmp is
+    // everybody's, see `LeakCheck::delete_temp_files` for what it takes to delete
+    let check = leak_check::LeakCheck::default();
+    let (_, leaks) = check.in_place(|| do_open_temp("leak_checked_apartment"));
+    println!("leak_checked_apartment(0): mid_open_temp() -> {}", leaks);
 
-        println!(">>> fork_and_join_0arg(1): Inside the NEW thread...  OK, calling now...");
-        let catch_result: Result<_, Box<dyn Any + Send>> = panic::catch_unwind(|| {
-            println!(">>>> fork_and_join_0arg(1): Inside the NEW thread...  INSIDE catch_unwind()...  OK, calling now...");
-            my_function()
+    let fn_open_temp = || do_open_temp("leak_checked_apartment");
+    metrics::name_function(fn_open_temp, "mid_open_temp"); // for the metrics and the trace
+    let call = check.call_isolated(
+        IsolationMode::ReExec,
+        &SandboxLimits::default(),
+        fn_open_temp,
+    );
+    println!(
+        "leak_checked_apartment(1): mid_open_temp() -> {}",
+        call.outcome
+    );
+    if let Some(leaks) = call.leaks {
+        println!("leak_checked_apartment(1): {}", leaks);
+    }
+}
+
+fn journaled_apartment(journal: &journal::Journal) {
+    // Output (Linux, 1st run):
+    //      journaled_apartment: holding /tmp/journaled_apartment_4242_0, /tmp/journaled_apartment.lock and /tmp/journaled_apartment.log
+    //      mid_exit(): Calling exit() now...
+    // Output (Linux, 2nd run):
+    //      main(): recovery: 3 left behind by dead processes, 0 still held
+    //          temp /tmp/journaled_apartment_4242_0 (pid 4242): deleted
+    //          lockfile /tmp/journaled_apartment.lock (pid 4242): deleted
+    //          flock /tmp/journaled_apartment.log (pid 4242): released by the kernel when its holder died
+    //      journaled_apartment: holding ...  (and off we go again)
+    // The in-place call to mid_exit() takes US down, so none of the guards below get dropped;
+    // without the journal, the 2nd run would fail on the lock file left by the 1st
+    let temp_file = journal.temp_file("journaled_apartment");
+    let lock_path = std::env::temp_dir().join("journaled_apartment.lock");
+    let lock_file = journal.lock_file(&lock_path);
+    let log_path = std::env::temp_dir().join("journaled_apartment.log");
+    let log_lock = journal.advisory_lock(&log_path);
+    match (&temp_file, &lock_file, &log_lock) {
+        (Ok(temp_file), Ok(_), Ok(_)) => println!(
+            "journaled_apartment: holding {}, {} and {}",
+            temp_file.path().display(),
+            lock_path.display(),
+            log_path.display()
+        ),
+        _ => {
+            eprintln!(
+                "journaled_apartment: could not take everything: {:?} {:?} {:?}",
+                temp_file.as_ref().err(),
+                lock_file.as_ref().err(),
+                log_lock.as_ref().err()
+            );
+            return;
+        }
+    }
+    let _ = do_proc_exit("journaled_apartment");
+}
+
+fn compare_apartment() {
+    // Output (Linux):
+    //      scenario           C++ host (test1.exe)                             Rust host (in-place)                             same?
+    //      divide_by_zero     killed by signal 8                               killed by signal 8                               yes
+    //                         | main(0): About to call 'mid_divide_by_zero()'  |
+    //      access_violation   killed by signal 11                              killed by signal 11                              yes
+    //                         | main(0): About to call 'mid_access_violatio... |
+    //      exit               exited with status 102                           exited with status 102                           yes
+    //                         | main(0): About to call 'mid_exit(-666)'        | mid_exit(): Calling exit() now...
+    //                         | mid_exit(): Calling exit() now...              |
+    //      compare_apartment: both hosts went down the same way in 3 of 3 scenarios
+    // i.e. exactly what the README says: being Rust does not save the host (our own
+    // "calling mid_xxx()" markers are in the trace, see call_trace.rs)
+    let Some(cpp_host) = compare_hosts::cpp_host() else {
+        eprintln!("compare_apartment: build.rs could not build the C++ host (no C++ compiler?)");
+        return;
+    };
+    let rust_host = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            eprintln!("compare_apartment: {}", e);
+            return;
+        }
+    };
+    match compare_hosts::compare(&cpp_host, &rust_host) {
+        Ok(comparisons) => {
+            print!("{}", compare_hosts::render_table(&comparisons));
+            let same = comparisons.iter().filter(|c| c.same_termination()).count();
+            println!(
+                "compare_apartment: both hosts went down the same way in {} of {} scenarios",
+                same,
+                comparisons.len()
+            );
+            if same != comparisons.len() {
+                std::process::exit(1);
+            }
+        }
+        Err(e) => eprintln!("compare_apartment: {}", e),
+    }
+}
+
+fn run_scenario(scenario: &str) {
+    // NOTE: the result is irrelevant, the scenarios are about whether we live to see it
+    let _ = match scenario {
+        "divide_by_zero" => do_div_by_zero("scenario"),
+        "access_violation" => do_seg_fault("scenario"),
+        "exit" => do_proc_exit("scenario"),
+        other => {
+            eprintln!(
+                "run_scenario: unknown scenario {:?} (one of {:?})",
+                other,
+                compare_hosts::SCENARIOS
+            );
+            std::process::exit(2);
+        }
+    };
+}
+
+// The C functions `cargo run -- fuzz` knows how to call
+fn fuzz_targets() -> Vec<fuzz::FuzzTarget> {
+    vec![
+        fuzz::FuzzTarget {
+            name: "mid_checksum",
+            arity: 2,
+            call: |args| Ok(unsafe { ffi::mid_checksum(args[0], args[1]) }.to_string()),
+        },
+        fuzz::FuzzTarget {
+            name: "mid_exit",
+            arity: 1,
+            call: |args| Ok(unsafe { ffi::mid_exit(args[0]) }.to_string()),
+        },
+    ]
+}
+
+fn fuzz_apartment(args: &[String]) {
+    // Output (Linux, `cargo run -- fuzz mid_checksum 500`):
+    //      fuzz_apartment: mid_checksum, 500 inputs, seed 1
+    //      fuzz_apartment: 4 findings
+    //          mid_checksum(0, -1) -> exceeded sandbox limit WallTime  (19 hits, first [-1103054526, -1]; fuzz_cases/mid_checksum_e462a665864a940d.case)
+    //          mid_checksum(13, 0) -> exited with status 13  (17 hits, first [13, 28]; fuzz_cases/mid_checksum_9551f1a7e733fb24.case)
+    //          mid_checksum(-1271833, 1) -> killed by signal 11 in mid_checksum at mid_exit.c:112  (100 hits, first [-2147483647, 1]; ...)
+    //          mid_checksum(0, 0) -> killed by signal 8 in mid_checksum at mid_exit.c:111  (10 hits, first [-25068208, 0]; ...)
+    // Each finding is saved under fuzz_cases/; `cargo run -- fuzz replay` re-runs all of
+    // them (i.e. against the next version of the library) and tells which ones changed
+    let cases_dir = std::path::Path::new("fuzz_cases");
+    let targets = fuzz_targets();
+    let mut fuzzer = fuzz::Fuzzer::new(fuzz::FuzzConfig::default());
+
+    if args.first().map(String::as_str) == Some("replay") {
+        let cases = match fuzz::load_cases(cases_dir) {
+            Ok(cases) => cases,
+            Err(e) => {
+                eprintln!("fuzz_apartment: {}: {}", cases_dir.display(), e);
+                return;
+            }
+        };
+        let mut changed = 0;
+        for (path, case) in &cases {
+            match fuzzer.replay(&targets, case) {
+                Ok(()) => println!("fuzz_apartment: {}: still {}", path.display(), case),
+                Err(now) => {
+                    changed += 1;
+                    let now = now.map_or("comes back normally".to_string(), |bucket| {
+                        bucket.to_string()
+                    });
+                    println!(
+                        "fuzz_apartment: {}: CHANGED {}, now {}",
+                        path.display(),
+                        case,
+                        now
+                    );
+                }
+            }
+        }
+        println!("fuzz_apartment: {} cases, {} changed", cases.len(), changed);
+        if changed > 0 {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let name = args.first().map_or("mid_checksum", String::as_str);
+    let Some(target) = targets.iter().find(|target| target.name == name) else {
+        eprintln!("fuzz_apartment: no fuzz target named {}", name);
+        return;
+    };
+    let mut config = fuzz::FuzzConfig::default();
+    if let Some(iterations) = args.get(1).and_then(|n| n.parse().ok()) {
+        config.iterations = iterations;
+    }
+    if let Some(seed) = args.get(2).and_then(|n| n.parse().ok()) {
+        config.seed = seed;
+    }
+    println!(
+        "fuzz_apartment: {}, {} inputs, seed {}",
+        target.name, config.iterations, config.seed
+    );
+    let mut fuzzer = fuzz::Fuzzer::new(config);
+    let findings = fuzzer.fuzz(target);
+    println!("fuzz_apartment: {} findings", findings.len());
+    for finding in &findings {
+        let case = fuzz::Case {
+            target: target.name.to_string(),
+            args: finding.minimized.clone(),
+            bucket: finding.bucket.clone(),
+        };
+        let saved = match fuzz::save_case(cases_dir, target, finding) {
+            Ok(path) => path.display().to_string(),
+            Err(e) => format!("not saved: {}", e),
+        };
+        println!(
+            "    {}  ({} hits, first {:?}; {})",
+            case, finding.hits, finding.original, saved
+        );
+    }
+}
+
+fn metrics_apartment(args: &[String]) {
+    // Output (Linux, `cargo run -- metrics`, the C library's own printf()s omitted):
+    //      # HELP ffi_calls_total Guarded foreign calls, by function.
+    //      # TYPE ffi_calls_total counter
+    //      ffi_calls_total{function="mid_access_violation"} 5
+    //      ffi_calls_total{function="mid_checksum"} 5
+    //      ffi_calls_total{function="mid_exit"} 2
+    //      ...
+    //      ffi_call_outcomes_total{function="mid_access_violation",outcome="signaled"} 3
+    //      ...
+    //      ffi_call_outcomes_total{function="mid_access_violation",outcome="quarantined"} 2
+    //      ...
+    //      ffi_call_outcomes_total{function="mid_checksum",outcome="timeout"} 1
+    //      ...
+    //      ffi_call_duration_seconds_bucket{function="mid_checksum",le="1"} 4
+    //      ffi_call_duration_seconds_bucket{function="mid_checksum",le="5"} 5
+    // `cargo run -- metrics /var/lib/node_exporter/ffi.prom` writes the same to a file, and
+    // `cargo run -- metrics serve` keeps on serving it at http://127.0.0.1:9464/metrics
+    // Nothing below records anything itself; the isolation layer does it for every call
+    let breaker = CircuitBreaker::new(QuarantinePolicy::default());
+    let fn_seg_fault = || do_seg_fault("metrics_apartment");
+    for _ in 0..5 {
+        breaker.call("mid_access_violation", IsolationMode::Fork, fn_seg_fault);
+    }
+    let fn_proc_exit = || do_proc_exit("metrics_apartment");
+    breaker.call("mid_exit", IsolationMode::Fork, fn_proc_exit);
+    breaker.call("mid_exit", IsolationMode::ReExec, fn_proc_exit);
+
+    let fn_checksum = || Ok(unsafe { ffi::mid_checksum(10, 3) }.to_string());
+    let fn_checksum_hang = || Ok(unsafe { ffi::mid_checksum(0, -1) }.to_string());
+    metrics::name_function(fn_checksum, "mid_checksum");
+    metrics::name_function(fn_checksum_hang, "mid_checksum");
+    for _ in 0..4 {
+        isolation::call_isolated(IsolationMode::ForkServer, fn_checksum);
+    }
+    let limits = SandboxLimits {
+        wall_time_secs: Some(1),
+        ..Default::default()
+    };
+    isolation::call_isolated_with(IsolationMode::Fork, &limits, fn_checksum_hang);
+
+    let metrics = metrics::global();
+    match args.first().map(String::as_str) {
+        None => print!("{}", metrics.render()),
+        Some("serve") => {
+            let addr = args.get(1).map_or("127.0.0.1:9464", String::as_str);
+            let bound = match addr
+                .parse()
+                .map_err(|e| format!("{}", e))
+                .and_then(|addr| metrics.serve(addr).map_err(|e| e.to_string()))
+            {
+                Ok(bound) => bound,
+                Err(e) => {
+                    eprintln!("metrics_apartment: {}: {}", addr, e);
+                    return;
+                }
+            };
+            println!(
+                "metrics_apartment: serving http://{}/metrics, Ctrl-C to stop",
+                bound
+            );
+            loop {
+                std::thread::park();
+            }
+        }
+        Some(path) => match metrics.dump_to_file(std::path::Path::new(path)) {
+            Ok(()) => println!("metrics_apartment: wrote {}", path),
+            Err(e) => eprintln!("metrics_apartment: {}: {}", path, e),
+        },
+    }
+}
+
+#[cfg(unix)]
+fn scenario_files_apartment(dir: &str) {
+    // Output (Linux, `cargo run -- scenarios`):
+    //      PASS  access_violation: killed by signal 11
+    //      ...
+    //      PASS  exit: exited with status 102
+    //      PASS  pow() from libm: returned: 1.4142135623730951
+    //      scenario_files_apartment: 7 of 7 scenarios passed
+    // and a failing one says why, i.e. with `args = [12, 2]` in checksum_unlucky.toml:
+    //      FAIL  checksum_unlucky: returned: 26
+    //            expected exited with status 13, got returned: 26
+    //            expected "unlucky count" on stdout, got ""
+    // Exits with status 1 if anything failed, for whoever runs this from a CI job.
+    let results = match scenario_file::run_dir(std::path::Path::new(dir), Some("libmid_exit.so")) {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("scenario_files_apartment: {}: {}", dir, e);
+            std::process::exit(2);
+        }
+    };
+    for result in &results {
+        let outcome = result
+            .outcome
+            .as_ref()
+            .map_or_else(|| "not run".to_string(), |outcome| outcome.to_string());
+        let verdict = if result.passed() { "PASS" } else { "FAIL" };
+        println!("{}  {}: {}", verdict, result.name, outcome);
+        for failure in &result.failures {
+            println!("      {}", failure);
+        }
+    }
+    let passed = results.iter().filter(|result| result.passed()).count();
+    println!(
+        "scenario_files_apartment: {} of {} scenarios passed",
+        passed,
+        results.len()
+    );
+    if passed != results.len() {
+        std::process::exit(1);
+    }
+}
+
+fn dyn_call_apartment(args: &[String]) {
+    // Output (Linux, `cargo run -- call libmid_exit.so mid_checksum "int(int, int)" ...`):
+    //      ... 10 3:   mid_checksum(10, 3) from .../out/libmid_exit.so -> returned: 11
+    //      ... 13 1:   mid_checksum(): unlucky count, bailing out
+    //                  mid_checksum(13, 1) from .../out/libmid_exit.so -> exited with status 13
+    //      ... 10 0:   mid_checksum(10, 0) from .../out/libmid_exit.so -> killed by signal 8
+    //                  dyn_call_apartment: divide by zero at pc 0x7f641f8ce4d2
+    //      ... 0 -1:   mid_checksum(0, -1) from .../out/libmid_exit.so -> exceeded sandbox limit WallTime
+    // and `cargo run -- call libm.so.6 pow "double(double, double)" 2 0.5` works just as
+    // well; no header, no bindgen, no rebuild (see dyn_call.rs for what signatures work)
+    let [library, symbol, signature, call_args @ ..] = args else {
+        eprintln!("dyn_call_apartment: usage: call <library> <symbol> <signature> [args...]");
+        return;
+    };
+    let call = match dyn_call::DynCall::parse(
+        &dyn_call::find_library(library),
+        symbol,
+        signature,
+        call_args,
+    ) {
+        Ok(call) => call,
+        Err(e) => {
+            eprintln!("dyn_call_apartment: {}", e);
+            return;
+        }
+    };
+    let limits = SandboxLimits {
+        wall_time_secs: Some(10),
+        ..Default::default()
+    };
+    let report = dyn_call::call_isolated(&call, &limits);
+    println!("{} -> {}", call, report.outcome);
+    if let Some(crash) = report.crash {
+        // the .so is not our executable, so there is nothing to symbolize it with
+        println!(
+            "dyn_call_apartment: {} at pc 0x{:x}",
+            crash.description(),
+            crash.pc
+        );
+    }
+}
+
+fn shutdown_apartment(status_map: &str) {
+    // Output (Linux, `cargo run -- shutdown "-666=75,*=1"; echo $?`):
+    //      shutdown_apartment: 2 slow calls in flight, now calling mid_exit()
+    //      mid_exit(): Calling exit() now...
+    //      shutdown_apartment: slow call -> returned: slow work done
+    //      shutdown_apartment: slow call -> returned: slow work done
+    //      shutdown_apartment: goodbye
+    //      library exit(102) -> shutting down with status 75, 0 calls abandoned, 2 cleanups
+    //          goodbye: done
+    //          remove /tmp/shutdown_apartment_4242: done
+    //      75
+    // The worker's exit() is a request for US to shut down, which we do, orderly: the
+    // calls still in flight get to finish, the cleanups run, and the status is one our
+    // supervisor understands (without the map, it would have been 102)
+    let status_map = match shutdown::StatusMap::parse(status_map) {
+        Ok(status_map) => status_map,
+        Err(e) => {
+            eprintln!("shutdown_apartment: {}", e);
+            return;
+        }
+    };
+    let coordinator = std::sync::Arc::new(shutdown::ShutdownCoordinator::new(
+        shutdown::ShutdownPolicy {
+            status_map,
+            ..Default::default()
+        },
+    ));
+    let scratch = std::env::temp_dir().join(format!("shutdown_apartment_{}", std::process::id()));
+    let _ = std::fs::write(&scratch, b"work in progress");
+    coordinator.register_cleanup(&format!("remove {}", scratch.display()), move || {
+        let _ = std::fs::remove_file(&scratch);
+    });
+    coordinator.register_cleanup("goodbye", || println!("shutdown_apartment: goodbye"));
+
+    let fn_slow = || -> anyhow::Result<String> {
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        Ok("slow work done".into())
+    };
+    let slow_calls: Vec<_> = (0..2)
+        .map(|_| {
+            let coordinator = coordinator.clone();
+            std::thread::spawn(move || {
+                let outcome = coordinator.call(IsolationMode::ReExec, fn_slow);
+                println!("shutdown_apartment: slow call -> {}", outcome);
+            })
+        })
+        .collect();
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    println!("shutdown_apartment: 2 slow calls in flight, now calling mid_exit()");
+    let fn_proc_exit = || do_proc_exit("shutdown_apartment");
+    metrics::name_function(fn_proc_exit, "mid_exit"); // for the metrics and the trace
+    coordinator.call(IsolationMode::ReExec, fn_proc_exit);
+
+    for slow_call in slow_calls {
+        let _ = slow_call.join();
+    }
+    println!("shutdown_apartment: THIS WILL NEVER GET PRINTED");
+}
+
+fn record_replay_apartment(path: &str) {
+    // Output (Linux, `cargo run -- record`):
+    //      record_replay_apartment: recording to /tmp/calling_bad_Clibraries.calls.toml
+    //      record_replay_apartment: mid_checksum(10, 3) -> returned: 11
+    //      mid_checksum(): unlucky count, bailing out
+    //      record_replay_apartment: mid_checksum(13, 1) -> exited with status 13
+    //      record_replay_apartment: mid_checksum(10, 0) -> killed by signal 8
+    //      record_replay_apartment: replaying 3 calls, without the C library
+    //      record_replay_apartment: mid_checksum(10, 3) -> returned: 11
+    //      mid_checksum(): unlucky count, bailing out
+    //      record_replay_apartment: mid_checksum(13, 1) -> exited with status 13
+    //      record_replay_apartment: mid_checksum(10, 0) -> killed by signal 8
+    //      record_replay_apartment: mid_checksum(10, 3) -> failed: mid_checksum(10, 3) was called more often than in the recording /tmp/calling_bad_Clibraries.calls.toml
+    // The second time round, nothing gets dlopen()ed, let alone forked; the "unlucky count"
+    // line is the one recorded the first time.  `FFI_RECORD=calls.toml cargo run -- call ...`
+    // and `FFI_REPLAY=calls.toml cargo run -- call ...` do the same for any other call
+    let path = match path {
+        "" => std::env::temp_dir().join("calling_bad_Clibraries.calls.toml"),
+        path => std::path::PathBuf::from(path),
+    };
+    let limits = SandboxLimits {
+        wall_time_secs: Some(10),
+        ..Default::default()
+    };
+    let checksum = |args: &[&str]| {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let library = dyn_call::find_library("libmid_exit.so");
+        let call = dyn_call::DynCall::parse(&library, "mid_checksum", "int(int, int)", &args)
+            .expect("mid_checksum(int, int)");
+        let report = dyn_call::call_isolated(&call, &limits);
+        println!(
+            "record_replay_apartment: mid_checksum({}) -> {}",
+            args.join(", "),
+            report.outcome
+        );
+    };
+
+    if let Err(e) = record_replay::start_recording(&path) {
+        eprintln!("record_replay_apartment: {}: {}", path.display(), e);
+        return;
+    }
+    println!("record_replay_apartment: recording to {}", path.display());
+    let calls: [&[&str]; 3] = [&["10", "3"], &["13", "1"], &["10", "0"]];
+    for args in calls {
+        checksum(args);
+    }
+
+    match record_replay::start_replay(&path) {
+        Ok(recorded) => println!(
+            "record_replay_apartment: replaying {} calls, without the C library",
+            recorded
+        ),
+        Err(e) => {
+            eprintln!("record_replay_apartment: {}", e);
+            return;
+        }
+    }
+    for args in calls {
+        checksum(args);
+    }
+    // one more than was recorded
+    checksum(calls[0]);
+    record_replay::stop();
+}
+
+fn retry_apartment(calls: usize) {
+    // Output (Linux; which calls crash is anybody's guess, see mid_flaky()):
+    //      retry_apartment: call 1 -> returned: 42
+    //      retry_apartment: call 2 -> killed by signal 11 after 4 attempts (killed by signal 11, killed by signal 11, killed by signal 11, killed by signal 11)
+    //      retry_apartment: call 3 -> returned: 42 after 2 attempts (killed by signal 11, returned: 42)
+    //      retry_apartment: call 4 -> returned: 42
+    //      retry_apartment: call 5 -> returned: 42
+    //      retry_apartment: 4 of 5 calls went through, 9 attempts, waited 160ms in total
+    // Same call every time; nothing to fix on our side but the odds, hence a retry on
+    // signals (an exit() would not be retried, see retry.rs)
+    let fn_flaky = || {
+        let work = unsafe { ffi::mid_flaky(21) };
+        Ok(work.to_string())
+    };
+    metrics::name_function(fn_flaky, "mid_flaky"); // for the metrics and the trace
+    let policy = retry::RetryPolicy {
+        max_attempts: 4,
+        initial_backoff: std::time::Duration::from_millis(20),
+        ..Default::default()
+    };
+    let (mut succeeded, mut attempts, mut waited) = (0, 0, std::time::Duration::ZERO);
+    for n in 1..=calls {
+        let call = retry::call_isolated_with_retry(
+            IsolationMode::Fork,
+            &SandboxLimits::default(),
+            &policy,
+            fn_flaky,
+        );
+        println!("retry_apartment: call {} -> {}", n, call);
+        if matches!(call.outcome(), outcome::CallOutcome::Returned(_)) {
+            succeeded += 1;
+        }
+        attempts += call.attempts.len();
+        waited += call
+            .attempts
+            .iter()
+            .filter_map(|attempt| attempt.backoff)
+            .sum();
+    }
+    println!(
+        "retry_apartment: {} of {} calls went through, {} attempts, waited {:?} in total",
+        succeeded, calls, attempts, waited
+    );
+}
+
+fn shared_log_apartment() {
+    // Output (Linux):
+    //      shared_log_apartment: appended to /run/user/1000/calling_bad_Clibraries.log:
+    //      1792386566.528 host 19149 call -: shared_log_apartment: 3 threads, 2 calls each
+    //      1792386566.530 worker 19153 call 1: about to call mid_exit() from the C library
+    //      1792386566.532 worker 19156 call 3: about to call mid_access_violation() from the C library
+    //      1792386566.533 worker 19157 call 2: about to call mid_access_violation() from the C library
+    //      1792386566.534 host 19149 call 1: ReExec call -> exited with status 102
+    //      ...
+    //      1792386566.539 host 19149 call 5: ForkServer call -> killed by signal 11
+    // The host and its workers all append to the same file, each record tagged with who
+    // wrote it and the call it belongs to; whoever crashes or exit()s takes nothing with it
+    let Some(log) = shared_log::global() else {
+        eprintln!("shared_log_apartment: no shared log");
+        return;
+    };
+    let start = std::fs::metadata(log.path()).map_or(0, |m| m.len());
+    shared_log::log("shared_log_apartment: 3 threads, 2 calls each");
+
+    let fn_exit = || {
+        shared_log::log("about to call mid_exit() from the C library");
+        do_proc_exit("shared_log_apartment")
+    };
+    let fn_seg_fault = || {
+        shared_log::log("about to call mid_access_violation() from the C library");
+        do_seg_fault("shared_log_apartment")
+    };
+    let calls = [
+        (
+            IsolationMode::ReExec,
+            fn_exit as fn() -> anyhow::Result<String>,
+        ),
+        (IsolationMode::ForkServer, fn_seg_fault),
+        (IsolationMode::ReExec, fn_seg_fault),
+    ];
+    let threads: Vec<_> = calls
+        .into_iter()
+        .map(|(mode, my_function)| {
+            std::thread::spawn(move || {
+                for _ in 0..2 {
+                    let outcome = isolation::call_isolated(mode, my_function);
+                    shared_log::log(format!("{:?} call -> {}", mode, outcome));
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        let _ = thread.join();
+    }
+
+    println!(
+        "shared_log_apartment: appended to {}:",
+        log.path().display()
+    );
+    if let Ok(content) = std::fs::read(log.path()) {
+        let appended = content.get(start as usize..).unwrap_or_default();
+        print!("{}", String::from_utf8_lossy(appended));
+    }
+}
+
+#[cfg(unix)]
+fn shared_memory_apartment(megabytes: usize) {
+    // Output (Linux, `cargo run -- shm`):
+    //      shared_memory_apartment: 8 MiB image -> returned: 8388608 bytes (137.3ms), output 01 00 03 02 05 04...
+    //      shared_memory_apartment: 8 MiB image, bad checksum -> killed by signal 11 (39.0ms), no output
+    //      shared_memory_apartment: 24 MiB image, too big to copy -> killed by signal 11 (3.9ms), no output
+    //      shared_memory_apartment: 0 shared mappings left
+    // (mid_invert()'s "bad checksum" printf() is still in its stdout buffer when it crashes)
+    // The host never copies the image through a pipe, and never gets to see the half
+    // inverted buffer mid_invert() left behind (nor a scribbled-on input), see shared_mem.rs
+    fn invert_image(pixels: &[u8], out: &mut [u8]) -> anyhow::Result<usize> {
+        let len = i32::try_from(pixels.len())?;
+        let written = unsafe { ffi::mid_invert(pixels.as_ptr(), out.as_mut_ptr(), len) };
+        Ok(usize::try_from(written)?)
+    }
+    let limits = SandboxLimits {
+        wall_time_secs: Some(10),
+        ..Default::default()
+    };
+    let images = [
+        (megabytes, ""),
+        (megabytes, ", bad checksum"),
+        (24, ", too big to copy"), // mid_invert() works in place over 16 MiB
+    ];
+    for (megabytes, label) in images {
+        let len = megabytes * 1024 * 1024;
+        let mut buffers = match shared_mem::SharedBuffers::new(len, len) {
+            Ok(buffers) => buffers,
+            Err(e) => {
+                eprintln!("shared_memory_apartment: mmap() failed: {}", e);
+                return;
+            }
+        };
+        // decode the "image" straight into the shared mapping; the last byte is the sum
+        // of the pixels, which is what mid_invert() checks
+        let pixels = buffers.input_mut();
+        let mut sum = 0u8;
+        for (i, pixel) in pixels.iter_mut().take(len.saturating_sub(1)).enumerate() {
+            *pixel = (i % 256) as u8 ^ 0xfe;
+            sum = sum.wrapping_add(*pixel);
+        }
+        if let Some(last) = pixels.last_mut() {
+            *last = if label.contains("checksum") {
+                !sum
+            } else {
+                sum
+            };
+        }
+
+        let start = std::time::Instant::now();
+        let call = buffers.call("mid_invert", &limits, invert_image);
+        let elapsed = start.elapsed();
+        let output = match &call.output {
+            Some(output) => {
+                let head: Vec<String> = output
+                    .iter()
+                    .take(6)
+                    .map(|b| format!("{:02x}", b))
+                    .collect();
+                format!("output {}...", head.join(" "))
+            }
+            None => "no output".to_string(),
+        };
+        println!(
+            "shared_memory_apartment: {} MiB image{} -> {} ({:.1?}), {}",
+            megabytes, label, call.report.outcome, elapsed, output
+        );
+    }
+    println!(
+        "shared_memory_apartment: {} shared mappings left",
+        shared_mem::live_mappings()
+    );
+}
+
+fn soak_apartment(calls: usize) {
+    // Output (Linux, `cargo run --features malloc_wrap -- soak 100000`):
+    //      soak_apartment: one guarded call -> returned: mid_leak() call #1
+    //      soak_apartment: allocated 256 bytes in 3 blocks, freed 192 bytes in 2, outstanding 64 bytes in 1 blocks
+    //          64 bytes in 1 blocks from ./bad_c_libs/mid_exit.c:56
+    //      soak_apartment: 100000 calls: allocated 25600000 bytes in 300000 blocks, freed 19200000 bytes in 200000, outstanding 6400000 bytes in 100000 blocks
+    //          6400000 bytes in 100000 blocks from ./bad_c_libs/mid_exit.c:56
+    //      RSS 4992 KiB -> 27432 KiB (+22440 KiB)
+    // mid_leak() looks harmless enough when called once, but never frees its cache; the
+    // soak makes it obvious.  Without the feature, the C library's malloc() is not
+    // wrapped and all the counters stay at 0 (the RSS still grows, of course).
+    if !alloc_accounting::enabled() {
+        println!(
+            "soak_apartment: NOTE: built without `--features malloc_wrap`, nothing gets counted"
+        );
+    }
+    let call =
+        isolation::call_isolated_report(IsolationMode::ReExec, &SandboxLimits::default(), do_leak);
+    println!("soak_apartment: one guarded call -> {}", call.outcome);
+    if let Some(allocations) = call.allocations {
+        println!("soak_apartment: {}", allocations);
+    }
+
+    let soak = alloc_accounting::soak(calls, || {
+        let _ = do_leak();
+    });
+    println!("soak_apartment: {}", soak);
+}
+
+fn async_apartment() {
+    // Output (Linux):
+    //      async_apartment: mid_access_violation() -> killed by signal 11
+    //      async_apartment: mid_exit() -> exited with status 102
+    //      async_apartment: mid_divide_by_zero() -> killed by signal 8
+    //      async_apartment: slow call -> cancelled after 100 mSec
+    //      async_apartment: ticker ticked 10 times while all of the above was going on
+    // (the C library's own printf()s are omitted above)
+    // All the calls are in flight at the same time (at most 2 of them in a worker at any
+    // given moment), and the runtime is still free to run the ticker while they are
+    let runtime = tokio::runtime::Runtime::new().expect("async_apartment(): tokio runtime");
+    runtime.block_on(async {
+        let isolation = async_isolation::AsyncIsolation::new(2);
+        let ticker = tokio::spawn(async {
+            let mut ticks = 0;
+            let mut interval = tokio::time::interval(std::time::Duration::from_millis(50));
+            for _ in 0..10 {
+                interval.tick().await;
+                ticks += 1;
+            }
+            ticks
         });
-        // Handle the panic result if needed
-        match catch_result {
-            Ok(_) => {
-                println!("Thread completed successfully");
-                Ok("Success")
+
+        let fn_seg_fault = || do_seg_fault("async_apartment");
+        let fn_proc_exit = || do_proc_exit("async_apartment");
+        let fn_div_by_zero = || do_div_by_zero("async_apartment");
+        metrics::name_function(fn_seg_fault, "mid_access_violation");
+        metrics::name_function(fn_proc_exit, "mid_exit");
+        metrics::name_function(fn_div_by_zero, "mid_divide_by_zero");
+        let fn_slow = || {
+            std::thread::sleep(std::time::Duration::from_secs(10));
+            Ok("too late".into())
+        };
+        let (seg_fault, proc_exit, div_by_zero, slow) = tokio::join!(
+            isolation.call(IsolationMode::ReExec, fn_seg_fault),
+            isolation.call(IsolationMode::ReExec, fn_proc_exit),
+            isolation.call(IsolationMode::ReExec, fn_div_by_zero),
+            tokio::time::timeout(
+                std::time::Duration::from_millis(100),
+                isolation.call(IsolationMode::ReExec, fn_slow)
+            ),
+        );
+        println!("async_apartment: mid_access_violation() -> {}", seg_fault);
+        println!("async_apartment: mid_exit() -> {}", proc_exit);
+        println!("async_apartment: mid_divide_by_zero() -> {}", div_by_zero);
+        match slow {
+            Ok(outcome) => println!("async_apartment: slow call -> {}", outcome),
+            Err(_) => println!("async_apartment: slow call -> cancelled after 100 mSec"),
+        }
+        println!(
+            "async_apartment: ticker ticked {} times while all of the above was going on",
+            ticker.await.unwrap_or(0)
+        );
+    });
+}
+
+fn isolation_latency(calls: u32, ballast_mb: usize) {
+    // Output (Linux, `cargo run --release -- latency 200 512`, YMMV):
+    //      isolation_latency: 200 calls each, host ballast 512 MiB
+    //      isolation_latency: Thread        ->     22 uSec/call
+    //      isolation_latency: GuardedThread ->     25 uSec/call
+    //      isolation_latency: Fork          ->  12451 uSec/call
+    //      isolation_latency: ForkServer    ->    151 uSec/call
+    // The ballast is there to make the host "large" (every byte of it has been touched,
+    // hence fork() has to copy the page tables for all of it); the fork server was forked
+    // off before the ballast got allocated, so it stays cheap to fork no matter what
+    let ballast: Vec<u8> = vec![1; ballast_mb * 1024 * 1024];
+    println!(
+        "isolation_latency: {} calls each, host ballast {} MiB",
+        calls,
+        ballast.len() / (1024 * 1024)
+    );
+    let fn_noop = || Ok(String::new());
+    for mode in [
+        IsolationMode::Thread,
+        IsolationMode::GuardedThread,
+        IsolationMode::Fork,
+        IsolationMode::ForkServer,
+        IsolationMode::ReExec,
+    ] {
+        let start = std::time::Instant::now();
+        for _ in 0..calls {
+            let outcome = isolation::call_isolated(mode, fn_noop);
+            if outcome != outcome::CallOutcome::Returned(String::new()) {
+                println!("isolation_latency: {:?} -> {}", mode, outcome);
+                break;
             }
-            Err(panic_value) => Err(anyhow!("Thread panicked - {:?}", panic_value)),
         }
+        println!(
+            "isolation_latency: {:<13} -> {:>6} uSec/call",
+            format!("{:?}", mode),
+            start.elapsed().as_micros() / calls.max(1) as u128
+        );
+    }
+}
+
+fn fork_and_join_0arg(
+    my_function: fn() -> anyhow::Result<String>, // no need for Sync since we are not sharing data between threads
+) -> anyhow::Result<String> {
+    let span = tracing::info_span!("fork_and_join_0arg", function = ?my_function);
+    let _entered = span.enter();
+    tracing::debug!("about to fork a thread");
+
+    // first, fork off a new thread; a guarded one, so that a seg-fault (or SIGFPE) in the C
+    // library ends the thread rather than all of us (see thread_guard.rs for the faults it
+    // cannot contain, which still do)
+    let thread_span = span.clone();
+    let thread = thread_guard::spawn_guarded(move || {
+        let _entered = thread_span.enter();
+        let duration_msec = 1000;
+        tracing::debug!(
+            yield_msec = duration_msec,
+            "inside the new thread, yielding to let the main thread go first"
+        );
+        std::thread::sleep(std::time::Duration::from_millis(duration_msec)); // yield for few mSec to let the main thread print first
+
+        // the panics are caught by spawn_guarded(), along with the faults
+        tracing::debug!("inside the new thread, calling now");
+        my_function()
     });
-    // before we join (to get blocked), we'll print something out while the thread is yielded...
-    println!(">> fork_and_join_0arg(2): Inside the main thread (outside the spawned thread)...  Will now block self and wait for thread to join back");
+    // before we join (to get blocked), we'll log something while the thread is yielded...
+    tracing::debug!("inside the main thread, blocking until the thread joins back");
 
-    let result = handle.join();
-    println!(">> fork_and_join_0arg(4): we're back!");
+    let result = thread.join();
+    tracing::debug!("joined");
 
-    // If the spawned thread panics, the join() call will return an Err containing the panic value:
+    // If the spawned thread panics (or faults), join() tells us how it ended rather than what it returned:
     match result {
-        Ok(Ok(value)) => {
-            return Ok(format!("fork_and_join_0arg(1): Success! {:?}", value));
+        ThreadEnd::Returned(Ok(value)) => {
+            return Ok(format!("fork_and_join_0arg: Success! {:?}", value));
         }
-        Ok(Err(e)) => {
-            println!("fork_and_join_0arg(2): Error! {:?}", e);
+        ThreadEnd::Returned(Err(e)) => {
+            tracing::error!(error = ?e, "thread failed");
             return Err(e);
         }
-        Err(e) => {
-            println!("fork_and_join_0arg(3): Error! {:?}", e);
+        ThreadEnd::Panicked(e) => {
+            tracing::error!(panic = ?e, "thread panicked");
             return Err(anyhow!("Thread panicked"));
         }
+        ThreadEnd::Faulted { signal, crash } => {
+            tracing::error!(signal, "thread faulted");
+            // i.e. "null write in mid_access_violation at mid_exit.c:44", as for a worker process
+            let postmortem = match (crash, std::env::current_exe()) {
+                (Some(crash), Ok(exe)) => {
+                    let rendered = crash.render(&crash.symbolize(&exe));
+                    rendered.lines().next().unwrap_or_default().to_string()
+                }
+                _ => "no crash record".into(),
+            };
+            return Err(anyhow!(
+                "thread killed by signal {}: {}",
+                signal,
+                postmortem
+            ));
+        }
     }
 }
 
@@ -150,10 +1226,7 @@ where
 }
 
 fn do_proc_exit(debug_str: &str) -> anyhow::Result<String> {
-    println!(
-        "{} - do_proc_exit(0): About to call mid_exit() from C library",
-        debug_str
-    );
+    tracing::info!(caller = debug_str, "calling mid_exit()");
     let status = -666;
     unsafe {
         let result = ffi::mid_exit(status);
@@ -161,46 +1234,49 @@ fn do_proc_exit(debug_str: &str) -> anyhow::Result<String> {
             return Err(anyhow!("mid_exit() from C library failed"));
         }
     }
-    println!(
-        "{} - do_proc_exit(1): mid_exit() from C library called successfully",
-        debug_str
-    );
+    tracing::info!(caller = debug_str, "mid_exit() returned");
     Ok("Success!".into())
 }
 
 fn do_div_by_zero(debug_str: &str) -> anyhow::Result<String> {
-    println!(
-        "{} - do_div_by_zero(0): About to call mid_divide_by_zero() from C library",
-        debug_str
-    );
+    tracing::info!(caller = debug_str, "calling mid_divide_by_zero()");
     unsafe {
         let result = ffi::mid_divide_by_zero();
         if result < 0 {
             return Err(anyhow!("mid_divide_by_zero() from C library failed"));
         }
     }
-    println!(
-        "{} - do_div_by_zero(1): mid_divide_by_zero() from C library called successfully",
-        debug_str
-    );
+    tracing::info!(caller = debug_str, "mid_divide_by_zero() returned");
     Ok("Success!".into())
 }
 
+fn do_open_temp(debug_str: &str) -> anyhow::Result<String> {
+    tracing::info!(caller = debug_str, "calling mid_open_temp()");
+    let fd = unsafe { ffi::mid_open_temp() };
+    if fd < 0 {
+        return Err(anyhow!("mid_open_temp() from C library failed"));
+    }
+    Ok(format!("fd {}", fd))
+}
+
+// NOTE: no println!() here, soak_apartment() calls this thousands of times
+fn do_leak() -> anyhow::Result<String> {
+    let calls = unsafe { ffi::mid_leak() };
+    if calls < 0 {
+        return Err(anyhow!("mid_leak() from C library failed"));
+    }
+    Ok(format!("mid_leak() call #{}", calls))
+}
+
 fn do_seg_fault(debug_str: &str) -> anyhow::Result<String> {
     // access the C function from the generated bindings
-    println!(
-        "{} - do_seg_fault(0): About to call mid_access_violation() from C library",
-        debug_str
-    );
+    tracing::info!(caller = debug_str, "calling mid_access_violation()");
     unsafe {
         let result = ffi::mid_access_violation();
         if result < 0 {
             return Err(anyhow!("mid_access_violation() from C library failed"));
         }
     }
-    println!(
-        "{} - do_seg_fault(1): mid_access_violation() from C library called successfully",
-        debug_str
-    );
+    tracing::info!(caller = debug_str, "mid_access_violation() returned");
     Ok("Success!".into())
 }
@@ -33,4 +33,50 @@ mod tests {
     fn test_my_macro1() {
         // Invoke the procedural macro and add assertions here to verify the expected behavior
     }
+
+    // the type parameter's default (`= u16`) and bounds are for the struct only, the
+    // generated impl has to leave the default out
+    #[derive(my_macros::MyProcMacro)]
+    struct MyCounter<T: Copy + Default = u16> {
+        #[halve]
+        count: u64,
+        last: T,
+    }
+
+    // raw identifiers: named without the `r#`, and halved by `half_type()`
+    #[derive(my_macros::MyProcMacro)]
+    #[my_proc_macro(field = "type")]
+    struct MyToken {
+        r#type: u8,
+        #[halve]
+        r#loop: f32,
+    }
+
+    #[test]
+    fn test_raw_identifier_fields() {
+        let mut token = MyToken {
+            r#type: 9,
+            r#loop: 3.0,
+        };
+        token.half_type();
+        token.half_loop();
+        assert_eq!(token.r#type, 4);
+        assert_eq!(token.r#loop, 1.5);
+    }
+
+    #[test]
+    fn test_generic_struct_with_default_type() {
+        let mut counter: MyCounter = MyCounter { count: 9, last: 7 };
+        counter.half_count();
+        assert_eq!(counter.count, 4);
+        assert_eq!(counter.last, 7u16);
+
+        let mut counter = MyCounter {
+            count: 1,
+            last: 'x',
+        };
+        counter.half_count();
+        assert_eq!(counter.count, 0);
+        assert_eq!(counter.last, 'x');
+    }
 }
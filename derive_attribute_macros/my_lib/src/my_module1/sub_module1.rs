@@ -5,7 +5,36 @@ use my_macros::{make_answer, my_macro1, MyProcMacro};
 #[derive(MyProcMacro)]
 struct MyStruct {
     my_field: u32,
-    my_var1: f32, // NOTE: Try commenting this out, you should get the compiler warning that 'my_var1' does not exist, and if you change this to i32 (or any other type than f32), you will also get the message defined in the proc-macro
+    #[halve]
+    my_var1: f32, // NOTE: Try commenting out the #[halve] above, you should get the compiler error that there is nothing to halve, and if you change this to String (or any other type than a float or an integer), you will also get the message defined in the proc-macro
+}
+
+// same derive, but the fields to halve are named on the struct rather than marked;
+// each of them gets a `half_<field>()` of its own
+#[allow(dead_code)]
+#[derive(MyProcMacro)]
+#[my_proc_macro(field = "speed", field = "distance")]
+struct MyCar {
+    name: String,
+    speed: f64,
+    distance: i64,
+    #[halve]
+    fuel: u8,
+}
+
+// the derive has to carry the generics (lifetimes, types, const generics, where clause) over
+// to the impl it generates, else `impl MyFrame { ... }` would not compile
+#[allow(dead_code)]
+#[derive(MyProcMacro)]
+#[my_proc_macro(field = "scale")]
+struct MyFrame<'a, T, const N: usize>
+where
+    T: Clone + std::fmt::Debug,
+{
+    pixels: &'a [T; N],
+    scale: f32,
+    #[halve]
+    width: usize,
 }
 
 // calling proc-macro directly
@@ -20,7 +49,7 @@ pub(crate) fn do_something() {
     // first, dump my_var1 to verify its value
     let mut my_struct = MyStruct {
         my_field: 42,
-        my_var1: 3.14,
+        my_var1: 2.5,
     };
     println!("my_var1: {}", my_struct.my_var1);
     // then, call the method to halve my_var1
@@ -37,4 +66,36 @@ mod tests {
         do_something();
         // Add assertions here to verify the expected behavior of the do_something function
     }
+
+    #[test]
+    fn test_half_of_every_marked_field() {
+        let mut car = MyCar {
+            name: "my_car".to_string(),
+            speed: 90.5,
+            distance: -7,
+            fuel: 255,
+        };
+        car.half_speed();
+        car.half_distance();
+        car.half_fuel();
+        assert_eq!(car.speed, 45.25);
+        assert_eq!(car.distance, -3); // integer division, rounds towards zero
+        assert_eq!(car.fuel, 127);
+        assert_eq!(car.name, "my_car");
+    }
+
+    #[test]
+    fn test_generic_struct() {
+        let pixels = [1u8, 2, 3, 4];
+        let mut frame = MyFrame {
+            pixels: &pixels,
+            scale: 3.0,
+            width: 5,
+        };
+        frame.half_scale();
+        frame.half_width();
+        assert_eq!(frame.scale, 1.5);
+        assert_eq!(frame.width, 2);
+        assert_eq!(frame.pixels, &[1, 2, 3, 4]);
+    }
 }
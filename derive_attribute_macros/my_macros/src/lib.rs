@@ -3,7 +3,8 @@
 // because Cargo.toml [lib] name = "my_macros_lib" (in which, it's path=<this file>)
 
 use proc_macro::TokenStream;
-use quote::quote;
+use quote::{format_ident, quote};
+use syn::ext::IdentExt;
 use syn::{parse_macro_input, DeriveInput};
 
 // Declare the procedural macro dependency
@@ -41,49 +42,181 @@ pub fn make_answer(_item: TokenStream) -> TokenStream {
 }
 
 // Purpose of this macro:
-// verifies that the struct has a field named `my_var1` of type `f32`
-// and then generates a method to halve `my_var1` as `half_my_var1` in which
-// the caller can assume that they will inherit that function without impl it themselves
-#[proc_macro_derive(MyProcMacro)]
+// generates a method `half_<field>()` for every field marked to be halved, in which
+// the caller can assume that they will inherit those functions without impl them themselves.
+// A field gets marked either on the field itself, or by name on the struct:
+//
+//      #[derive(MyProcMacro)]
+//      #[my_proc_macro(field = "speed")]     // -> half_speed()
+//      struct Car {
+//          speed: f64,
+//          #[halve]                          // -> half_fuel()
+//          fuel: u32,
+//          name: String,                     // left alone
+//      }
+//
+// Only floats (`/= 2.0`) and integers (`/= 2`) can be halved; anything else (or a name
+// that is not a field, or a struct with nothing to halve) is a compile error
+#[proc_macro_derive(MyProcMacro, attributes(halve, my_proc_macro))]
 pub fn my_proc_macro_fn(input: TokenStream) -> TokenStream {
     // Parse the input tokens into a syntax tree
     let input = parse_macro_input!(input as DeriveInput);
 
-    // Check if the struct has a field named "my_var1" of type "f32"
-    let my_var1_exists = match input.data {
-        syn::Data::Struct(ref data) => data.fields.iter().any(|field| {
-            field
-                .ident
-                .as_ref()
-                .map_or(false, |ident| ident == "my_var1")
-                && matches!(&field.ty, syn::Type::Path(p) if p.path.is_ident("f32"))
-        }),
-        _ => false,
+    let fields = match halved_fields(&input) {
+        Ok(fields) => fields,
+        // Emit a helpful compiler error (all of them, rather than one per build)
+        Err(e) => return e.to_compile_error().into(),
     };
-
-    if !my_var1_exists {
-        // Emit a helpful compiler error
-        return syn::Error::new_spanned(
-            &input,
-            "Struct must have a field named 'my_var1' of type 'f32'",
-        )
-        .to_compile_error()
-        .into();
-    }
-
-    // Ensure that the struct has a field named `my_var1` of type `f32`
-    let _my_var1_type = quote! { f32 };
     let struct_name = &input.ident;
+    // `struct Foo<'a, T: Clone, const N: usize> where ...` needs its impl to be
+    // `impl<'a, T: Clone, const N: usize> Foo<'a, T, N> where ...`, not `impl Foo`
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
 
-    // Generate the code to halve `my_var1`
-    let expanded = quote! {
-        impl #struct_name {
-            pub fn half_my_var1(&mut self) {
-                self.my_var1 /= 2.0;
+    // Generate the code to halve each of them
+    let methods = fields.iter().map(|(member, ty)| {
+        // the Ident itself rather than its name: `r#type` gets `half_type()`, not a panic
+        let method = match member {
+            syn::Member::Named(ident) => format_ident!("half_{}", ident),
+            syn::Member::Unnamed(index) => format_ident!("half_{}", index.index),
+        };
+        let two = if is_float(ty) {
+            quote! { 2.0 }
+        } else {
+            quote! { 2 }
+        };
+        quote! {
+            pub fn #method(&mut self) {
+                self.#member /= #two;
             }
         }
+    });
+    let expanded = quote! {
+        impl #impl_generics #struct_name #type_generics #where_clause {
+            #(#methods)*
+        }
     };
 
     // Hand the output tokens back to the compiler
     TokenStream::from(expanded)
 }
+
+const FLOATS: [&str; 2] = ["f32", "f64"];
+const INTEGERS: [&str; 12] = [
+    "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64", "u128", "usize",
+];
+
+fn is_float(ty: &syn::Type) -> bool {
+    matches!(ty, syn::Type::Path(p) if FLOATS.iter().any(|float| p.path.is_ident(float)))
+}
+
+fn is_integer(ty: &syn::Type) -> bool {
+    matches!(ty, syn::Type::Path(p) if INTEGERS.iter().any(|int| p.path.is_ident(int)))
+}
+
+// `speed` for a named field (`type` for `r#type`), `0` for the first one of a tuple struct
+fn member_name(member: &syn::Member) -> String {
+    match member {
+        syn::Member::Named(ident) => ident.unraw().to_string(),
+        syn::Member::Unnamed(index) => index.index.to_string(),
+    }
+}
+
+// The fields marked `#[halve]`, or named in `#[my_proc_macro(field = "...")]`, in the
+// order they are declared in; every mistake along the way gets reported, not just the first
+fn halved_fields(input: &DeriveInput) -> syn::Result<Vec<(syn::Member, syn::Type)>> {
+    let syn::Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            input,
+            "MyProcMacro can only be derived for a struct",
+        ));
+    };
+    let mut errors: Vec<syn::Error> = Vec::new();
+
+    // the names given on the struct itself
+    let mut named: Vec<syn::LitStr> = Vec::new();
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("my_proc_macro"))
+    {
+        let parsed = attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("field") {
+                named.push(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `field = \"<field name>\"`"))
+            }
+        });
+        if let Err(e) = parsed {
+            errors.push(e);
+        }
+    }
+
+    let mut fields = Vec::new();
+    for (i, field) in data.fields.iter().enumerate() {
+        let member = match &field.ident {
+            Some(ident) => syn::Member::Named(ident.clone()),
+            None => syn::Member::Unnamed(i.into()),
+        };
+        let mut marked = false;
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("halve"))
+        {
+            match attr.meta.require_path_only() {
+                Ok(_) => marked = true,
+                Err(e) => errors.push(e),
+            }
+        }
+        let name = member_name(&member);
+        if named.iter().any(|lit| lit.value() == name) {
+            marked = true;
+        }
+        if !marked {
+            continue;
+        }
+        if is_float(&field.ty) || is_integer(&field.ty) {
+            fields.push((member, field.ty.clone()));
+        } else {
+            errors.push(syn::Error::new_spanned(
+                &field.ty,
+                format!(
+                    "field '{}' cannot be halved, it is neither a float nor an integer",
+                    name
+                ),
+            ));
+        }
+    }
+
+    for lit in &named {
+        let known = data
+            .fields
+            .iter()
+            .enumerate()
+            .any(|(i, field)| match &field.ident {
+                Some(ident) => ident.unraw() == lit.value(),
+                None => i.to_string() == lit.value(),
+            });
+        if !known {
+            errors.push(syn::Error::new_spanned(
+                lit,
+                format!("struct has no field named '{}'", lit.value()),
+            ));
+        }
+    }
+
+    if fields.is_empty() && errors.is_empty() {
+        errors.push(syn::Error::new_spanned(
+            input,
+            "Struct must have a field to halve: mark it #[halve], or name it in #[my_proc_macro(field = \"...\")]",
+        ));
+    }
+    match errors.into_iter().reduce(|mut all, e| {
+        all.combine(e);
+        all
+    }) {
+        Some(errors) => Err(errors),
+        None => Ok(fields),
+    }
+}